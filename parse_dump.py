@@ -151,33 +151,24 @@ def get_trace(dump: dict, device_id=0):
     return trace[device_id]
 
 
-def cli():
+def export_device(trace, output_dir, trace_only=False):
     """
-    elements: 原始的 action 对象，保存了分配地址，callstack等信息，长度为n
-    allocation_over_time: 用来画图的东西。其中最后一项是summary，可以忽略，长度为n+1
-
+    把一个device的trace保存为json，返回写出的文件路径
     """
-    import argparse
-
-    parser = argparse.ArgumentParser()
-    # parse arg -p or --path
-    parser.add_argument("-p", "--path", type=str, default="snapshots/snapshot.pickle", help="path to snapshot")
-    parser.add_argument("-o", "--output_dir", type=str, default="alloc_data/", help="output dir")
-    parser.add_argument("-d", "--device", type=int, default=0, help="device id")
-    parser.add_argument("-z", "--zip", action="store_true", help="Whether to save as zipped")
-    args = parser.parse_args()
-    path = args.path
-    output_dir = args.output_dir
-    device_id = args.device
-
     # if output dir does not exist, create
     if not os.path.exists(output_dir):
         os.makedirs(output_dir)
 
-    with open(path, "rb") as f:
-        dump = pickle.load(f)
+    if trace_only:
+        # elements.json without allocations.json: snap-rs lays out the raw events itself
+        print("Saving raw trace to json")
+        elements_path = os.path.join(output_dir, "elements.json")
+        with open(elements_path, "w") as f:
+            f.write(json.dumps(trace))
+
+        ic(len(trace))
+        return [elements_path]
 
-    trace = get_trace(dump, device_id)
     alloc_data = process_alloc_data(trace)
 
     # max_size = out["max_size"]
@@ -201,17 +192,56 @@ def cli():
     with open(elements_path, "w") as f:
         f.write(json.dumps(elements))
 
-    import zipfile
+    ic(len(elements))
+    ic(len(allocations))
+    return [allocations_path, elements_path]
+
+
+def cli():
+    """
+    elements: 原始的 action 对象，保存了分配地址，callstack等信息，长度为n
+    allocation_over_time: 用来画图的东西。其中最后一项是summary，可以忽略，长度为n+1
+
+    """
+    import argparse
+
+    parser = argparse.ArgumentParser()
+    # parse arg -p or --path
+    parser.add_argument("-p", "--path", type=str, default="snapshots/snapshot.pickle", help="path to snapshot")
+    parser.add_argument("-o", "--output_dir", type=str, default="alloc_data/", help="output dir")
+    parser.add_argument("-d", "--device", type=int, default=0, help="device id")
+    parser.add_argument(
+        "-a", "--all-devices", action="store_true", help="Export every device, each under device_<id>/"
+    )
+    parser.add_argument("-z", "--zip", action="store_true", help="Whether to save as zipped")
+    parser.add_argument(
+        "-t", "--trace", action="store_true", help="Save the raw device trace only, snap-rs computes the layout"
+    )
+    args = parser.parse_args()
+    path = args.path
+    output_dir = args.output_dir
+    device_id = args.device
+
+    with open(path, "rb") as f:
+        dump = pickle.load(f)
+
+    if args.all_devices:
+        paths = []
+        for device_id in range(len(dump["device_traces"])):
+            print(f"Device {device_id}")
+            device_dir = os.path.join(output_dir, f"device_{device_id}")
+            paths += export_device(get_trace(dump, device_id), device_dir, args.trace)
+    else:
+        paths = export_device(get_trace(dump, device_id), output_dir, args.trace)
 
     if args.zip:
+        import zipfile
+
         print("Saving as zip")
         zip_path = os.path.join(output_dir, "snap.zip")
         with zipfile.ZipFile(zip_path, "w") as f:
-            f.write(allocations_path)
-            f.write(elements_path)
-
-    ic(len(elements))
-    ic(len(allocations))
+            for p in paths:
+                f.write(p)
 
 
 if __name__ == "__main__":
@@ -1,15 +1,32 @@
+use crate::callstack::{CallstackTable, StackId};
+use crate::clock::{Clock, format_timestamp};
 use crate::utils::format_bytes;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter, Result};
 
 // Corresponds to the Python Frame dataclass
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Frame {
     pub name: String, // function name
     pub filename: String,
     pub line: u32,
 }
 
+impl Frame {
+    /// A Python source frame, not a C/C++ one
+    pub fn is_python(&self) -> bool {
+        self.filename.ends_with(".py")
+    }
+
+    /// A Python frame outside of installed packages and the standard library
+    pub fn is_user_code(&self) -> bool {
+        self.is_python()
+            && !["/lib/python", "site-packages/", "dist-packages/"]
+                .iter()
+                .any(|dir| self.filename.contains(dir))
+    }
+}
+
 // Implement Display for Frame to make callstack printing cleaner
 impl Display for Frame {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -18,66 +35,130 @@ impl Display for Frame {
 }
 
 // Corresponds to the Python Allocation dataclass
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Allocation {
     pub timesteps: Vec<u64>, // x coords, sorted
     pub offsets: Vec<u64>,   // y coords, length same as `timesteps`
     pub size: u64,           // height (sweep distance)
-    pub callstack: Vec<Frame>,
+    pub stack: StackId,      // callstack, resolved through the `CallstackTable` of its snap
     pub peak_mem: u64,
     pub peak_timestamps: Vec<u64>, // reaches its peak at these timestamps
+    pub action: String, // trace action that created this element: alloc, segment_alloc, or free_completed if allocated before recording
+    pub addr: u64,      // device address
+    pub stream: u64,    // CUDA stream
+    pub time_us: u64,   // wall-clock time of the trace event, in microseconds
 }
 
-impl Display for Allocation {
+/// An allocation together with the table its callstack is resolved through
+pub struct AllocationDisplay<'a> {
+    alloc: &'a Allocation,
+    callstacks: &'a CallstackTable,
+    clock: Option<&'a Clock>, // to show elapsed times next to timesteps
+    shown_frames: Option<&'a [bool]>, // by frame id, see `FrameFilter`; all frames if None
+}
+
+impl Display for AllocationDisplay<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let AllocationDisplay {
+            alloc,
+            callstacks,
+            clock,
+            shown_frames,
+        } = self;
         writeln!(f, "Allocation Details:")?;
-        writeln!(f, "├── Size: {}", format_bytes(self.size))?;
-        writeln!(f, "├── Peak Memory: {}", format_bytes(self.peak_mem))?;
-        writeln!(f, "├── Peak Timestamps: {:?}", self.peak_timestamps)?;
+        writeln!(f, "├── Action: {}", alloc.action)?;
+        writeln!(f, "├── Address: {:#x}", alloc.addr)?;
+        writeln!(f, "├── Stream: {}", alloc.stream)?;
+        writeln!(f, "├── Time: {} us", alloc.time_us)?;
+        writeln!(f, "├── Size: {}", format_bytes(alloc.size))?;
+        writeln!(f, "├── Peak Memory: {}", format_bytes(alloc.peak_mem))?;
+        writeln!(f, "├── Peak Timestamps: {:?}", alloc.peak_timestamps)?;
+        let (start, stop) = alloc.start_end_time();
         writeln!(
             f,
             "├── Timesteps: start {}, stop {}",
-            self.timesteps.first().unwrap_or(&0),
-            self.timesteps.last().unwrap_or(&0)
+            format_timestamp(start, *clock),
+            format_timestamp(stop, *clock)
         )?;
         writeln!(f, "├── Offsets: omitted")?;
         // Or print offsets if desired:
-        // writeln!(f, "├── Offsets: {:?}", self.offsets)?;
+        // writeln!(f, "├── Offsets: {:?}", alloc.offsets)?;
 
         writeln!(f, "└── Callstack:")?;
-        if self.callstack.is_empty() {
+        // keep the depth of each frame in the whole callstack
+        let frames: Vec<_> = callstacks
+            .frame_ids(alloc.stack)
+            .iter()
+            .enumerate()
+            .filter(|&(_, &id)| shown_frames.is_none_or(|shown| shown[id as usize]))
+            .map(|(i, &id)| (i, callstacks.frame(id)))
+            .collect();
+        let hidden = callstacks.frame_ids(alloc.stack).len() - frames.len();
+        let depth = frames.len();
+        if depth == 0 && hidden == 0 {
             writeln!(f, "    └── (empty callstack)")?;
         } else {
-            for (i, frame) in self.callstack.iter().enumerate() {
-                let prefix = if i == self.callstack.len() - 1 {
+            for (n, (i, frame)) in frames.iter().enumerate() {
+                let prefix = if n == depth - 1 && hidden == 0 {
                     "    └──"
                 } else {
                     "    ├──"
                 };
                 writeln!(f, "{} ({}){}", prefix, i, frame)?;
             }
+            if hidden > 0 {
+                writeln!(f, "    └── ({} frames hidden, see `frames`)", hidden)?;
+            }
         }
 
         Ok(())
     }
 }
 
+impl<'a> AllocationDisplay<'a> {
+    pub fn with_clock(self, clock: Option<&'a Clock>) -> Self {
+        AllocationDisplay { clock, ..self }
+    }
+
+    pub fn with_shown_frames(self, shown_frames: &'a [bool]) -> Self {
+        AllocationDisplay {
+            shown_frames: Some(shown_frames),
+            ..self
+        }
+    }
+}
+
 impl Allocation {
+    pub fn display<'a>(&'a self, callstacks: &'a CallstackTable) -> AllocationDisplay<'a> {
+        AllocationDisplay {
+            alloc: self,
+            callstacks,
+            clock: None,
+            shown_frames: None,
+        }
+    }
+
     pub fn is_alive_in_interval(&self, start: u64, stop: u64) -> bool {
         self.is_alive_at(start) && self.is_alive_at(stop)
     }
 
     pub fn is_alive_at(&self, timestamp: u64) -> bool {
-        self.timesteps[0] <= timestamp && timestamp <= *self.timesteps.last().unwrap()
+        match (self.timesteps.first(), self.timesteps.last()) {
+            (Some(&start), Some(&stop)) => start <= timestamp && timestamp <= stop,
+            _ => false, // malformed, see `check`
+        }
     }
 
     pub fn start_end_time(&self) -> (u64, u64) {
-        (self.timesteps[0], *self.timesteps.last().unwrap())
+        (
+            self.timesteps.first().copied().unwrap_or(0),
+            self.timesteps.last().copied().unwrap_or(0),
+        )
     }
 }
 
 // Intermediate struct to help parse the structure of allocations.json
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct RawAllocationData {
     pub timesteps: Vec<u64>,
     pub offsets: Vec<u64>,
@@ -85,8 +166,16 @@ pub struct RawAllocationData {
 }
 
 // Intermediate struct to help parse the structure of elements.json
-// Each element in elements.json contains a list of frames.
-#[derive(Deserialize)]
+// Each element in elements.json is a device trace event, which contains a list of frames.
+#[derive(Deserialize, Serialize, Debug)]
 pub struct ElementData {
+    pub action: String, // alloc, free_requested, free_completed, segment_alloc, ...
+    pub addr: u64,
+    pub size: u64,
+    #[serde(default)]
+    pub stream: u64,
+    #[serde(default)]
+    pub time_us: u64,
+    #[serde(default)]
     pub frames: Vec<Frame>,
 }
@@ -0,0 +1,261 @@
+use crate::pickle::Segment;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// State of an allocated block, blocks that are not in a segment's `blocks` are free
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockState {
+    ActiveAllocated,
+    ActiveAwaitingFree, // freed by the program, but still in use by a stream
+}
+
+/// Trace actions that change the state of the caching allocator
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocatorAction {
+    SegmentAlloc,
+    SegmentFree,
+    Alloc,
+    Free, // legacy traces free blocks without free_requested
+    FreeRequested,
+    FreeCompleted,
+}
+
+impl AllocatorAction {
+    pub fn parse(action: &str) -> Option<Self> {
+        match action {
+            // expandable segments map and unmap ranges instead, each mapped range is a segment here
+            "segment_alloc" | "segment_map" => Some(AllocatorAction::SegmentAlloc),
+            "segment_free" | "segment_unmap" => Some(AllocatorAction::SegmentFree),
+            "alloc" => Some(AllocatorAction::Alloc),
+            "free" => Some(AllocatorAction::Free),
+            "free_requested" => Some(AllocatorAction::FreeRequested),
+            "free_completed" => Some(AllocatorAction::FreeCompleted),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AllocatorEvent {
+    pub action: AllocatorAction,
+    pub addr: u64,
+    pub size: u64,
+    pub stream: u64,
+    pub timestep: u64, // first timestep at which the event is visible
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct BlockInfo {
+    pub size: u64,
+    pub state: BlockState,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SegmentState {
+    pub address: u64,
+    pub total_size: u64,
+    pub stream: u64,
+    pub blocks: BTreeMap<u64, BlockInfo>, // address -> block, only the active blocks
+}
+
+impl SegmentState {
+    /// Bytes held by active blocks (allocated or awaiting free)
+    pub fn allocated(&self) -> u64 {
+        self.blocks.values().map(|block| block.size).sum()
+    }
+}
+
+/// Segments of one device, keyed by address
+pub type Segments = BTreeMap<u64, SegmentState>;
+
+/// Segments and blocks of the caching allocator over time: the state before the trace,
+/// and every allocator event of the trace in order.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SegmentHistory {
+    initial: Segments,
+    events: Vec<AllocatorEvent>,
+}
+
+impl SegmentHistory {
+    /// Rebuilds the history from the segments at the end of the trace (`snapshot["segments"]`),
+    /// by undoing `events` from last to first.
+    pub fn rewind(final_segments: &[Segment], events: Vec<AllocatorEvent>) -> Self {
+        let mut segments: Segments = final_segments
+            .iter()
+            .map(|segment| {
+                let blocks = segment
+                    .blocks
+                    .iter()
+                    .filter_map(|block| {
+                        let state = match block.state.as_str() {
+                            "active_allocated" => BlockState::ActiveAllocated,
+                            "active_awaiting_free" => BlockState::ActiveAwaitingFree,
+                            _ => return None,
+                        };
+                        Some((
+                            block.address,
+                            BlockInfo {
+                                size: block.size,
+                                state,
+                            },
+                        ))
+                    })
+                    .collect();
+                let state = SegmentState {
+                    address: segment.address,
+                    total_size: segment.total_size,
+                    stream: segment.stream,
+                    blocks,
+                };
+                (segment.address, state)
+            })
+            .collect();
+
+        for event in events.iter().rev() {
+            undo(&mut segments, event);
+        }
+
+        SegmentHistory {
+            initial: segments,
+            events,
+        }
+    }
+
+    /// The history of a trace without the allocator state at its end (a json snapshot), replayed
+    /// from no segments: segments allocated before recording, and their blocks, are missing.
+    pub fn replay(events: Vec<AllocatorEvent>) -> Self {
+        SegmentHistory {
+            initial: Segments::new(),
+            events,
+        }
+    }
+
+    /// Whether a block at `addr` was allocated before the first event of the trace. Only a
+    /// history rewound from the allocator state (a pickle) knows such blocks.
+    pub fn allocated_before(&self, addr: u64) -> bool {
+        self.initial
+            .values()
+            .any(|segment| segment.blocks.contains_key(&addr))
+    }
+
+    /// Segments as they are at `timestep`
+    pub fn at(&self, timestep: u64) -> Segments {
+        let mut segments = self.initial.clone();
+        let visible = self.events.partition_point(|e| e.timestep <= timestep);
+        for event in &self.events[..visible] {
+            apply(&mut segments, event);
+        }
+        segments
+    }
+
+    /// `(timestep, reserved, allocated)` after every change, starting with the state before the trace
+    pub fn reserved_timeline(&self) -> Vec<(u64, u64, u64)> {
+        let mut reserved: u64 = self.initial.values().map(|s| s.total_size).sum();
+        let mut allocated: u64 = self.initial.values().map(|s| s.allocated()).sum();
+        let mut timeline = vec![(0, reserved, allocated)];
+
+        for event in &self.events {
+            match event.action {
+                AllocatorAction::SegmentAlloc => reserved += event.size,
+                AllocatorAction::SegmentFree => reserved = reserved.saturating_sub(event.size),
+                AllocatorAction::Alloc => allocated += event.size,
+                AllocatorAction::Free | AllocatorAction::FreeCompleted => {
+                    allocated = allocated.saturating_sub(event.size)
+                }
+                AllocatorAction::FreeRequested => continue,
+            }
+
+            match timeline.last_mut() {
+                Some(last) if last.0 == event.timestep => {
+                    *last = (event.timestep, reserved, allocated)
+                }
+                _ => timeline.push((event.timestep, reserved, allocated)),
+            }
+        }
+        timeline
+    }
+}
+
+/// The segment that contains `addr`
+fn segment_of(segments: &mut Segments, addr: u64) -> Option<&mut SegmentState> {
+    segments
+        .range_mut(..=addr)
+        .next_back()
+        .map(|(_, segment)| segment)
+        .filter(|segment| addr < segment.address + segment.total_size)
+}
+
+fn set_block(segments: &mut Segments, event: &AllocatorEvent, state: Option<BlockState>) {
+    let Some(segment) = segment_of(segments, event.addr) else {
+        debug!(
+            "Block {:#x} ({:?}) is not inside any known segment",
+            event.addr, event.action
+        );
+        return;
+    };
+    match state {
+        Some(state) => {
+            segment.blocks.insert(
+                event.addr,
+                BlockInfo {
+                    size: event.size,
+                    state,
+                },
+            );
+        }
+        None => {
+            segment.blocks.remove(&event.addr);
+        }
+    }
+}
+
+fn apply(segments: &mut Segments, event: &AllocatorEvent) {
+    match event.action {
+        AllocatorAction::SegmentAlloc => {
+            segments.insert(
+                event.addr,
+                SegmentState {
+                    address: event.addr,
+                    total_size: event.size,
+                    stream: event.stream,
+                    blocks: BTreeMap::new(),
+                },
+            );
+        }
+        AllocatorAction::SegmentFree => {
+            segments.remove(&event.addr);
+        }
+        AllocatorAction::Alloc => set_block(segments, event, Some(BlockState::ActiveAllocated)),
+        AllocatorAction::FreeRequested => {
+            set_block(segments, event, Some(BlockState::ActiveAwaitingFree))
+        }
+        AllocatorAction::Free | AllocatorAction::FreeCompleted => set_block(segments, event, None),
+    }
+}
+
+fn undo(segments: &mut Segments, event: &AllocatorEvent) {
+    match event.action {
+        AllocatorAction::SegmentAlloc => {
+            segments.remove(&event.addr);
+        }
+        AllocatorAction::SegmentFree => {
+            segments.insert(
+                event.addr,
+                SegmentState {
+                    address: event.addr,
+                    total_size: event.size,
+                    stream: event.stream,
+                    blocks: BTreeMap::new(),
+                },
+            );
+        }
+        AllocatorAction::Alloc => set_block(segments, event, None),
+        AllocatorAction::Free | AllocatorAction::FreeRequested => {
+            set_block(segments, event, Some(BlockState::ActiveAllocated))
+        }
+        AllocatorAction::FreeCompleted => {
+            set_block(segments, event, Some(BlockState::ActiveAwaitingFree))
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use crate::allocation::Frame;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+pub type FrameId = u32;
+pub type StackId = u32;
+
+/// Interned frames and callstacks. Allocations keep a `StackId` instead of their own frames,
+/// since most allocations of a dump share a handful of (long) stacks.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(from = "StoredTable")]
+pub struct CallstackTable {
+    frames: Vec<Frame>,
+    stacks: Vec<Box<[FrameId]>>, // innermost frame first, like the `frames` of a trace event
+
+    #[serde(skip)]
+    frame_ids: HashMap<Frame, FrameId>,
+    #[serde(skip)]
+    stack_ids: HashMap<Box<[FrameId]>, StackId>,
+}
+
+/// `CallstackTable` without its lookup maps, which are rebuilt on deserialization
+#[derive(Deserialize)]
+struct StoredTable {
+    frames: Vec<Frame>,
+    stacks: Vec<Box<[FrameId]>>,
+}
+
+impl From<StoredTable> for CallstackTable {
+    fn from(stored: StoredTable) -> Self {
+        let frame_ids = stored
+            .frames
+            .iter()
+            .enumerate()
+            .map(|(id, frame)| (frame.clone(), id as FrameId))
+            .collect();
+        let stack_ids = stored
+            .stacks
+            .iter()
+            .enumerate()
+            .map(|(id, stack)| (stack.clone(), id as StackId))
+            .collect();
+
+        CallstackTable {
+            frames: stored.frames,
+            stacks: stored.stacks,
+            frame_ids,
+            stack_ids,
+        }
+    }
+}
+
+impl CallstackTable {
+    pub fn intern_frame(&mut self, frame: Frame) -> FrameId {
+        if let Some(&id) = self.frame_ids.get(&frame) {
+            return id;
+        }
+        let id = self.frames.len() as FrameId;
+        self.frames.push(frame.clone());
+        self.frame_ids.insert(frame, id);
+        id
+    }
+
+    /// Id of the stack made of `frames`, adding the stack (and its frames) if it is new
+    pub fn intern_stack(&mut self, frames: Vec<Frame>) -> StackId {
+        let stack: Box<[FrameId]> = frames
+            .into_iter()
+            .map(|frame| self.intern_frame(frame))
+            .collect();
+
+        if let Some(&id) = self.stack_ids.get(&stack) {
+            return id;
+        }
+        let id = self.stacks.len() as StackId;
+        self.stacks.push(stack.clone());
+        self.stack_ids.insert(stack, id);
+        id
+    }
+
+    pub fn frame(&self, id: FrameId) -> &Frame {
+        &self.frames[id as usize]
+    }
+
+    pub fn frame_ids(&self, stack: StackId) -> &[FrameId] {
+        &self.stacks[stack as usize]
+    }
+
+    /// Frames of `stack`, innermost first
+    pub fn frames(
+        &self,
+        stack: StackId,
+    ) -> impl DoubleEndedIterator<Item = &Frame> + ExactSizeIterator + '_ {
+        self.frame_ids(stack).iter().map(|&id| self.frame(id))
+    }
+
+    pub fn num_frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn num_stacks(&self) -> usize {
+        self.stacks.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CallstackTable;
+    use crate::allocation::Frame;
+
+    #[test]
+    fn test_intern() {
+        let frame = |name: &str, line| Frame {
+            name: name.to_string(),
+            filename: "model.py".to_string(),
+            line,
+        };
+
+        let mut table = CallstackTable::default();
+        let a = table.intern_stack(vec![frame("forward", 10), frame("main", 3)]);
+        let b = table.intern_stack(vec![frame("backward", 20), frame("main", 3)]);
+        let c = table.intern_stack(vec![frame("forward", 10), frame("main", 3)]);
+        let empty = table.intern_stack(Vec::new());
+
+        assert_eq!(a, c);
+        assert_ne!(a, b);
+        assert_eq!(table.num_stacks(), 3);
+        assert_eq!(table.num_frames(), 3);
+        assert_eq!(table.frames(empty).len(), 0);
+        assert_eq!(
+            table.frames(b).map(|f| f.name.as_str()).collect::<Vec<_>>(),
+            vec!["backward", "main"]
+        );
+
+        // lookup maps survive a roundtrip
+        let bytes = bincode::serialize(&table).unwrap();
+        let mut restored: CallstackTable = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(
+            restored.intern_stack(vec![frame("backward", 20), frame("main", 3)]),
+            b
+        );
+        assert_eq!(restored.num_stacks(), 3);
+    }
+}
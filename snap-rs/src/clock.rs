@@ -0,0 +1,163 @@
+use crate::allocation::Allocation;
+use crate::utils::{format_duration, parse_duration};
+
+/// Maps the synthetic timesteps of a layout to the wall-clock `time_us` of the trace events.
+/// Only allocs (and frees of blocks allocated before recording) carry a time, so a timestep
+/// between two of them gets the time of the next one.
+#[derive(Debug, Clone)]
+pub struct Clock {
+    points: Vec<(u64, u64)>, // (timestep, time_us), both ascending
+}
+
+impl Clock {
+    /// None if the trace has no wall-clock times
+    pub fn from_allocations(allocations: &[Allocation]) -> Option<Clock> {
+        let mut points: Vec<(u64, u64)> = allocations
+            .iter()
+            .filter(|alloc| alloc.time_us > 0 && !alloc.timesteps.is_empty())
+            .map(|alloc| {
+                let (start, stop) = alloc.start_end_time();
+                match alloc.action.as_str() {
+                    // the event of a block allocated before recording is its free
+                    "free" | "free_completed" => (stop, alloc.time_us),
+                    _ => (start, alloc.time_us),
+                }
+            })
+            .collect();
+        if points.is_empty() {
+            return None;
+        }
+        points.sort();
+        points.dedup_by_key(|(timestep, _)| *timestep);
+
+        // events of different streams may be recorded slightly out of order
+        let mut latest = 0;
+        for (_, time_us) in points.iter_mut() {
+            latest = latest.max(*time_us);
+            *time_us = latest;
+        }
+
+        Some(Clock { points })
+    }
+
+    /// `time_us` of the first event of the trace
+    pub fn origin_us(&self) -> u64 {
+        self.points[0].1
+    }
+
+    /// `time_us` at `timestep`
+    pub fn time_us(&self, timestep: u64) -> u64 {
+        let next = self.points.partition_point(|&(t, _)| t < timestep);
+        self.points
+            .get(next)
+            .unwrap_or(self.points.last().unwrap())
+            .1
+    }
+
+    /// Microseconds from the first event of the trace to `timestep`
+    pub fn elapsed_us(&self, timestep: u64) -> u64 {
+        self.time_us(timestep) - self.origin_us()
+    }
+
+    /// First timestep at which `elapsed_us` have passed, or the last event if the trace is shorter
+    pub fn timestep_at(&self, elapsed_us: u64) -> u64 {
+        let time_us = self.origin_us() + elapsed_us;
+        match self.points.partition_point(|&(_, t)| t < time_us) {
+            0 => 0,
+            next if next == self.points.len() => self.points[next - 1].0,
+            // the timesteps after the previous event already get the time of the next one
+            next => self.points[next - 1].0 + 1,
+        }
+    }
+}
+
+/// Parses a `@timestamp` argument: a timestep (`@1200`), or the time elapsed since the first event
+/// of the trace (`@12.5s`, `@+340ms`), which requires a `clock`
+pub fn parse_timestamp(s: &str, clock: Option<&Clock>) -> anyhow::Result<u64> {
+    let ts = s
+        .strip_prefix('@')
+        .ok_or_else(|| anyhow::anyhow!("Invalid timestamp format: {} (expected @timestamp)", s))?;
+
+    if let Ok(timestep) = ts.parse::<u64>() {
+        return Ok(timestep);
+    }
+
+    let elapsed_us = parse_duration(ts.strip_prefix('+').unwrap_or(ts))
+        .map_err(|_| anyhow::anyhow!("Invalid timestamp format: {}", s))?;
+    let clock = clock.ok_or_else(|| {
+        anyhow::anyhow!(
+            "This trace has no wall-clock times (time_us), use a timestep instead of {}",
+            s
+        )
+    })?;
+    Ok(clock.timestep_at(elapsed_us))
+}
+
+/// Parses a `@start..end` range of timestamps, both inclusive, e.g. `@100..200` or `@1.5s..@2s`
+pub fn parse_time_range(s: &str, clock: Option<&Clock>) -> anyhow::Result<(u64, u64)> {
+    let (start, end) = s
+        .split_once("..")
+        .ok_or_else(|| anyhow::anyhow!("Expected @start..end, got: {}", s))?;
+    let parse = |t: &str| parse_timestamp(&format!("@{}", t.trim().trim_start_matches('@')), clock);
+    let (start, end) = (parse(start)?, parse(end)?);
+    if start > end {
+        return Err(anyhow::anyhow!("Empty time range: {}", s));
+    }
+    Ok((start, end))
+}
+
+/// `@timestep`, followed by the elapsed time if there is a clock
+pub fn format_timestamp(timestep: u64, clock: Option<&Clock>) -> String {
+    match clock {
+        Some(clock) => format!(
+            "@{} (+{})",
+            timestep,
+            format_duration(clock.elapsed_us(timestep))
+        ),
+        None => format!("@{}", timestep),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clock, format_timestamp, parse_time_range, parse_timestamp};
+    use crate::repl_ops::memsnap::test_snapshot;
+
+    #[test]
+    fn test_clock() {
+        let allocations = test_snapshot().allocations;
+
+        let clock = Clock::from_allocations(&allocations).unwrap();
+        assert_eq!(clock.elapsed_us(0), 0);
+
+        // time never goes backwards, and maps back to the first timestep at that time
+        let last = allocations
+            .iter()
+            .map(|a| a.start_end_time().1)
+            .max()
+            .unwrap();
+        let mut previous = 0;
+        for timestep in 0..=last {
+            let elapsed = clock.elapsed_us(timestep);
+            assert!(elapsed >= previous);
+            assert!(clock.timestep_at(elapsed) <= timestep);
+            assert_eq!(clock.elapsed_us(clock.timestep_at(elapsed)), elapsed);
+            previous = elapsed;
+        }
+
+        assert_eq!(parse_timestamp("@42", None).unwrap(), 42);
+        assert!(parse_timestamp("@1.5s", None).is_err());
+        assert!(parse_timestamp("42", Some(&clock)).is_err());
+        let end = clock.elapsed_us(last);
+        assert_eq!(
+            parse_timestamp(&format!("@+{}us", end), Some(&clock)).unwrap(),
+            clock.timestep_at(end)
+        );
+        assert_eq!(parse_timestamp("@0s", Some(&clock)).unwrap(), 0);
+        assert_eq!(parse_time_range("@3..@17", None).unwrap(), (3, 17));
+        assert!(parse_time_range("@17..3", None).is_err());
+
+        assert_eq!(format_timestamp(last, None), format!("@{}", last));
+        assert!(format_timestamp(last, Some(&clock)).starts_with(&format!("@{} (+", last)));
+    }
+}
@@ -0,0 +1,191 @@
+use crate::allocation::Frame;
+use crate::callstack::{CallstackTable, FrameId};
+use regex::Regex;
+use std::fmt::{Display, Formatter};
+
+/// Which frames are shown before the `hide`/`keep` regexes apply
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FramePreset {
+    #[default]
+    All,
+    PythonOnly,
+    CppOnly,
+    UserCodeOnly, // Python frames outside of packages and the standard library
+}
+
+impl FramePreset {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "all" => Some(FramePreset::All),
+            "python-only" => Some(FramePreset::PythonOnly),
+            "cpp-only" => Some(FramePreset::CppOnly),
+            "user-code-only" => Some(FramePreset::UserCodeOnly),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            FramePreset::All => "all",
+            FramePreset::PythonOnly => "python-only",
+            FramePreset::CppOnly => "cpp-only",
+            FramePreset::UserCodeOnly => "user-code-only",
+        }
+    }
+
+    fn shows(&self, frame: &Frame) -> bool {
+        match self {
+            FramePreset::All => true,
+            FramePreset::PythonOnly => frame.is_python(),
+            FramePreset::CppOnly => !frame.is_python(),
+            FramePreset::UserCodeOnly => frame.is_user_code(),
+        }
+    }
+}
+
+/// Frames printed and exported for each callstack. A frame is shown if it matches a `keep`
+/// regex, or else if the preset shows it and it matches no `hide` regex. Regexes are matched
+/// against "filename:line:name", like `frame ~` in filters.
+#[derive(Debug, Clone, Default)]
+pub struct FrameFilter {
+    pub preset: FramePreset,
+    pub hide: Vec<Regex>,
+    pub keep: Vec<Regex>,
+}
+
+impl PartialEq for FrameFilter {
+    fn eq(&self, other: &Self) -> bool {
+        let patterns =
+            |regexes: &[Regex]| regexes.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+        self.preset == other.preset
+            && patterns(&self.hide) == patterns(&other.hide)
+            && patterns(&self.keep) == patterns(&other.keep)
+    }
+}
+
+impl FrameFilter {
+    /// Applies one rule: `preset <name>`, `hide <regex>`, `keep <regex>` or `clear`. The regex
+    /// may be quoted, like strings in filters.
+    pub fn apply(&mut self, rule: &str) -> anyhow::Result<()> {
+        let (command, arg) = rule.split_once(char::is_whitespace).unwrap_or((rule, ""));
+        let arg = unquote(arg.trim());
+        let regex =
+            || Regex::new(arg).map_err(|e| anyhow::anyhow!("Invalid regex '{}': {}", arg, e));
+        match command {
+            "preset" => self.preset = FramePreset::parse(arg).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Unknown preset: '{}', expected all, python-only, cpp-only or user-code-only",
+                    arg
+                )
+            })?,
+            "hide" if !arg.is_empty() => self.hide.push(regex()?),
+            "keep" if !arg.is_empty() => self.keep.push(regex()?),
+            "clear" if arg.is_empty() => *self = FrameFilter::default(),
+            _ => return Err(anyhow::anyhow!("Invalid frame filter rule: '{}'", rule)),
+        }
+        Ok(())
+    }
+
+    /// Reads a config file of rules, one per line; blank lines and `#` comments are skipped
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read frame filter '{}': {}", path, e))?;
+        let mut filter = FrameFilter::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            filter
+                .apply(line)
+                .map_err(|e| anyhow::anyhow!("{}:{}: {}", path, number + 1, e))?;
+        }
+        Ok(filter)
+    }
+
+    pub fn shows(&self, frame: &Frame) -> bool {
+        let text = format!("{}:{}:{}", frame.filename, frame.line, frame.name);
+        self.keep.iter().any(|r| r.is_match(&text))
+            || (self.preset.shows(frame) && !self.hide.iter().any(|r| r.is_match(&text)))
+    }
+
+    /// Whether each frame of `callstacks` is shown, by frame id
+    pub fn mask(&self, callstacks: &CallstackTable) -> Vec<bool> {
+        (0..callstacks.num_frames() as FrameId)
+            .map(|id| self.shows(callstacks.frame(id)))
+            .collect()
+    }
+}
+
+/// `s` without the quotes around it, if it is quoted
+fn unquote(s: &str) -> &str {
+    ['"', '\'']
+        .iter()
+        .find_map(|&q| s.strip_prefix(q)?.strip_suffix(q))
+        .unwrap_or(s)
+}
+
+/// The rules, in the config file format
+impl Display for FrameFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "preset {}", self.preset.name())?;
+        for regex in &self.hide {
+            write!(f, "\nhide {}", regex)?;
+        }
+        for regex in &self.keep {
+            write!(f, "\nkeep {}", regex)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameFilter, FramePreset};
+    use crate::allocation::Frame;
+
+    #[test]
+    fn test_frame_filter() {
+        let frame = |filename: &str, name: &str| Frame {
+            name: name.to_string(),
+            filename: filename.to_string(),
+            line: 1,
+        };
+        let unwind = frame("??", "torch::unwind::unwind()");
+        let torch = frame(
+            "/env/lib/python3.10/site-packages/torch/nn/modules/linear.py",
+            "forward",
+        );
+        let user = frame("/home/me/train.py", "step");
+
+        let mut filter = FrameFilter::default();
+        assert!([&unwind, &torch, &user].iter().all(|f| filter.shows(f)));
+
+        filter.apply("preset user-code-only").unwrap();
+        assert!(!filter.shows(&unwind) && !filter.shows(&torch) && filter.shows(&user));
+
+        filter.apply("keep nn/modules/linear").unwrap();
+        filter.apply("hide train\\.py:").unwrap();
+        assert!(filter.shows(&torch) && !filter.shows(&user));
+
+        // the config file format is the rules themselves
+        let path = std::env::temp_dir().join("tomi_test_frames.conf");
+        std::fs::write(&path, format!("# comment\n\n{}\n", filter)).unwrap();
+        let loaded = FrameFilter::load(path.to_str().unwrap()).unwrap();
+        assert_eq!(loaded, filter);
+        std::fs::remove_file(&path).unwrap();
+
+        // alternation, quoted or not
+        for rule in ["hide unwind|c10", "hide \"unwind|c10\""] {
+            let mut filter = FrameFilter::default();
+            filter.apply(rule).unwrap();
+            assert_eq!(filter.hide[0].as_str(), "unwind|c10");
+            assert!(!filter.shows(&unwind) && filter.shows(&torch));
+        }
+
+        filter.apply("clear").unwrap();
+        assert_eq!(filter.preset, FramePreset::All);
+        assert!(filter.apply("preset nope").is_err());
+        assert!(filter.apply("hide (").is_err());
+    }
+}
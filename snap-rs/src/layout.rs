@@ -0,0 +1,268 @@
+use crate::{
+    allocation::RawAllocationData,
+    allocator::{AllocatorAction, AllocatorEvent},
+    pickle::TraceEvent,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Number of timesteps spent sliding the blocks above a freed allocation down.
+const FREE_SHIFT_STEPS: u64 = 3;
+
+/// How allocations are placed on the y axis (`offsets`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Layout {
+    /// Allocations are stacked on top of each other in allocation order, and the blocks above a
+    /// freed allocation slide down to fill the gap (the layout of `parse_dump.py` and PyTorch's viewer).
+    #[default]
+    Stack,
+    /// Allocations stay at their real device address (relative to the lowest address in the trace),
+    /// so fragmentation and address reuse become visible. Timesteps are the same as in `Stack`.
+    Address,
+}
+
+impl FromStr for Layout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stack" => Ok(Layout::Stack),
+            "address" | "addr" => Ok(Layout::Address),
+            _ => Err(anyhow::anyhow!(
+                "Unknown layout: {}, expected `stack` or `address`",
+                s
+            )),
+        }
+    }
+}
+
+/// The trace split into allocation elements and the order in which they are allocated/freed.
+/// Events are pushed one at a time, so a trace can be laid out while it is still being parsed.
+#[derive(Default)]
+pub struct TraceActions {
+    elements: Vec<TraceEvent>,
+    initially_allocated: Vec<usize>, // freed in the trace, but allocated before recording started
+    actions: Vec<usize>,             // element index of every alloc/free, in trace order
+    events: Vec<AllocatorEvent>,     // timestep: number of actions before the event, until laid out
+    addr_to_alloc: HashMap<u64, usize>,
+    num_frees: usize,
+}
+
+impl TraceActions {
+    pub fn push(&mut self, event: TraceEvent) {
+        if let Some(action) = AllocatorAction::parse(&event.action) {
+            self.events.push(AllocatorEvent {
+                action,
+                addr: event.addr,
+                size: event.size,
+                stream: event.stream,
+                timestep: self.actions.len() as u64,
+            });
+        }
+
+        match event.action.as_str() {
+            "alloc" | "segment_alloc" => {
+                self.addr_to_alloc.insert(event.addr, self.elements.len());
+                self.actions.push(self.elements.len());
+                self.elements.push(event);
+            }
+            "free" | "free_completed" => {
+                self.num_frees += 1;
+                match self.addr_to_alloc.remove(&event.addr) {
+                    Some(elem) => self.actions.push(elem),
+                    None => {
+                        self.initially_allocated.push(self.elements.len());
+                        self.actions.push(self.elements.len());
+                        self.elements.push(event);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A device trace after layout
+pub struct LaidOutTrace {
+    pub allocations: Vec<RawAllocationData>, // allocations[i] is the layout of elements[i]
+    pub elements: Vec<TraceEvent>,
+    pub allocator_events: Vec<AllocatorEvent>, // with the timestep they happen at in this layout
+}
+
+/// Rust port of `process_alloc_data` in `parse_dump.py`: lays out the events of one device trace.
+///
+/// Returns `(allocations, elements)` where `allocations[i]` is the layout of `elements[i]`.
+/// Note: unlike `parse_dump.py`, allocations are ordered by element, so that the two vectors line up
+/// even when the trace starts with blocks that were allocated before recording.
+pub fn process_alloc_data(
+    device_trace: Vec<TraceEvent>,
+    layout: Layout,
+) -> (Vec<RawAllocationData>, Vec<TraceEvent>) {
+    info!("Processing events");
+    let mut trace = TraceActions::default();
+    for event in device_trace {
+        trace.push(event);
+    }
+
+    let laid_out = layout_actions(trace, layout);
+    (laid_out.allocations, laid_out.elements)
+}
+
+/// Lays out a trace whose events have all been pushed, see `process_alloc_data`
+pub fn layout_actions(mut trace: TraceActions, layout: Layout) -> LaidOutTrace {
+    if trace.num_frees == 0 && !trace.elements.is_empty() {
+        warn!("Trace contains no free events, every allocation will live until the end");
+    }
+
+    let (mut data, action_timesteps) = stack_layout(&trace);
+
+    if layout == Layout::Address {
+        address_layout(&mut data, trace.elements.iter().map(|e| e.addr));
+    }
+
+    // the address layout keeps the timesteps of the stack layout
+    for event in trace.events.iter_mut() {
+        let timestep = action_timesteps[event.timestep as usize];
+        event.timestep = match event.action {
+            // a freed block is still drawn at the timestep of its free
+            AllocatorAction::Free | AllocatorAction::FreeCompleted => timestep + 1,
+            // everything else is visible from the timestep of the next action on
+            _ => timestep,
+        };
+    }
+
+    LaidOutTrace {
+        allocations: data,
+        elements: trace.elements,
+        allocator_events: trace.events,
+    }
+}
+
+/// Also returns the timestep at which each action (and the end of the trace) happens
+fn stack_layout(trace: &TraceActions) -> (Vec<RawAllocationData>, Vec<u64>) {
+    let mut data: Vec<RawAllocationData> = trace
+        .elements
+        .iter()
+        .map(|element| RawAllocationData {
+            timesteps: Vec::new(),
+            offsets: Vec::new(),
+            size: element.size,
+        })
+        .collect();
+
+    // elements currently on the stack, bottom to top
+    let mut current: Vec<usize> = Vec::new();
+    let mut total_mem = 0;
+    let mut timestep = 0;
+
+    info!("Processing initial allocations");
+    for &elem in trace.initially_allocated.iter().rev() {
+        current.push(elem);
+        data[elem].timesteps.push(timestep);
+        data[elem].offsets.push(total_mem);
+        total_mem += data[elem].size;
+    }
+
+    info!("Processing actions");
+    let mut action_timesteps = Vec::with_capacity(trace.actions.len() + 1);
+    for &elem in &trace.actions {
+        action_timesteps.push(timestep);
+        let size = data[elem].size;
+
+        match current.iter().rposition(|&e| e == elem) {
+            None => {
+                // new allocation, goes on top of the stack
+                current.push(elem);
+                data[elem].timesteps.push(timestep);
+                data[elem].offsets.push(total_mem);
+                total_mem += size;
+                timestep += 1;
+            }
+            Some(idx) => {
+                // free: close this allocation, then shift everything above it down
+                push_last_offset(&mut data[elem], timestep);
+                current.remove(idx);
+
+                if idx < current.len() {
+                    for &above in &current[idx..] {
+                        let entry = &mut data[above];
+                        push_last_offset(entry, timestep);
+                        let offset = *entry.offsets.last().unwrap() - size;
+                        entry.timesteps.push(timestep + FREE_SHIFT_STEPS);
+                        entry.offsets.push(offset);
+                    }
+                    timestep += FREE_SHIFT_STEPS;
+                }
+
+                total_mem -= size;
+                timestep += 1;
+            }
+        }
+    }
+
+    // close everything that is still alive at the end of the trace
+    for &elem in &current {
+        push_last_offset(&mut data[elem], timestep);
+    }
+    action_timesteps.push(timestep);
+
+    (data, action_timesteps)
+}
+
+/// Re-places already laid out allocations at their device address, keeping their lifetimes.
+/// `addrs` yields the address of each allocation, in the same order as `data`.
+pub fn address_layout(data: &mut [RawAllocationData], addrs: impl Iterator<Item = u64> + Clone) {
+    let base = addrs.clone().min().unwrap_or(0);
+
+    for (entry, addr) in data.iter_mut().zip(addrs) {
+        pin_offset(&mut entry.timesteps, &mut entry.offsets, addr - base);
+    }
+}
+
+/// Keeps only the first and last timestep of an allocation, both at `offset`
+pub fn pin_offset(timesteps: &mut Vec<u64>, offsets: &mut Vec<u64>, offset: u64) {
+    let (Some(&start), Some(&stop)) = (timesteps.first(), timesteps.last()) else {
+        return;
+    };
+    *timesteps = vec![start, stop];
+    *offsets = vec![offset; 2];
+}
+
+/// Repeat the last offset of `entry` at `timestep`.
+fn push_last_offset(entry: &mut RawAllocationData, timestep: u64) {
+    let offset = *entry.offsets.last().unwrap();
+    entry.timesteps.push(timestep);
+    entry.offsets.push(offset);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Layout, process_alloc_data};
+    use crate::pickle::read_torch_snapshot;
+
+    #[test]
+    fn test_address_layout() {
+        let pickle_path = "../snapshots/snapshot.pickle";
+
+        let trace = || {
+            read_torch_snapshot(pickle_path)
+                .unwrap()
+                .device_traces
+                .remove(0)
+        };
+
+        let (stacked, elements) = process_alloc_data(trace(), Layout::Stack);
+        let (placed, _) = process_alloc_data(trace(), Layout::Address);
+        let base = elements.iter().map(|e| e.addr).min().unwrap();
+
+        assert_eq!(stacked.len(), placed.len());
+        for ((s, p), element) in stacked.iter().zip(placed.iter()).zip(elements.iter()) {
+            // same lifetime, constant offset at the real address
+            assert_eq!(s.timesteps.first(), p.timesteps.first());
+            assert_eq!(s.timesteps.last(), p.timesteps.last());
+            assert_eq!(p.offsets, vec![element.addr - base; 2]);
+        }
+    }
+}
@@ -1,5 +1,13 @@
 pub mod allocation;
+pub mod allocator;
+pub mod callstack;
+pub mod clock;
+pub mod frame_filter;
+pub mod layout;
+pub mod lifetime;
 pub mod load;
+pub mod pickle;
 pub mod repl;
 pub mod repl_ops;
+pub mod stream;
 pub mod utils;
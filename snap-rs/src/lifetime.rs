@@ -0,0 +1,231 @@
+use crate::allocation::Allocation;
+use crate::repl_ops::memsnap::AllocationIndex;
+
+/// Lifetime `[start, stop]` of an allocation, both inclusive, like `Allocation::is_alive_at`
+type Interval = (u64, u64, AllocationIndex);
+
+/// A node of a centered interval tree: the intervals that contain `center`, and the subtrees of
+/// those entirely before and after it
+#[derive(Debug, Clone)]
+struct Node {
+    center: u64,
+    by_start: Vec<Interval>, // ascending start
+    by_stop: Vec<Interval>,  // descending stop
+    before: Option<usize>,
+    after: Option<usize>,
+}
+
+/// Index of the allocation lifetimes, answering which allocations are alive at a timestamp or in
+/// a range in O(log n + k), with O(n) memory
+#[derive(Debug, Clone, Default)]
+pub struct LifetimeIndex {
+    nodes: Vec<Node>,
+    root: Option<usize>,
+    starts: Vec<(u64, AllocationIndex)>, // ascending
+    stops: Vec<(u64, AllocationIndex)>,  // ascending
+}
+
+impl LifetimeIndex {
+    /// Allocations without timesteps, or stopping before they start, are never alive, see `check`
+    pub fn new(allocations: &[Allocation]) -> Self {
+        let intervals: Vec<Interval> = allocations
+            .iter()
+            .enumerate()
+            .filter(|(_, alloc)| !alloc.timesteps.is_empty())
+            .map(|(index, alloc)| {
+                let (start, stop) = alloc.start_end_time();
+                (start, stop, index)
+            })
+            .filter(|&(start, stop, _)| start <= stop)
+            .collect();
+
+        let mut starts: Vec<_> = intervals.iter().map(|&(s, _, i)| (s, i)).collect();
+        let mut stops: Vec<_> = intervals.iter().map(|&(_, e, i)| (e, i)).collect();
+        starts.sort_unstable();
+        stops.sort_unstable();
+
+        let mut index = LifetimeIndex {
+            starts,
+            stops,
+            ..Default::default()
+        };
+        index.root = index.build(intervals);
+        index
+    }
+
+    fn build(&mut self, intervals: Vec<Interval>) -> Option<usize> {
+        if intervals.is_empty() {
+            return None;
+        }
+
+        // the median endpoint is an endpoint of some interval, so every node holds at least one,
+        // and each subtree gets at most half of the endpoints
+        let mut endpoints: Vec<u64> = intervals.iter().flat_map(|&(s, e, _)| [s, e]).collect();
+        let mid = endpoints.len() / 2;
+        let center = *endpoints.select_nth_unstable(mid).1;
+
+        let (mut before, mut after, mut by_start) = (Vec::new(), Vec::new(), Vec::new());
+        for interval in intervals {
+            match interval {
+                (_, stop, _) if stop < center => before.push(interval),
+                (start, _, _) if start > center => after.push(interval),
+                _ => by_start.push(interval),
+            }
+        }
+        by_start.sort_unstable_by_key(|&(start, _, _)| start);
+        let mut by_stop = by_start.clone();
+        by_stop.sort_unstable_by_key(|&(_, stop, _)| std::cmp::Reverse(stop));
+
+        let id = self.nodes.len();
+        self.nodes.push(Node {
+            center,
+            by_start,
+            by_stop,
+            before: None,
+            after: None,
+        });
+        self.nodes[id].before = self.build(before);
+        self.nodes[id].after = self.build(after);
+        Some(id)
+    }
+
+    /// Allocations alive at `timestamp`, in no particular order
+    pub fn alive_at(&self, timestamp: u64) -> Vec<AllocationIndex> {
+        self.overlapping(timestamp, timestamp)
+    }
+
+    /// Allocations alive at some timestamp in `[from, to]`, in no particular order
+    pub fn overlapping(&self, from: u64, to: u64) -> Vec<AllocationIndex> {
+        let mut alive = Vec::new();
+        let mut stack: Vec<usize> = self.root.into_iter().collect();
+        while let Some(id) = stack.pop() {
+            let node = &self.nodes[id];
+            if to < node.center {
+                // every interval of the node stops at or after the center, after `to`
+                let starting = node
+                    .by_start
+                    .iter()
+                    .take_while(|&&(start, _, _)| start <= to);
+                alive.extend(starting.map(|&(_, _, index)| index));
+                stack.extend(node.before);
+            } else if from > node.center {
+                // and starts at or before the center, before `from`
+                let stopping = node
+                    .by_stop
+                    .iter()
+                    .take_while(|&&(_, stop, _)| stop >= from);
+                alive.extend(stopping.map(|&(_, _, index)| index));
+                stack.extend(node.after);
+            } else {
+                alive.extend(node.by_start.iter().map(|&(_, _, index)| index));
+                stack.extend(node.before);
+                stack.extend(node.after);
+            }
+        }
+        alive
+    }
+
+    /// Allocations alive at every timestamp of `[from, to]`, in no particular order
+    pub fn alive_throughout(&self, from: u64, to: u64) -> Vec<AllocationIndex> {
+        let mut alive = Vec::new();
+        let mut id = self.root;
+        while let Some(node) = id.map(|id| &self.nodes[id]) {
+            // the intervals that contain `from` are all on the path to it
+            let (containing, next): (Vec<&Interval>, _) = if from < node.center {
+                let starting = node
+                    .by_start
+                    .iter()
+                    .take_while(|&&(start, _, _)| start <= from);
+                (starting.collect(), node.before)
+            } else {
+                let stopping = node
+                    .by_stop
+                    .iter()
+                    .take_while(|&&(_, stop, _)| stop >= from);
+                (stopping.collect(), node.after)
+            };
+            alive.extend(
+                containing
+                    .into_iter()
+                    .filter(|&&(_, stop, _)| stop >= to)
+                    .map(|&(_, _, index)| index),
+            );
+            id = next;
+        }
+        alive
+    }
+
+    /// Allocations whose first timestep is in `[from, to]`, by first timestep
+    pub fn started_in(&self, from: u64, to: u64) -> Vec<AllocationIndex> {
+        Self::in_range(&self.starts, from, to)
+    }
+
+    /// Allocations whose last timestep is in `[from, to]`, by last timestep
+    pub fn stopped_in(&self, from: u64, to: u64) -> Vec<AllocationIndex> {
+        Self::in_range(&self.stops, from, to)
+    }
+
+    fn in_range(sorted: &[(u64, AllocationIndex)], from: u64, to: u64) -> Vec<AllocationIndex> {
+        let first = sorted.partition_point(|&(t, _)| t < from);
+        let last = sorted.partition_point(|&(t, _)| t <= to);
+        sorted[first..last.max(first)]
+            .iter()
+            .map(|&(_, index)| index)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LifetimeIndex;
+    use crate::repl_ops::memsnap::test_snapshot;
+
+    #[test]
+    fn test_lifetime_index() {
+        let allocations = test_snapshot().allocations;
+        let index = LifetimeIndex::new(&allocations);
+
+        let sorted = |mut indices: Vec<usize>| {
+            indices.sort();
+            indices
+        };
+        let brute = |keep: &dyn Fn(u64, u64) -> bool| -> Vec<usize> {
+            (0..allocations.len())
+                .filter(|&i| {
+                    let (start, stop) = allocations[i].start_end_time();
+                    !allocations[i].timesteps.is_empty() && keep(start, stop)
+                })
+                .collect()
+        };
+
+        let last = allocations
+            .iter()
+            .map(|a| a.start_end_time().1)
+            .max()
+            .unwrap();
+        for t in 0..=last + 1 {
+            let alive = (0..allocations.len())
+                .filter(|&i| allocations[i].is_alive_at(t))
+                .collect::<Vec<_>>();
+            assert_eq!(sorted(index.alive_at(t)), alive);
+        }
+        for (from, to) in [(0, 0), (3, 17), (20, 21), (40, last), (last, last + 5)] {
+            assert_eq!(
+                sorted(index.overlapping(from, to)),
+                brute(&|start, stop| start <= to && stop >= from)
+            );
+            assert_eq!(
+                sorted(index.alive_throughout(from, to)),
+                brute(&|start, stop| start <= from && stop >= to)
+            );
+            assert_eq!(
+                sorted(index.started_in(from, to)),
+                brute(&|start, _| from <= start && start <= to)
+            );
+            assert_eq!(
+                sorted(index.stopped_in(from, to)),
+                brute(&|_, stop| from <= stop && stop <= to)
+            );
+        }
+    }
+}
@@ -1,43 +1,62 @@
 use crate::allocation::{Allocation, ElementData, RawAllocationData};
+use crate::allocator::{AllocatorAction, AllocatorEvent, SegmentHistory};
+use crate::callstack::CallstackTable;
+use crate::layout::{Layout, TraceActions, layout_actions, pin_offset};
+use crate::pickle::{Segment, TraceEvent, read_torch_snapshot};
+use crate::stream::{JsonSource, for_each_entry};
 use log::info;
-use serde::Deserialize;
-use std::error::Error;
+use std::collections::BTreeMap;
 use std::fs;
 use std::fs::File;
-use std::io::{self, Read};
-use std::path::Path;
 use zip::ZipArchive;
 
 #[derive(Debug)]
 pub enum SnapType {
     Json {
-        allocations_path: String,
+        allocations_path: Option<String>,
         elements_path: String,
     },
     Zip {
         path: String,
+        device: usize,
     },
+    Pickle {
+        path: String,
+        device: usize,
+    },
+}
+
+/// The allocations of one device, with the table their callstacks are interned in
+#[derive(Debug, Default)]
+pub struct LoadedSnap {
+    pub allocations: Vec<Allocation>,
+    pub callstacks: CallstackTable,
+    pub segments: Option<SegmentHistory>, // complete when loaded from a pickle, which has the allocator state
 }
 
 #[derive(Debug)]
 pub struct RawSnap {
     pub(crate) dumptype: SnapType,
-    pub(crate) allocations: String,
-    pub(crate) elements: String,
+    pub(crate) allocations: Option<JsonSource>, // None: `elements` is the raw device trace, to be laid out by us
+    pub(crate) elements: JsonSource,
 }
 
-/// Unzips "allocations.json" and "elements.json" from a zip file into memory.
+/// Finds "allocations.json" and "elements.json" in a zip file. Nothing is decompressed yet:
+/// the entries are streamed when the snap is loaded by `load_allocations`.
 ///
 /// ## Arguments
 /// * `zip_file_path` - The path to the zip file.
 ///
 /// ## Returns
-/// A `Result` containing a tuple of `(Option<String>, Option<String>)` where the first
-/// `String` is the content of "allocations.json" and the second is the content of
-/// "elements.json", or an `io::Error` if an error occurs.
-pub fn read_snap_from_zip(zip_file_path: &str) -> anyhow::Result<RawSnap> {
-    let mut allocations: Option<String> = None;
-    let mut elements: Option<String> = None;
+/// A `Result` containing one `RawSnap` per device, or an error if "elements.json" is missing.
+/// "allocations.json" is optional: without it, "elements.json" is read as the raw device trace
+/// (`snapshot["device_traces"][device]`, e.g. from `parse_dump.py --trace`) and laid out on load.
+///
+/// A zip may hold several devices, with the json files of each device under a `device_<id>/`
+/// directory (as written by `parse_dump.py --all-devices`). Files outside such a directory belong to device 0.
+pub fn read_snap_from_zip(zip_file_path: &str) -> anyhow::Result<BTreeMap<usize, RawSnap>> {
+    let mut allocations: BTreeMap<usize, JsonSource> = BTreeMap::new();
+    let mut elements: BTreeMap<usize, JsonSource> = BTreeMap::new();
 
     // Open the zip file
     let file = File::open(zip_file_path)?;
@@ -47,7 +66,7 @@ pub fn read_snap_from_zip(zip_file_path: &str) -> anyhow::Result<RawSnap> {
 
     // Iterate over each file in the zip archive
     for i in 0..archive.len() {
-        let mut file = archive.by_index(i)?;
+        let file = archive.by_index(i)?;
 
         let outpath = match file.enclosed_name() {
             Some(path) => path.to_owned(),
@@ -56,138 +75,347 @@ pub fn read_snap_from_zip(zip_file_path: &str) -> anyhow::Result<RawSnap> {
 
         if outpath.extension().and_then(|s| s.to_str()) == Some("json") {
             let filename = outpath.file_name().and_then(|s| s.to_str()).unwrap_or("");
+            let device = outpath
+                .parent()
+                .into_iter()
+                .flat_map(|dir| dir.iter())
+                .filter_map(|dir| dir.to_str()?.strip_prefix("device_")?.parse::<usize>().ok())
+                .next_back()
+                .unwrap_or(0);
 
+            let source = JsonSource::ZipEntry {
+                zip_path: zip_file_path.to_string(),
+                index: i,
+                size: file.size(),
+            };
             if filename.contains("allocations") {
-                let mut content = String::new();
-                file.read_to_string(&mut content)?;
-                allocations = Some(content);
+                allocations.insert(device, source);
             } else if filename.contains("elements") {
-                let mut content = String::new();
-                file.read_to_string(&mut content)?;
-                elements = Some(content);
+                elements.insert(device, source);
             }
         }
     }
 
-    match (allocations, elements) {
-        (None, None) => Err(anyhow::anyhow!("allocations and elements not found!")),
-        (None, Some(_)) => Err(anyhow::anyhow!("allocations not found!")),
-        (Some(_), None) => Err(anyhow::anyhow!("elements not found!")),
-        (Some(allocs), Some(elems)) => Ok(RawSnap {
-            dumptype: SnapType::Zip {
-                path: zip_file_path.to_string(),
-            },
-            allocations: allocs,
-            elements: elems,
-        }),
+    if elements.is_empty() {
+        return Err(anyhow::anyhow!("elements not found!"));
+    }
+    if let Some(device) = allocations.keys().find(|d| !elements.contains_key(d)) {
+        return Err(anyhow::anyhow!("elements of device {} not found!", device));
     }
+
+    Ok(elements
+        .into_iter()
+        .map(|(device, elems)| {
+            let rawsnap = RawSnap {
+                dumptype: SnapType::Zip {
+                    path: zip_file_path.to_string(),
+                    device,
+                },
+                allocations: allocations.remove(&device),
+                elements: elems,
+            };
+            (device, rawsnap)
+        })
+        .collect())
 }
 
-pub fn read_snap_from_jsons(alloc_path: &str, elements_path: &str) -> anyhow::Result<RawSnap> {
-    // Read and parse allocations.json
-    info!("Loading: allocations");
-    let alloc_content = fs::read_to_string(alloc_path)
-        .map_err(|e| anyhow::anyhow!("Failed to read allocations file '{}': {}", alloc_path, e))?;
+fn json_file(path: &str, what: &str) -> anyhow::Result<JsonSource> {
+    let metadata = fs::metadata(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read {} file '{}': {}", what, path, e))?;
+    Ok(JsonSource::File {
+        path: path.to_string(),
+        size: metadata.len(),
+    })
+}
 
-    // Read and parse elements.json
-    info!("Loading: elements");
-    let elements_content = fs::read_to_string(elements_path)
-        .map_err(|e| anyhow::anyhow!("Failed to read elements file '{}': {}", elements_path, e))?;
+pub fn read_snap_from_jsons(alloc_path: &str, elements_path: &str) -> anyhow::Result<RawSnap> {
+    let alloc_content = json_file(alloc_path, "allocations")?;
+    let elements_content = json_file(elements_path, "elements")?;
 
     Ok(RawSnap {
         dumptype: SnapType::Json {
-            allocations_path: alloc_path.to_string(),
+            allocations_path: Some(alloc_path.to_string()),
             elements_path: elements_path.to_string(),
         },
-        allocations: alloc_content,
+        allocations: Some(alloc_content),
         elements: elements_content,
     })
 }
 
-pub fn load_allocations(rawsnap: RawSnap) -> Result<Vec<Allocation>, anyhow::Error> {
-    let raw_allocs: Vec<RawAllocationData> =
-        serde_json::from_str(&rawsnap.allocations).map_err(|e| {
-            anyhow::anyhow!(
-                "Failed to parse allocations JSON from '{:?}': {}",
-                rawsnap.dumptype,
-                e
-            )
+/// Reads a raw device trace (list of alloc/free/segment events) dumped as json, without allocations.json
+pub fn read_snap_from_trace_json(trace_path: &str) -> anyhow::Result<RawSnap> {
+    let trace_content = json_file(trace_path, "trace")?;
+
+    Ok(RawSnap {
+        dumptype: SnapType::Json {
+            allocations_path: None,
+            elements_path: trace_path.to_string(),
+        },
+        allocations: None,
+        elements: trace_content,
+    })
+}
+
+/// Streams the json files of `rawsnap` into allocations, without holding their text in memory
+pub fn load_allocations(rawsnap: RawSnap, layout: Layout) -> Result<LoadedSnap, anyhow::Error> {
+    let Some(allocations) = &rawsnap.allocations else {
+        // no precomputed layout, elements is the raw trace: lay it out while it is parsed
+        let mut trace = TraceActions::default();
+        for_each_entry(&rawsnap.elements, "trace events", |event: TraceEvent| {
+            trace.push(event)
         })?;
 
+        let laid_out = layout_actions(trace, layout);
+        let mut loaded =
+            build_allocations(&rawsnap.dumptype, laid_out.allocations, laid_out.elements)?;
+        loaded.segments = Some(SegmentHistory::replay(laid_out.allocator_events));
+        return Ok(loaded);
+    };
+
+    let mut allocs: Vec<Allocation> = Vec::new();
+    let mut callstacks = CallstackTable::default();
+    for_each_entry(
+        allocations,
+        "allocations",
+        |raw_alloc: RawAllocationData| allocs.push(new_allocation(raw_alloc)),
+    )?;
+
     // elements.json is a list, where each item has a "frames" key.
-    let elements_data: Vec<ElementData> = serde_json::from_str(&rawsnap.elements).map_err(|e| {
-        anyhow::anyhow!(
-            "Failed to parse elements JSON from '{:?}': {}",
-            rawsnap.dumptype,
-            e
-        )
+    let mut next = 0;
+    let num_elements = for_each_entry(&rawsnap.elements, "elements", |element: ElementData| {
+        if let Some(alloc) = allocs.get_mut(next) {
+            fill_element(alloc, element, &mut callstacks);
+        }
+        next += 1;
     })?;
+    check_counts(&rawsnap.dumptype, allocs.len(), num_elements)?;
 
-    // Check if the number of allocations matches the number of element data (callstacks)
-    if raw_allocs.len() != elements_data.len() {
-        return Err(anyhow::anyhow!(
-            "Mismatch in the number of entries: {} allocations vs {} elements",
-            raw_allocs.len(),
-            elements_data.len()
-        )
-        .into());
+    if layout == Layout::Address {
+        let base = allocs.iter().map(|a| a.addr).min().unwrap_or(0);
+        for alloc in allocs.iter_mut() {
+            pin_offset(&mut alloc.timesteps, &mut alloc.offsets, alloc.addr - base);
+        }
+    }
+    allocs.iter_mut().for_each(compute_peak);
+    let segments = SegmentHistory::replay(allocator_events(&allocs));
+
+    Ok(LoadedSnap {
+        allocations: allocs,
+        callstacks,
+        segments: Some(segments),
+    })
+}
+
+/// Loads the raw `torch.cuda.memory._snapshot()` pickle and lays out the trace of every device,
+/// exactly like `parse_dump.py` does before writing allocations.json and elements.json.
+pub fn load_allocations_from_pickle(
+    pickle_path: &str,
+    layout: Layout,
+) -> Result<BTreeMap<usize, LoadedSnap>, anyhow::Error> {
+    info!("Loading: pickle");
+    let snapshot = read_torch_snapshot(pickle_path)?;
+
+    let mut segments: BTreeMap<usize, Vec<Segment>> = BTreeMap::new();
+    for segment in snapshot.segments {
+        segments.entry(segment.device).or_default().push(segment);
+    }
+
+    snapshot
+        .device_traces
+        .into_iter()
+        .enumerate()
+        .map(|(device, trace)| {
+            info!("Laying out device {}", device);
+            let dumptype = SnapType::Pickle {
+                path: pickle_path.to_string(),
+                device,
+            };
+            let final_segments = segments.remove(&device).unwrap_or_default();
+            let allocations =
+                build_allocations_from_trace(&dumptype, trace, layout, &final_segments)?;
+            Ok((device, allocations))
+        })
+        .collect()
+}
+
+/// Lays out a raw device trace and combines it with the callstack of each element.
+/// `final_segments` is the allocator state at the end of the trace, to rebuild the segment history from.
+fn build_allocations_from_trace(
+    dumptype: &SnapType,
+    trace: Vec<TraceEvent>,
+    layout: Layout,
+    final_segments: &[Segment],
+) -> Result<LoadedSnap, anyhow::Error> {
+    let mut actions = TraceActions::default();
+    for event in trace {
+        actions.push(event);
     }
 
+    let laid_out = layout_actions(actions, layout);
+    let mut loaded = build_allocations(dumptype, laid_out.allocations, laid_out.elements)?;
+    loaded.segments = Some(SegmentHistory::rewind(
+        final_segments,
+        laid_out.allocator_events,
+    ));
+    Ok(loaded)
+}
+
+/// The allocator events of allocations that were laid out already (allocations.json), at the
+/// timesteps `layout_actions` gives them: a block is freed the timestep after it is last drawn,
+/// unless it lives until the end. allocations.json has no free_requested or segment_free events,
+/// so blocks are never awaiting free and segments are never released.
+fn allocator_events(allocations: &[Allocation]) -> Vec<AllocatorEvent> {
+    let trace_end = allocations
+        .iter()
+        .map(|alloc| alloc.start_end_time().1)
+        .max()
+        .unwrap_or(0);
+
+    let mut events = Vec::new();
+    for alloc in allocations {
+        let (start, stop) = alloc.start_end_time();
+        let event = |action, timestep| AllocatorEvent {
+            action,
+            addr: alloc.addr,
+            size: alloc.size,
+            stream: alloc.stream,
+            timestep,
+        };
+        match AllocatorAction::parse(&alloc.action) {
+            Some(AllocatorAction::SegmentAlloc) => {
+                events.push(event(AllocatorAction::SegmentAlloc, start))
+            }
+            Some(AllocatorAction::Alloc) => {
+                events.push(event(AllocatorAction::Alloc, start));
+                if stop < trace_end {
+                    events.push(event(AllocatorAction::Free, stop + 1));
+                }
+            }
+            // allocated before recording, only its free is in the trace
+            Some(AllocatorAction::Free | AllocatorAction::FreeCompleted) => {
+                events.push(event(AllocatorAction::Free, stop + 1))
+            }
+            _ => {}
+        }
+    }
+    events.sort_by_key(|event| event.timestep);
+    events
+}
+
+/// Combines the layout of each allocation with its element data (callstack)
+fn build_allocations(
+    dumptype: &SnapType,
+    raw_allocs: Vec<RawAllocationData>,
+    elements_data: Vec<ElementData>,
+) -> Result<LoadedSnap, anyhow::Error> {
+    // Check if the number of allocations matches the number of element data (callstacks)
+    check_counts(dumptype, raw_allocs.len(), elements_data.len())?;
+
     // Combine the data from raw_allocs and elements_data (callstacks)
+    let mut callstacks = CallstackTable::default();
     let allocations: Vec<Allocation> = raw_allocs
         .into_iter()
-        .zip(elements_data.into_iter())
+        .zip(elements_data)
         .map(|(raw_alloc, element_data)| {
-            let peak_base = *raw_alloc.offsets.iter().max().unwrap();
-            let peak_timestamps = raw_alloc
-                .timesteps
-                .iter()
-                .zip(raw_alloc.offsets.iter())
-                .filter_map(|(&timestamp, &offset)| {
-                    if offset == peak_base {
-                        // if this timestep has peak memory
-                        Some(timestamp)
-                    } else {
-                        None
-                    }
-                })
-                .collect();
-
-            let peak = peak_base + raw_alloc.size;
-            Allocation {
-                timesteps: raw_alloc.timesteps,
-                offsets: raw_alloc.offsets,
-                size: raw_alloc.size,
-                callstack: element_data.frames, // element_data.frames is Vec<Frame>
-                peak_mem: peak,
-                peak_timestamps,
-            }
+            let mut alloc = new_allocation(raw_alloc);
+            fill_element(&mut alloc, element_data, &mut callstacks);
+            compute_peak(&mut alloc);
+            alloc
         })
         .collect();
 
-    Ok(allocations)
+    Ok(LoadedSnap {
+        allocations,
+        callstacks,
+        segments: None,
+    })
+}
+
+fn check_counts(dumptype: &SnapType, allocations: usize, elements: usize) -> anyhow::Result<()> {
+    if allocations != elements {
+        return Err(anyhow::anyhow!(
+            "Mismatch in the number of entries in '{:?}': {} allocations vs {} elements",
+            dumptype,
+            allocations,
+            elements
+        ));
+    }
+    Ok(())
+}
+
+/// An allocation with only its layout; element data and peak are filled in later
+fn new_allocation(raw_alloc: RawAllocationData) -> Allocation {
+    Allocation {
+        timesteps: raw_alloc.timesteps,
+        offsets: raw_alloc.offsets,
+        size: raw_alloc.size,
+        stack: 0,
+        peak_mem: 0,
+        peak_timestamps: Vec::new(),
+        action: String::new(),
+        addr: 0,
+        stream: 0,
+        time_us: 0,
+    }
+}
+
+fn fill_element(
+    alloc: &mut Allocation,
+    element_data: ElementData,
+    callstacks: &mut CallstackTable,
+) {
+    alloc.stack = callstacks.intern_stack(element_data.frames); // element_data.frames is Vec<Frame>
+    alloc.action = element_data.action;
+    alloc.addr = element_data.addr;
+    alloc.stream = element_data.stream;
+    alloc.time_us = element_data.time_us;
+}
+
+/// Highest point of the allocation, and the timestamps at which it is reached
+fn compute_peak(alloc: &mut Allocation) {
+    let peak_base = alloc.offsets.iter().copied().max().unwrap_or(0);
+    alloc.peak_timestamps = alloc
+        .timesteps
+        .iter()
+        .zip(alloc.offsets.iter())
+        .filter_map(|(&timestamp, &offset)| {
+            if offset == peak_base {
+                // if this timestep has peak memory
+                Some(timestamp)
+            } else {
+                None
+            }
+        })
+        .collect();
+    alloc.peak_mem = peak_base + alloc.size;
 }
 
 #[cfg(test)]
 mod tests {
     use crate::repl_ops::memsnap::MemSnap;
 
-    use super::{load_allocations, read_snap_from_jsons, read_snap_from_zip};
+    use super::{load_allocations, load_allocations_from_pickle, read_snap_from_jsons};
+    use crate::layout::Layout;
+    use crate::pickle::unpickle;
 
     #[test]
     fn test_basic() {
         let alloc_path = "../snapshots/allocations.json";
         let elements_path = "../snapshots/elements.json";
 
-        match load_allocations(read_snap_from_jsons(alloc_path, elements_path).unwrap()) {
-            Ok(allocations) => {
+        match load_allocations(
+            read_snap_from_jsons(alloc_path, elements_path).unwrap(),
+            Layout::Stack,
+        ) {
+            Ok(loaded) => {
+                let allocations = &loaded.allocations;
                 if allocations.is_empty() {
                     println!("No allocations were loaded.");
                 } else {
                     println!("Successfully loaded {} allocations:", allocations.len());
 
                     if let Some(first_alloc) = allocations.first() {
-                        println!("{:#?}", first_alloc);
+                        println!("{}", first_alloc.display(&loaded.callstacks));
                     }
                     // println!("{:#?}", allocations);
                 }
@@ -202,7 +430,7 @@ mod tests {
     fn test_loadzip() {
         let zip_path = "../snapshots/large/transformer.zip";
 
-        match MemSnap::from_zip(zip_path) {
+        match MemSnap::from_zip(zip_path, Layout::Stack) {
             Ok(snap) => {
                 dbg!(snap.allocations.len());
             }
@@ -211,4 +439,48 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_pickle() {
+        let alloc_path = "../snapshots/allocations.json";
+        let elements_path = "../snapshots/elements.json";
+        let pickle_path = "../snapshots/snapshot.pickle";
+
+        let from_json = load_allocations(
+            read_snap_from_jsons(alloc_path, elements_path).unwrap(),
+            Layout::Stack,
+        )
+        .unwrap();
+        let mut devices = load_allocations_from_pickle(pickle_path, Layout::Stack).unwrap();
+        assert_eq!(devices.len(), 1);
+        let from_pickle = devices.remove(&0).unwrap();
+
+        assert_eq!(from_json.allocations.len(), from_pickle.allocations.len());
+        assert_eq!(
+            from_json.callstacks.num_stacks(),
+            from_pickle.callstacks.num_stacks()
+        );
+        for (a, b) in from_json
+            .allocations
+            .iter()
+            .zip(from_pickle.allocations.iter())
+        {
+            assert_eq!(a.timesteps, b.timesteps);
+            assert_eq!(a.offsets, b.offsets);
+            assert_eq!(a.size, b.size);
+            assert_eq!(a.peak_mem, b.peak_mem);
+            assert!(
+                from_json
+                    .callstacks
+                    .frames(a.stack)
+                    .eq(from_pickle.callstacks.frames(b.stack))
+            );
+        }
+
+        // a garbage length (BINUNICODE8 of u64::MAX bytes) is a truncated pickle, not a panic
+        let mut garbage = vec![0x80, 4, 0x8d];
+        garbage.extend(u64::MAX.to_le_bytes());
+        let err = unpickle(&garbage).unwrap_err();
+        assert!(err.to_string().starts_with("unexpected end of pickle"));
+    }
 }
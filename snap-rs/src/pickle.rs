@@ -0,0 +1,410 @@
+use crate::allocation::{ElementData, Frame};
+use serde::Deserialize;
+use serde_json::{Map, Number, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::rc::Rc;
+
+// Corresponds to the dict returned by `torch.cuda.memory._snapshot()`
+#[derive(Deserialize, Debug)]
+pub struct TorchSnapshot {
+    pub segments: Vec<Segment>,
+    pub device_traces: Vec<Vec<TraceEvent>>,
+}
+
+// One segment (cudaMalloc'd region) held by the caching allocator at dump time
+#[derive(Deserialize, Debug)]
+pub struct Segment {
+    pub device: usize,
+    pub address: u64,
+    pub total_size: u64,
+    pub allocated_size: u64,
+    pub active_size: u64,
+    pub requested_size: u64,
+    pub stream: u64,
+    pub segment_type: String,
+    #[serde(default)]
+    pub frames: Vec<Frame>,
+    pub blocks: Vec<Block>,
+}
+
+// One block inside a segment
+#[derive(Deserialize, Debug)]
+pub struct Block {
+    pub address: u64,
+    pub size: u64,
+    pub requested_size: u64,
+    pub state: String, // active_allocated, active_awaiting_free or inactive
+    #[serde(default)]
+    pub frames: Vec<Frame>,
+}
+
+// One entry of `device_traces[device]`. elements.json is a list of these.
+pub type TraceEvent = ElementData;
+
+/// Decodes a pickled PyTorch memory snapshot (as written by `pickle.dump(torch.cuda.memory._snapshot(), f)`).
+pub fn read_torch_snapshot(pickle_path: &str) -> anyhow::Result<TorchSnapshot> {
+    let bytes = fs::read(pickle_path)
+        .map_err(|e| anyhow::anyhow!("Failed to read pickle file '{}': {}", pickle_path, e))?;
+
+    let value = unpickle(&bytes)
+        .map_err(|e| anyhow::anyhow!("Failed to decode pickle '{}': {}", pickle_path, e))?;
+
+    serde_json::from_value(value).map_err(|e| {
+        anyhow::anyhow!(
+            "Pickle '{}' is not a PyTorch memory snapshot: {}",
+            pickle_path,
+            e
+        )
+    })
+}
+
+// Python objects as seen by the unpickler.
+// Lists and dicts are shared, because MEMOIZE stores a reference *before* the container is filled.
+#[derive(Clone, Debug)]
+enum PyObject {
+    None,
+    Bool(bool),
+    Int(i128),
+    Float(f64),
+    Str(Rc<str>),
+    Bytes(Rc<[u8]>),
+    Tuple(Rc<[PyObject]>),
+    List(Rc<RefCell<Vec<PyObject>>>),
+    Dict(Rc<RefCell<Vec<(PyObject, PyObject)>>>),
+}
+
+impl PyObject {
+    fn to_json(&self) -> Value {
+        match self {
+            PyObject::None => Value::Null,
+            PyObject::Bool(b) => Value::Bool(*b),
+            PyObject::Int(i) => {
+                if let Ok(i) = i64::try_from(*i) {
+                    Value::from(i)
+                } else if let Ok(u) = u64::try_from(*i) {
+                    Value::from(u)
+                } else {
+                    Number::from_f64(*i as f64).map_or(Value::Null, Value::Number)
+                }
+            }
+            PyObject::Float(f) => Number::from_f64(*f).map_or(Value::Null, Value::Number),
+            PyObject::Str(s) => Value::String(s.to_string()),
+            PyObject::Bytes(b) => Value::String(String::from_utf8_lossy(b).into_owned()),
+            PyObject::Tuple(items) => Value::Array(items.iter().map(|x| x.to_json()).collect()),
+            PyObject::List(items) => {
+                Value::Array(items.borrow().iter().map(|x| x.to_json()).collect())
+            }
+            PyObject::Dict(items) => {
+                let mut map = Map::new();
+                for (key, value) in items.borrow().iter() {
+                    let key = match key {
+                        PyObject::Str(s) => s.to_string(),
+                        other => other.to_json().to_string(),
+                    };
+                    map.insert(key, value.to_json());
+                }
+                Value::Object(map)
+            }
+        }
+    }
+}
+
+struct Unpickler<'a> {
+    data: &'a [u8],
+    pos: usize,
+    stack: Vec<PyObject>,
+    marks: Vec<usize>, // stack lengths at each MARK
+    memo: HashMap<u32, PyObject>,
+}
+
+impl<'a> Unpickler<'a> {
+    fn read(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&end| end <= self.data.len())
+            .ok_or_else(|| anyhow::anyhow!("unexpected end of pickle at byte {}", self.pos))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.read(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_le_bytes(self.read(2)?.try_into()?))
+    }
+
+    fn read_u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.read(4)?.try_into()?))
+    }
+
+    fn read_u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.read(8)?.try_into()?))
+    }
+
+    fn read_str(&mut self, len: usize) -> anyhow::Result<PyObject> {
+        Ok(PyObject::Str(std::str::from_utf8(self.read(len)?)?.into()))
+    }
+
+    fn read_long(&mut self, len: usize) -> anyhow::Result<PyObject> {
+        if len > 16 {
+            return Err(anyhow::anyhow!("integer of {} bytes is too large", len));
+        }
+        let bytes = self.read(len)?;
+        // little-endian two's complement, sign-extended to 128 bits
+        let fill = if bytes.last().is_some_and(|&b| b & 0x80 != 0) {
+            0xff
+        } else {
+            0
+        };
+        let mut buf = [fill; 16];
+        buf[..len].copy_from_slice(bytes);
+        Ok(PyObject::Int(i128::from_le_bytes(buf)))
+    }
+
+    fn pop(&mut self) -> anyhow::Result<PyObject> {
+        self.stack
+            .pop()
+            .ok_or_else(|| anyhow::anyhow!("stack underflow at byte {}", self.pos))
+    }
+
+    fn top(&self) -> anyhow::Result<&PyObject> {
+        self.stack
+            .last()
+            .ok_or_else(|| anyhow::anyhow!("stack underflow at byte {}", self.pos))
+    }
+
+    /// Pops everything pushed since the last MARK
+    fn pop_mark(&mut self) -> anyhow::Result<Vec<PyObject>> {
+        let mark = self
+            .marks
+            .pop()
+            .ok_or_else(|| anyhow::anyhow!("missing MARK at byte {}", self.pos))?;
+        Ok(self.stack.split_off(mark))
+    }
+
+    fn memo_get(&self, id: u32) -> anyhow::Result<PyObject> {
+        self.memo
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("memo entry {} not found", id))
+    }
+
+    fn memo_put(&mut self, id: u32) -> anyhow::Result<()> {
+        let top = self.top()?.clone();
+        self.memo.insert(id, top);
+        Ok(())
+    }
+
+    fn extend_list(&mut self, items: Vec<PyObject>) -> anyhow::Result<()> {
+        match self.top()? {
+            PyObject::List(list) => {
+                list.borrow_mut().extend(items);
+                Ok(())
+            }
+            other => Err(anyhow::anyhow!("cannot append to {:?}", other)),
+        }
+    }
+
+    fn extend_dict(&mut self, items: Vec<PyObject>) -> anyhow::Result<()> {
+        match self.top()? {
+            PyObject::Dict(dict) => {
+                let mut dict = dict.borrow_mut();
+                let mut items = items.into_iter();
+                while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                    dict.push((key, value));
+                }
+                Ok(())
+            }
+            other => Err(anyhow::anyhow!("cannot set items on {:?}", other)),
+        }
+    }
+
+    fn run(&mut self) -> anyhow::Result<PyObject> {
+        loop {
+            let opcode = self.read_u8()?;
+            match opcode {
+                // PROTO
+                0x80 => {
+                    self.read_u8()?;
+                }
+                // FRAME
+                0x95 => {
+                    self.read_u64()?;
+                }
+                // STOP
+                b'.' => return self.pop(),
+                b'(' => self.marks.push(self.stack.len()),
+                b'0' => {
+                    self.pop()?;
+                }
+                b'1' => {
+                    self.pop_mark()?;
+                }
+                b'2' => {
+                    let top = self.top()?.clone();
+                    self.stack.push(top);
+                }
+
+                b'N' => self.stack.push(PyObject::None),
+                0x88 => self.stack.push(PyObject::Bool(true)),
+                0x89 => self.stack.push(PyObject::Bool(false)),
+                b'J' => {
+                    let i = self.read_u32()? as i32;
+                    self.stack.push(PyObject::Int(i as i128));
+                }
+                b'K' => {
+                    let i = self.read_u8()?;
+                    self.stack.push(PyObject::Int(i as i128));
+                }
+                b'M' => {
+                    let i = self.read_u16()?;
+                    self.stack.push(PyObject::Int(i as i128));
+                }
+                0x8a => {
+                    let len = self.read_u8()? as usize;
+                    let long = self.read_long(len)?;
+                    self.stack.push(long);
+                }
+                0x8b => {
+                    let len = self.read_u32()? as usize;
+                    let long = self.read_long(len)?;
+                    self.stack.push(long);
+                }
+                b'G' => {
+                    let f = f64::from_be_bytes(self.read(8)?.try_into()?);
+                    self.stack.push(PyObject::Float(f));
+                }
+
+                0x8c => {
+                    let len = self.read_u8()? as usize;
+                    let s = self.read_str(len)?;
+                    self.stack.push(s);
+                }
+                b'X' => {
+                    let len = self.read_u32()? as usize;
+                    let s = self.read_str(len)?;
+                    self.stack.push(s);
+                }
+                0x8d => {
+                    let len = self.read_u64()? as usize;
+                    let s = self.read_str(len)?;
+                    self.stack.push(s);
+                }
+                b'C' => {
+                    let len = self.read_u8()? as usize;
+                    let b = self.read(len)?;
+                    self.stack.push(PyObject::Bytes(b.into()));
+                }
+                b'B' => {
+                    let len = self.read_u32()? as usize;
+                    let b = self.read(len)?;
+                    self.stack.push(PyObject::Bytes(b.into()));
+                }
+                0x8e => {
+                    let len = self.read_u64()? as usize;
+                    let b = self.read(len)?;
+                    self.stack.push(PyObject::Bytes(b.into()));
+                }
+
+                b')' => self.stack.push(PyObject::Tuple(Rc::new([]))),
+                b't' => {
+                    let items = self.pop_mark()?;
+                    self.stack.push(PyObject::Tuple(items.into()));
+                }
+                0x85..=0x87 => {
+                    let n = (opcode - 0x84) as usize;
+                    if self.stack.len() < n {
+                        return Err(anyhow::anyhow!("stack underflow at byte {}", self.pos));
+                    }
+                    let items = self.stack.split_off(self.stack.len() - n);
+                    self.stack.push(PyObject::Tuple(items.into()));
+                }
+                // EMPTY_LIST, EMPTY_SET: sets are only ever read back as lists
+                b']' | 0x8f => self
+                    .stack
+                    .push(PyObject::List(Rc::new(RefCell::new(Vec::new())))),
+                b'l' | 0x91 => {
+                    let items = self.pop_mark()?;
+                    self.stack
+                        .push(PyObject::List(Rc::new(RefCell::new(items))));
+                }
+                b'a' => {
+                    let item = self.pop()?;
+                    self.extend_list(vec![item])?;
+                }
+                b'e' | 0x90 => {
+                    let items = self.pop_mark()?;
+                    self.extend_list(items)?;
+                }
+                b'}' => self
+                    .stack
+                    .push(PyObject::Dict(Rc::new(RefCell::new(Vec::new())))),
+                b'd' => {
+                    let items = self.pop_mark()?;
+                    self.stack
+                        .push(PyObject::Dict(Rc::new(RefCell::new(Vec::new()))));
+                    self.extend_dict(items)?;
+                }
+                b's' => {
+                    let value = self.pop()?;
+                    let key = self.pop()?;
+                    self.extend_dict(vec![key, value])?;
+                }
+                b'u' => {
+                    let items = self.pop_mark()?;
+                    self.extend_dict(items)?;
+                }
+
+                0x94 => {
+                    let id = self.memo.len() as u32;
+                    self.memo_put(id)?;
+                }
+                b'q' => {
+                    let id = self.read_u8()? as u32;
+                    self.memo_put(id)?;
+                }
+                b'r' => {
+                    let id = self.read_u32()?;
+                    self.memo_put(id)?;
+                }
+                b'h' => {
+                    let id = self.read_u8()? as u32;
+                    let obj = self.memo_get(id)?;
+                    self.stack.push(obj);
+                }
+                b'j' => {
+                    let id = self.read_u32()?;
+                    let obj = self.memo_get(id)?;
+                    self.stack.push(obj);
+                }
+
+                other => {
+                    return Err(anyhow::anyhow!(
+                        "unsupported pickle opcode 0x{:02x} at byte {}",
+                        other,
+                        self.pos - 1
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Minimal unpickler for the plain containers (dict/list/tuple/str/int/float) that
+/// `torch.cuda.memory._snapshot()` is made of. Class instances are not supported.
+pub fn unpickle(data: &[u8]) -> anyhow::Result<Value> {
+    let mut unpickler = Unpickler {
+        data,
+        pos: 0,
+        stack: Vec::new(),
+        marks: Vec::new(),
+        memo: HashMap::new(),
+    };
+    Ok(unpickler.run()?.to_json())
+}
@@ -1,4 +1,21 @@
-use crate::{repl_ops::memsnap::MemSnap, utils::format_bytes};
+use crate::{
+    clock::{Clock, parse_time_range, parse_timestamp},
+    frame_filter::FrameFilter,
+    repl_ops::{
+        columnar::TableFormat,
+        filter::{Filter, split_where},
+        flame::FlameWeight,
+        group::GroupKey,
+        memsnap::MemSnap,
+        peaks::Prominence,
+        perfetto::DEFAULT_SLICE_MIN_SIZE,
+        range::RangeQuery,
+        subset::SubsetFilter,
+    },
+    utils::{format_bytes, parse_bytes},
+};
+use std::fs::OpenOptions;
+use std::io::Write;
 use thiserror::Error;
 
 // define a quit error
@@ -21,7 +38,7 @@ pub enum TopkOption {
     TimestampVerbose(u64),
 }
 
-pub fn parse_topk_option(options: &[&str]) -> anyhow::Result<TopkOption> {
+pub fn parse_topk_option(options: &[&str], clock: Option<&Clock>) -> anyhow::Result<TopkOption> {
     match options.len() {
         0 => Ok(TopkOption::Global),
         1 => {
@@ -29,11 +46,7 @@ pub fn parse_topk_option(options: &[&str]) -> anyhow::Result<TopkOption> {
             if s == "v" || s == "verbose" {
                 Ok(TopkOption::GlobalVerbose)
             } else if s.starts_with('@') {
-                if let Ok(ts) = s[1..].parse::<u64>() {
-                    Ok(TopkOption::Timestamp(ts))
-                } else {
-                    Err(anyhow::anyhow!("Invalid timestamp format: {}", s))
-                }
+                Ok(TopkOption::Timestamp(parse_timestamp(s, clock)?))
             } else {
                 Err(anyhow::anyhow!("Unrecognized single element: {}", s))
             }
@@ -47,11 +60,7 @@ pub fn parse_topk_option(options: &[&str]) -> anyhow::Result<TopkOption> {
 
             // Case: one verbose, one timestamp
             if s1_is_verbose && s2_is_timestamp {
-                if let Ok(ts) = s2[1..].parse::<u64>() {
-                    Ok(TopkOption::TimestampVerbose(ts))
-                } else {
-                    Err(anyhow::anyhow!("Invalid timestamp format: {}", s2))
-                }
+                Ok(TopkOption::TimestampVerbose(parse_timestamp(s2, clock)?))
             } else {
                 Err(anyhow::anyhow!(
                     "Unrecognized two elements: {:?} (expected [verbose] [timestamp])",
@@ -66,21 +75,401 @@ pub fn parse_topk_option(options: &[&str]) -> anyhow::Result<TopkOption> {
     }
 }
 
+/// Allocations listed by a command, which can be piped into the next one (`top 50 | where ... | inspect`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listing {
+    pub indices: Vec<usize>,
+    pub ranked: bool, // sorted descending by size
+    pub verbose: bool,
+}
+
+/// Result of a command, rendered to text at the end of the pipeline
+#[derive(Debug, Clone, PartialEq)]
+pub enum Output {
+    Text(String),
+    Allocations(Listing),
+}
+
+/// `> path` (overwrite) or `>> path` (append) at the end of a command line
+#[derive(Debug, PartialEq)]
+pub struct Redirect<'a> {
+    pub path: &'a str,
+    pub append: bool,
+}
+
+/// Byte offsets of the chars of `line` that are outside of quoted strings
+fn unquoted(line: &str) -> impl Iterator<Item = (usize, char)> + '_ {
+    let mut quote = None;
+    line.char_indices().filter(move |&(_, c)| match quote {
+        Some(q) => {
+            if c == q {
+                quote = None;
+            }
+            false
+        }
+        None => {
+            if c == '"' || c == '\'' {
+                quote = Some(c);
+            }
+            true
+        }
+    })
+}
+
+/// Splits a command line at the `|`s outside of quotes
+pub fn split_pipeline(line: &str) -> Vec<&str> {
+    let mut stages = Vec::new();
+    let mut start = 0;
+    for (i, c) in unquoted(line) {
+        if c == '|' {
+            stages.push(line[start..i].trim());
+            start = i + 1;
+        }
+    }
+    stages.push(line[start..].trim());
+    stages
+}
+
+/// Splits a trailing `> path` or `>> path` off a command line. In a `where` clause, a `>` followed
+/// by a number or a timestamp compares (`where size > 1MiB`) and is not a redirection.
+pub fn split_redirect(line: &str) -> (&str, Option<Redirect<'_>>) {
+    let Some(pos) = unquoted(line)
+        .filter(|&(_, c)| c == '>')
+        .map(|(i, _)| i)
+        .last()
+    else {
+        return (line, None);
+    };
+    let path = line[pos + 1..].trim();
+    let append = line[..pos].ends_with('>');
+    // the pipeline stage the `>` is in
+    let stage = split_pipeline(&line[..pos]).pop().unwrap_or_default();
+    let comparison = split_where(stage).1.is_some()
+        && path.starts_with(|c: char| c == '=' || c == '@' || c.is_ascii_digit());
+    if path.is_empty() || path.contains(char::is_whitespace) || (!append && comparison) {
+        return (line, None);
+    }
+    let command = &line[..if append { pos - 1 } else { pos }];
+    (command.trim(), Some(Redirect { path, append }))
+}
+
+/// Splits at the first whitespace: command, then trimmed arguments
+fn split_command(cmd: &str) -> (&str, &str) {
+    let parts: Vec<&str> = cmd.splitn(2, ' ').collect();
+    (parts[0], parts.get(1).map_or("", |s| s.trim()))
+}
+
 impl MemSnap {
-    /// Input: trimmed command string
+    /// Allocation indices, or each allocation in detail if `verbose`
+    fn render(&self, output: Output) -> String {
+        let listing = match output {
+            Output::Text(text) => return text,
+            Output::Allocations(listing) => listing,
+        };
+        let indices = &listing.indices;
+
+        if listing.verbose {
+            return indices
+                .iter()
+                .enumerate()
+                .map(|(rank, &i)| {
+                    // rank: ranking sorted by size descending
+                    let header = if listing.ranked {
+                        format!("#{}", rank)
+                    } else {
+                        format!("Index {}", i)
+                    };
+                    format!("{}\n{}", header, self.display(i))
+                })
+                .collect::<Vec<_>>()
+                .join("\n\n");
+        }
+
+        let list = indices
+            .iter()
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        if listing.ranked {
+            format!("Index, sorted descending by allocation size: {}", list)
+        } else {
+            let total: u64 = indices.iter().map(|&i| self.allocations[i].size).sum();
+            format!(
+                "{} allocations, {} in total: {}",
+                indices.len(),
+                format_bytes(total),
+                list
+            )
+        }
+    }
+
+    /// Input: trimmed command line, a pipeline of commands optionally redirected to a file
     /// Return the output string as an ExecResult
     pub fn exec(&mut self, cmd: String) -> anyhow::Result<String> {
-        // 1. Split at whitespace: first part as command, rest as arguments.
-        // If the command string is empty after trimming, return success with empty info.
-        let parts: Vec<&str> = cmd.splitn(2, ' ').collect();
-        let command = parts[0];
-        let args = parts.get(1).map_or("", |s| s.trim()); // Get arguments, trim, or empty string if no args
+        // frame regexes are full of `|` and `<...>`, so `frames` is neither piped nor redirected
+        if split_command(&cmd).0 == "frames" {
+            return self
+                .exec_command(&cmd, None)
+                .map(|output| self.render(output));
+        }
+        let (pipeline, redirect) = split_redirect(&cmd);
+        let stages = split_pipeline(pipeline);
+        if stages.len() > 1 && stages.iter().any(|stage| stage.is_empty()) {
+            return Err(anyhow::anyhow!("Empty command in pipeline: {}", pipeline));
+        }
+
+        let mut output: Option<Output> = None;
+        for (i, stage) in stages.iter().enumerate() {
+            let input = match output.take() {
+                None => None,
+                Some(Output::Allocations(listing)) => Some(listing),
+                Some(Output::Text(_)) => {
+                    return Err(anyhow::anyhow!(
+                        "`{}` does not list allocations, it cannot be piped into `{}`",
+                        stages[i - 1],
+                        stage
+                    ));
+                }
+            };
+            output = Some(self.exec_command(stage, input)?);
+        }
+        let text = output.map(|output| self.render(output)).unwrap_or_default();
+
+        let Some(Redirect { path, append }) = redirect else {
+            return Ok(text);
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .map_err(|e| anyhow::anyhow!("Failed to open '{}': {}", path, e))?;
+        writeln!(file, "{}", text)?;
+        Ok(format!(
+            "Output {} to {}",
+            if append { "appended" } else { "written" },
+            path
+        ))
+    }
+
+    /// One command of a pipeline, with the allocations listed by the previous one as `input`
+    fn exec_command(&mut self, cmd: &str, input: Option<Listing>) -> anyhow::Result<Output> {
+        let (command, args) = split_command(cmd);
+        match command {
+            "peak" if input.is_none() => {
+                let (args, filter) = split_where(args);
+                let filter = filter
+                    .map(|f| Filter::parse(f, self.clock.as_ref()))
+                    .transpose()?;
+                // split args by every whitespace
+                let argv = args.split_whitespace().collect::<Vec<&str>>();
+                // if no index is specified, inspect the last allocation
+                if argv.len() != 1 && argv.len() != 2 {
+                    return Err(anyhow::anyhow!(
+                        "`peak` command takes [k] [verbose] [where <filter>] as argument."
+                    ));
+                }
+
+                // parse as usize
+                let k = argv[0].parse::<usize>()?;
+                let verbose = match argv.get(1) {
+                    None => false,
+                    Some(&"verbose") | Some(&"v") => true,
+                    Some(option) => {
+                        return Err(anyhow::anyhow!(
+                            "Invalid option: {}, expected `verbose` or `v`",
+                            option
+                        ));
+                    }
+                };
+
+                let indices = match &filter {
+                    None => self.peak_topk(k)?,
+                    Some(filter) => {
+                        self.build_peak_sorted_sizes();
+                        self.take_matching(self.peak_sorted_sizes.as_ref().unwrap(), filter, k)
+                    }
+                };
+                Ok(Output::Allocations(Listing {
+                    indices,
+                    ranked: true,
+                    verbose,
+                }))
+            }
+            "top" => {
+                let (args, filter) = split_where(args);
+                let filter = filter
+                    .map(|f| Filter::parse(f, self.clock.as_ref()))
+                    .transpose()?;
+                // split args by every whitespace
+                let argv = args.split_whitespace().collect::<Vec<&str>>();
+                // if no index is specified, inspect the last allocation
+                if argv.is_empty() || argv.len() > 3 {
+                    return Err(anyhow::anyhow!(
+                        "`top` command takes [k] and optional [verbose] [@timestamp] [where <filter>] as argument."
+                    ));
+                }
+                // try to parse the index as a number
+                let k = argv[0].parse::<usize>()?;
+
+                if k >= self.allocations.len() {
+                    return Err(anyhow::anyhow!(
+                        "Index out of bounds: {} >= {}",
+                        k,
+                        self.allocations.len()
+                    ));
+                }
+
+                let options = &argv[1..];
+                let (timestamp, verbose) = match parse_topk_option(options, self.clock.as_ref())? {
+                    TopkOption::Global => (None, false),
+                    TopkOption::Timestamp(timestamp) => (Some(timestamp), false),
+                    TopkOption::GlobalVerbose => (None, true),
+                    TopkOption::TimestampVerbose(timestamp) => (Some(timestamp), true),
+                };
+
+                let indices = match (input, timestamp, &filter) {
+                    // NOTE: topK of the piped allocations
+                    (Some(listing), timestamp, filter) => {
+                        let mut indices = listing.indices;
+                        if let Some(timestamp) = timestamp {
+                            indices.retain(|&i| self.allocations[i].is_alive_at(timestamp));
+                        }
+                        if let Some(filter) = filter {
+                            let mask = self.filter_mask(filter);
+                            indices.retain(|&i| mask[i]);
+                        }
+                        indices.sort_by_key(|&i| std::cmp::Reverse(self.allocations[i].size));
+                        indices.truncate(k);
+                        indices
+                    }
+                    // NOTE: global topK
+                    (None, None, None) => self.global_topk(k)?,
+                    // NOTE: timestamp topK
+                    (None, Some(timestamp), None) => self.timestamp_topk(timestamp, k)?,
+                    (None, None, Some(filter)) => {
+                        self.build_global_sorted_sizes();
+                        self.take_matching(self.global_sorted_sizes.as_ref().unwrap(), filter, k)
+                    }
+                    (None, Some(timestamp), Some(filter)) => {
+                        let ranked = self.timestamp_sorted(timestamp)?.to_vec();
+                        self.take_matching(&ranked, filter, k)
+                    }
+                };
+                Ok(Output::Allocations(Listing {
+                    indices,
+                    ranked: true,
+                    verbose,
+                }))
+            }
+            "where" => {
+                if args.is_empty() {
+                    return Err(anyhow::anyhow!("Usage: where <filter>"));
+                }
+                let filter = Filter::parse(args, self.clock.as_ref())?;
+                let listing = match input {
+                    Some(mut listing) => {
+                        let mask = self.filter_mask(&filter);
+                        listing.indices.retain(|&i| mask[i]);
+                        listing
+                    }
+                    None => Listing {
+                        indices: self.filter_indices(&filter),
+                        ranked: false,
+                        verbose: false,
+                    },
+                };
+                Ok(Output::Allocations(listing))
+            }
+            "alive" | "born" | "freed" | "overlap" if input.is_none() => {
+                let (args, filter) = split_where(args);
+                let filter = filter
+                    .map(|f| Filter::parse(f, self.clock.as_ref()))
+                    .transpose()?;
+                let argv = args.split_whitespace().collect::<Vec<&str>>();
+                let (range, verbose) = match argv.as_slice() {
+                    [range] => (*range, false),
+                    [range, "v" | "verbose"] => (*range, true),
+                    _ => {
+                        return Err(anyhow::anyhow!(
+                            "Usage: {} <@start..end> [verbose] [where <filter>]",
+                            command
+                        ));
+                    }
+                };
+                let (from, to) = if range.contains("..") {
+                    parse_time_range(range, self.clock.as_ref())?
+                } else {
+                    let timestamp = self.parse_timestamp(range)?;
+                    (timestamp, timestamp)
+                };
+
+                let query = RangeQuery::parse(command).unwrap();
+                Ok(Output::Allocations(Listing {
+                    indices: self.range_query(query, from, to, filter.as_ref()),
+                    ranked: false,
+                    verbose,
+                }))
+            }
+            "i" | "inspect" if input.is_some() || split_where(args).1.is_some() => {
+                let (args, filter) = split_where(args);
+                if !args.is_empty() {
+                    return Err(anyhow::anyhow!(
+                        "`inspect` takes no index when allocations are piped into it or filtered."
+                    ));
+                }
+                let filter = filter
+                    .map(|f| Filter::parse(f, self.clock.as_ref()))
+                    .transpose()?;
+                // `inspect where <filter>` is `where <filter> | inspect`
+                let listing = match (input, &filter) {
+                    (Some(mut listing), Some(filter)) => {
+                        let mask = self.filter_mask(filter);
+                        listing.indices.retain(|&i| mask[i]);
+                        listing
+                    }
+                    (Some(listing), None) => listing,
+                    (None, Some(filter)) => Listing {
+                        indices: self.filter_indices(filter),
+                        ranked: false,
+                        verbose: false,
+                    },
+                    (None, None) => unreachable!(),
+                };
+                Ok(Output::Allocations(Listing {
+                    verbose: true,
+                    ..listing
+                }))
+            }
+            "save-subset" if input.is_some() => {
+                if args.is_empty() || args.contains(char::is_whitespace) {
+                    return Err(anyhow::anyhow!(
+                        "Usage: ... | save-subset <path.zip> (the piped allocations are saved)"
+                    ));
+                }
+                let filter = SubsetFilter::Indices(input.unwrap().indices);
+                let count = self.save_subset(args, &filter)?;
+                Ok(Output::Text(format!(
+                    "{} allocations saved to {}, open it with --zip",
+                    count, args
+                )))
+            }
+            _ if input.is_some() => Err(anyhow::anyhow!(
+                "`{}` does not take piped allocations, only top, where, inspect and save-subset do.",
+                command
+            )),
+            _ => self.exec_text(command, args).map(Output::Text),
+        }
+    }
 
+    /// Commands whose output is text
+    fn exec_text(&mut self, command: &str, args: &str) -> anyhow::Result<String> {
         if command.is_empty() {
             return Ok("".into());
         }
 
-        // 2. Handle the commands based on the parsed command and arguments.
+        // Handle the commands based on the parsed command and arguments.
         match command {
             "sql" => {
                 if args.is_empty() {
@@ -98,132 +487,263 @@ impl MemSnap {
                 Ok("Build Sqlite OK".into())
             }
             "byte" => match args.parse::<u64>() {
-                Ok(bytes) => Ok(format_bytes(bytes).into()),
+                Ok(bytes) => Ok(format_bytes(bytes)),
                 Err(e) => Err(anyhow::anyhow!(
                     "Invalid byte value (expected uint64): {}",
                     e
                 )),
             },
-            "timeline" => {
+            "device" => {
+                if args.is_empty() {
+                    return Ok(format!("Selected device: {}", self.device));
+                }
+                let device = args
+                    .parse::<usize>()
+                    .map_err(|e| anyhow::anyhow!("Invalid device id: {}", e))?;
+                self.select_device(device)?;
+                Ok(format!(
+                    "Selected device {} ({} allocations)",
+                    device,
+                    self.allocations.len()
+                ))
+            }
+            "save" => {
                 if args.is_empty() {
+                    return Err(anyhow::anyhow!("`save` command requires a path argument."));
+                }
+                self.save_cache(args)?;
+                Ok(format!("Cache saved to {}", args))
+            }
+            "save-subset" => {
+                let Some((path, filter)) = args.split_once(char::is_whitespace) else {
                     return Err(anyhow::anyhow!(
-                        "`timeline` command requires a path argument.".to_string(),
+                        "Usage: save-subset <path.zip> <@start..end | indices | ~pattern>"
                     ));
+                };
+                let filter = SubsetFilter::parse(
+                    filter.trim(),
+                    self.clock.as_ref(),
+                    self.allocations.len(),
+                )?;
+                let count = self.save_subset(path, &filter)?;
+                Ok(format!(
+                    "{} allocations saved to {}, open it with --zip",
+                    count, path
+                ))
+            }
+            "load-baseline" => {
+                if args.is_empty() {
+                    return Err(anyhow::anyhow!("Usage: load-baseline <path.zip>"));
                 }
-                self.plot_timeline(args)?;
-                Ok(format!("Plot saved to {}", args).into())
+                self.load_baseline(args)?;
+                let baseline = self.baseline.as_ref().unwrap();
+                Ok(format!(
+                    "Baseline loaded: device {}, {} allocations",
+                    baseline.device,
+                    baseline.allocations.len()
+                ))
             }
-            "peak" => {
-                // split args by every whitespace
+            "diff" => {
                 let argv = args.split_whitespace().collect::<Vec<&str>>();
-                // if no index is specified, inspect the last allocation
-                if argv.len() != 1 && argv.len() != 2 {
-                    return Err(anyhow::anyhow!("`peak` command takes [k] as argument."));
-                }
-
-                // parse as usize
-                let k = argv[0].parse::<usize>()?;
-                match argv.len() {
-                    1 => Ok("Index, sorted descending by allocation size: ".to_owned()
-                        + &self
-                            .peak_topk(k)?
-                            .iter()
-                            .map(|&i| i.to_string())
-                            .collect::<Vec<_>>()
-                            .join(", ")),
-                    2 => {
-                        if argv[1] == "verbose" || argv[1] == "v" {
-                            Ok(self
-                                .peak_topk(k)?
-                                .iter()
-                                .enumerate()
-                                // rank: ranking sorted by size descending
-                                .map(|(rank, &i)| format!("#{}\n{}", rank, self.allocations[i]))
-                                .collect::<Vec<_>>()
-                                .join("\n\n"))
-                        } else {
-                            Err(anyhow::anyhow!(
-                                "Invalid option: {}, expected `verbose` or `v`",
-                                argv[1]
-                            ))
-                        }
+                let (k, verbose) = match argv.as_slice() {
+                    [] => (20, false),
+                    [v] if *v == "v" || *v == "verbose" => (20, true),
+                    [k, rest @ ..] if rest.is_empty() || rest == ["v"] || rest == ["verbose"] => (
+                        k.parse::<usize>()
+                            .map_err(|e| anyhow::anyhow!("Invalid k: {}", e))?,
+                        !rest.is_empty(),
+                    ),
+                    _ => return Err(anyhow::anyhow!("Usage: diff [k] [verbose]")),
+                };
+                self.diff_report(k, verbose)
+            }
+            "group" => {
+                let usage =
+                    "Usage: group <function|file|file:line|stack|user> [@timestamp|all] [top n]";
+                let argv = args.split_whitespace().collect::<Vec<&str>>();
+                let Some((key, options)) = argv.split_first() else {
+                    return Err(anyhow::anyhow!(usage));
+                };
+                let key = GroupKey::parse(key)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown group key: {}. {}", key, usage))?;
+                let (when, n) = match options {
+                    [] => (None, "20"),
+                    ["top", n] => (None, *n),
+                    [when] => (Some(*when), "20"),
+                    [when, "top", n] => (Some(*when), *n),
+                    _ => return Err(anyhow::anyhow!(usage)),
+                };
+                let n = n
+                    .parse::<usize>()
+                    .map_err(|e| anyhow::anyhow!("Invalid n: {}", e))?;
+                let timestamp = match when {
+                    // the memory held at the peak, by default
+                    None => Some(self.peak_timestamp()),
+                    Some("all") => None,
+                    Some(when) => Some(self.parse_timestamp(when)?),
+                };
+                Ok(self.group_report(key, timestamp, n))
+            }
+            "frames" => {
+                let mut filter = self.frame_filter.clone();
+                match args.split_once(char::is_whitespace) {
+                    _ if args.is_empty() => return Ok(self.frames_summary()),
+                    Some(("load", path)) => filter = FrameFilter::load(path.trim())?,
+                    Some(("save", path)) => {
+                        std::fs::write(path.trim(), format!("{}\n", filter))?;
+                        return Ok(format!("Frame filter saved to {}", path.trim()));
                     }
-                    _ => unreachable!(),
+                    _ => filter.apply(args)?,
                 }
+                let had_database = self.database.is_some();
+                self.set_frame_filter(filter);
+                let mut summary = self.frames_summary();
+                if had_database {
+                    summary += "\nThe SQL database was dropped, run `sqlbuild` again.";
+                }
+                Ok(summary)
             }
-            "top" => {
-                // split args by every whitespace
+            "peakinfo" => {
+                let n = match args {
+                    "" => None,
+                    n => Some(
+                        n.parse::<usize>()
+                            .map_err(|e| anyhow::anyhow!("Invalid n: {}", e))?,
+                    ),
+                };
+                Ok(self.peak_report(n))
+            }
+            "peaks" | "iterations" | "leaks" => {
                 let argv = args.split_whitespace().collect::<Vec<&str>>();
-                // if no index is specified, inspect the last allocation
-                if argv.len() == 0 || argv.len() > 3 {
-                    return Err(anyhow::anyhow!(
-                        "`top` command takes [k] and optional [verbose] [@timestamp] as argument."
-                    ));
+                let (n, options) = match argv.split_first() {
+                    Some((n, options)) if command == "peaks" && n.parse::<usize>().is_ok() => {
+                        (n.parse::<usize>()?, options)
+                    }
+                    _ => (20, argv.as_slice()),
+                };
+                let prominence = match options {
+                    [] => Prominence::DEFAULT,
+                    ["prominence", prominence] => Prominence::parse(prominence)?,
+                    _ => {
+                        return Err(anyhow::anyhow!(
+                            "Usage: {}{} [prominence <bytes|percent>]",
+                            command,
+                            if command == "peaks" { " [n]" } else { "" }
+                        ));
+                    }
+                };
+                match command {
+                    "peaks" => Ok(self.peaks_report(n, prominence)),
+                    "iterations" => Ok(self.iterations_report(prominence)),
+                    _ => Ok(self.leaks_report(prominence)),
                 }
-                // try to parse the index as a number
-                let k = argv[0].parse::<usize>()?;
-
-                if k >= self.allocations.len() {
-                    // return Err(anyhow::anyhow!(format!(
-                    //     "Index out of bounds: {} >= {}",
-                    //     k,
-                    //     self.allocations.len()
-                    // ));
-
+            }
+            "devices" => {
+                if !args.is_empty() {
                     return Err(anyhow::anyhow!(
-                        "Index out of bounds: {} >= {}",
-                        k,
-                        self.allocations.len()
+                        "`devices` command does not take arguments."
                     ));
                 }
-
-                let options = &argv[1..];
-                let topk_options = parse_topk_option(options)?;
-                match topk_options {
-                    TopkOption::Global => Ok("Index, sorted descending by allocation size: "
-                        .to_owned()
-                        + &self
-                            // NOTE: global topK
-                            .global_topk(k)?
-                            .iter()
-                            .map(|x| x.to_string())
-                            .collect::<Vec<_>>()
-                            .join(", ")),
-                    TopkOption::Timestamp(timestamp) => {
-                        Ok("Index, sorted descending by allocation size: ".to_owned()
-                            + &self
-                                // NOTE: timestamp topK
-                                .timestamp_topk(timestamp, k)?
-                                .iter()
-                                .map(|x| x.to_string())
-                                .collect::<Vec<_>>()
-                                .join(", "))
+                Ok(self.devices_summary())
+            }
+            "check" => {
+                if !args.is_empty() {
+                    return Err(anyhow::anyhow!("`check` command does not take arguments."));
+                }
+                Ok(self.check_report().0)
+            }
+            "segments" => {
+                if args.is_empty() {
+                    return self.segments_summary();
+                }
+                let timestamp = self.parse_timestamp(args)?;
+                self.segments_at(timestamp)
+            }
+            "export" => {
+                let argv = args.split_whitespace().collect::<Vec<&str>>();
+                match argv.as_slice() {
+                    ["perfetto", path, min_size @ ..] if min_size.len() <= 1 => {
+                        let min_size = match min_size.first() {
+                            Some(size) => parse_bytes(size)?,
+                            None => DEFAULT_SLICE_MIN_SIZE,
+                        };
+                        let slices = self.export_perfetto(path, min_size)?;
+                        Ok(format!(
+                            "Trace with {} allocations >= {} saved to {}, open it in https://ui.perfetto.dev",
+                            slices,
+                            format_bytes(min_size),
+                            path
+                        ))
+                    }
+                    ["pprof", path, when] => {
+                        let timestamp = match *when {
+                            "all" => None,
+                            when if when.starts_with('@') => Some(self.parse_timestamp(when)?),
+                            _ => {
+                                return Err(anyhow::anyhow!(
+                                    "Expected @timestamp or `all`, got: {}",
+                                    when
+                                ));
+                            }
+                        };
+                        let samples = self.export_pprof(path, timestamp)?;
+                        Ok(format!(
+                            "Profile with {} callstacks saved to {}, open it with `pprof -http=: {}`",
+                            samples, path, path
+                        ))
+                    }
+                    [format, dir] if TableFormat::parse(format).is_some() => {
+                        let paths = self.export_tables(dir, TableFormat::parse(format).unwrap())?;
+                        Ok(format!("Tables saved to:\n  {}", paths.join("\n  ")))
                     }
-                    TopkOption::GlobalVerbose => Ok(self
-                        // NOTE: global topK
-                        .global_topk(k)?
-                        .iter()
-                        .enumerate()
-                        // rank: ranking sorted by size descending
-                        .map(|(rank, &i)| format!("#{}\n{}", rank, self.allocations[i]))
-                        .collect::<Vec<_>>()
-                        .join("\n\n")),
-                    TopkOption::TimestampVerbose(timestamp) => Ok(self
-                        // NOTE: timestamp topK
-                        .timestamp_topk(timestamp, k)?
-                        .iter()
-                        .enumerate()
-                        // rank: ranking sorted by size descending
-                        .map(|(rank, &i)| format!("#{}\n{}", rank, self.allocations[i]))
-                        .collect::<Vec<_>>()
-                        .join("\n\n")),
+                    _ => Err(anyhow::anyhow!(
+                        "Usage: export perfetto <path> [min_size] | export pprof <path.pb.gz> <@timestamp|all> | export csv|parquet|arrow <dir>"
+                    )),
                 }
             }
+            "flame" => {
+                let argv = args.split_whitespace().collect::<Vec<&str>>();
+                let [when, path] = argv.as_slice() else {
+                    return Err(anyhow::anyhow!("Usage: flame <@timestamp|all> <path.svg>"));
+                };
+                let weight = match *when {
+                    "all" => FlameWeight::ByteTimesteps,
+                    when if when.starts_with('@') => {
+                        FlameWeight::AliveAt(self.parse_timestamp(when)?)
+                    }
+                    _ => {
+                        return Err(anyhow::anyhow!(
+                            "Expected @timestamp or `all`, got: {}",
+                            when
+                        ));
+                    }
+                };
+                let folded_path = self.write_flamegraph(weight, path)?;
+                Ok(format!(
+                    "Flamegraph saved to {}, folded stacks to {}",
+                    path, folded_path
+                ))
+            }
+            "timeline" => {
+                let argv = args.split_whitespace().collect::<Vec<&str>>();
+                let (path, time_axis) = match argv.as_slice() {
+                    [path] => (*path, false),
+                    [path, "time"] => (*path, true),
+                    _ => {
+                        return Err(anyhow::anyhow!(
+                            "`timeline` command requires a path argument, optionally followed by `time`."
+                                .to_string(),
+                        ));
+                    }
+                };
+                self.plot_timeline(path, time_axis)?;
+                Ok(format!("Plot saved to {}", path))
+            }
             "i" | "inspect" => {
                 // split args by every whitespace
                 let argv = args.split_whitespace().collect::<Vec<&str>>();
                 // if no index is specified, inspect the last allocation
-                if argv.len() == 0 {
+                if argv.is_empty() {
                     return Err(anyhow::anyhow!(
                         "`inspect` command requires at least an index argument.".to_string(),
                     ));
@@ -250,7 +770,7 @@ impl MemSnap {
 
                 if options.is_empty() {
                     // if no options are specified, just print the allocation details
-                    Ok(self.allocations[index].to_string())
+                    Ok(self.display(index).to_string())
                 } else {
                     // TODO: implement other options
                     // Err(anyhow::anyhow!(format)
@@ -269,13 +789,93 @@ impl MemSnap {
                 Ok(
                     r#"Available commands:
   help                              - Display this help message.
-  i | inspect <index>               - Inspect an allocation at the specified index.
+  i | inspect <index>               - Inspect an allocation at the specified index (or each piped allocation, without index).
+  i | inspect where <filter>        - Inspect each allocation that matches the filter, like `where <filter> | inspect`.
   top <k> [verbose] [@timestamp]    - Print the top k allocations (sorted descending by size).
                                         If timestamp is specified, print the top k allocations at the specified timestamp.
+                                        Piped allocations are ranked instead of all of them.
   peak <k> [verbose]                - Print the peak allocations (sorted descending by size).
+  leaks [prominence <p>]            - Allocations never freed (alive at the end of the trace), by callstack. Stacks that leave a
+                                        new allocation alive in every iteration (see `iterations`) are flagged, with the bytes
+                                        they add per iteration.
+  peakinfo [n]                      - What makes up the global peak of memory in use: the lines (innermost Python frame) and
+                                        allocations alive at it (the n largest, default all) with their share of the peak,
+                                        and the allocations born during the climb to it.
+  peaks [n] [prominence <p>]        - The n (default 20) most prominent local maxima of the memory in use, in time order, with
+                                        the time between them and the spread of their heights. A peak's prominence is how far
+                                        memory drops before rising higher; p is bytes (64MiB) or a share of the peak (default 1%).
+  iterations [prominence <p>]       - Split the trace at the lowest point between each two peaks, and print each iteration's
+                                        peak and minimum and their change since the previous one, to spot creep across steps.
+  where <filter>                    - List the allocations (or the piped ones) that match the filter, with their total size.
+  alive <@start..end> [verbose]     - List the allocations alive throughout the range (both ends included), with their total size.
+  born <@start..end> [verbose]      - ... allocated in the range.
+  freed <@start..end> [verbose]     - ... freed in the range (not those still alive at the end of the trace).
+  overlap <@start..end> [verbose]   - ... alive at some point of the range. All four take `where <filter>` too.
+  group <key> [@timestamp|all] [top n]
+                                    - Aggregate allocations by function, file, file:line, stack or user (the innermost frame of
+                                        user code). Per group: bytes and allocations alive at the timestamp (default: the peak),
+                                        bytes alive at the peak, total bytes and count, largest first (top n, default 20).
+                                        Function, file and line are those of the innermost Python frame.
   byte <value>                      - Format a byte value (e.g., '1024' -> '1.0 KiB').
-  timeline <path>                   - Plot a timeline graph and save it to the specified path.
+  timeline <path> [time]            - Plot a timeline graph and save it to the specified path.
+                                        With `time`, the x axis is the elapsed wall-clock time instead of timesteps.
+  flame <@timestamp|all> <path.svg> - Flamegraph of the callstacks holding memory at the timestamp (weighted by bytes),
+                                        or over the whole trace (weighted by bytes x timesteps alive).
+                                        The folded stacks are written next to it, as <path>.folded.
+  export perfetto <path> [min_size] - Write a Chrome/Perfetto trace JSON: live bytes as a counter track, and allocations
+                                        of at least min_size (default 1MiB) as slices with their callstack.
+  export pprof <path.pb.gz> <@timestamp|all>
+                                    - Write a gzipped pprof heap profile (inuse_space, inuse_objects, alloc_space).
+                                        With @timestamp, inuse is the memory alive at it and alloc_space everything
+                                        allocated up to it; with `all`, inuse is taken at the end of the trace.
+  export csv|parquet|arrow <dir>    - Write the allocations, frames and stack_frames tables (same columns as in SQL, without
+                                        callstack) and timesteps (idx, timestep, offset) to <dir>/<table>.<format>.
+  segments [@timestamp]             - List the allocator segments at the timestamp, with their blocks, free space and stream.
+                                        Without timestamp, print peak reserved vs allocated memory (requires --pickle).
+  frames                            - Show the frame filter: which frames of callstacks are printed (inspect, verbose listings),
+                                        written to SQL and exports, and used by group.
+  frames preset <name>              - all, python-only, cpp-only or user-code-only (Python outside of packages and stdlib).
+  frames hide|keep <regex>          - Hide frames whose "filename:line:name" matches, or always keep them (over hide and preset).
+                                        The regex may be quoted; `|` and `>` in it are not pipes or redirections.
+  frames clear | load <path> | save <path>
+                                    - Reset it, or read/write it as a config file of these rules, one per line (`#` comments).
+  devices                           - List all devices with their number of allocations and peak memory.
+  device [id]                       - Select the device that all other commands run against (show it if no id).
+  check                             - Validate every allocation of the device: empty/unsorted timesteps, length mismatch, zero size, free without alloc
+                                        (only noted if a pickle shows the block was allocated before recording started).
+  save <path>                       - Save the loaded snapshot (all devices) to a binary .tomi cache, reopen with --cache.
+  save-subset <path.zip> <filter>   - Save some allocations of the device as a zip of allocations.json and elements.json,
+                                        re-stacked without the others (reopen with --zip). Filter is one of:
+                                        @start..end (alive in the time range), 3,5,10-20 (indices),
+                                        ~pattern (a "filename:line:name" line of the callstack contains pattern).
+  load-baseline <path.zip>          - Load another run (e.g. before a change) to compare the selected device with.
+  diff [k] [verbose]                - Allocation sites (matched by callstack) added, removed, grown, shrunk or changed since the baseline:
+                                        count, total bytes and bytes alive at each run's peak, largest change at the peak
+                                        first (top k, default 20). Verbose prints whole callstacks.
   q | quit                          - Exit the application.
+
+Pipelines and redirection:
+  top 50 @900 | where frame ~ "backward" | inspect
+                                    - top, peak, where, alive, born, freed and overlap list allocations, which `|` pipes into top, where, inspect
+                                        or save-subset <path.zip>. Quote regexes that contain `|`.
+  <command> > out.txt               - Write the output to a file instead, `>>` appends to it.
+                                        A `>` followed by a number or @timestamp is a filter comparison.
+
+Filters:
+  top and peak take `where <filter>` after their arguments, e.g. `top 10 v where size > 16MiB and frame ~ "attention"`.
+  size|peak <op> 16MiB              - Bytes, with op one of < <= > >= = !=.
+  lifetime <op> 500 | 20ms          - Timesteps alive, or wall-clock time alive.
+  start|end <op> 1200 | @1.5s       - First/last timestep.
+  idx|addr|stream <op> n            - Index, device address (0x... accepted), CUDA stream.
+  action = alloc                    - Trace action (alloc, segment_alloc, free_completed).
+  frame|file|func ~ "regex"         - Some frame ("filename:line:name"), file name or function matches; !~ negates.
+  alive @1200                       - Alive at the timestamp.
+  Combine them with and, or, not and parentheses.
+
+Timestamps:
+  @1200                             - A timestep of the layout.
+  @12.5s, @+340ms, @1500us, @2min   - The first timestep at that wall-clock time since the first event (needs time_us).
+  @100..200, @1.5s..@2s             - A range of them, both ends included.
   
 SQL commands:
   sqlbuild                          - Build the in-memory sqlite database from current data.
@@ -285,10 +885,26 @@ SQL schema:
   CREATE TABLE allocations (
       idx INTEGER PRIMARY KEY, 
       size INTEGER, 
+      stack_id INTEGER,
       callstack TEXT, 
       peak_mem INTEGER,
       start_timestamp INTEGER,
-      end_timestamp INTEGER
+      end_timestamp INTEGER,
+      action TEXT,
+      addr INTEGER,
+      stream INTEGER,
+      time_us INTEGER
+  )
+  CREATE TABLE frames (
+      frame_id INTEGER PRIMARY KEY,
+      name TEXT,
+      filename TEXT,
+      line INTEGER
+  )
+  CREATE TABLE stack_frames (      -- depth 0 is the innermost frame
+      stack_id INTEGER,
+      depth INTEGER,
+      frame_id INTEGER
   )
 "#
                         .to_string(),
@@ -297,8 +913,98 @@ SQL schema:
             "q" | "quit" => Err(Quit.into()),
             _ => Err(anyhow::anyhow!(
                 "Unsupported command: '{}'. Type 'help' for available commands.",
-                cmd
+                command
             )),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Redirect, split_pipeline, split_redirect};
+    use crate::repl_ops::memsnap::test_snapshot;
+
+    #[test]
+    fn test_pipeline() {
+        assert_eq!(
+            split_pipeline(r#"top 50 @900 | where frame ~ "a|b" | inspect"#),
+            vec!["top 50 @900", r#"where frame ~ "a|b""#, "inspect"]
+        );
+        assert_eq!(
+            split_redirect("where size > 1MiB"),
+            ("where size > 1MiB", None)
+        );
+        assert_eq!(
+            split_redirect("top 10 | where size > 1MiB"),
+            ("top 10 | where size > 1MiB", None)
+        );
+        assert_eq!(
+            split_redirect("top 10 > 2024.txt"),
+            (
+                "top 10",
+                Some(Redirect {
+                    path: "2024.txt",
+                    append: false
+                })
+            )
+        );
+        assert_eq!(
+            split_redirect("i 3 >> out.txt"),
+            (
+                "i 3",
+                Some(Redirect {
+                    path: "out.txt",
+                    append: true
+                })
+            )
+        );
+
+        let mut memsnap = test_snapshot();
+
+        let indices = |out: String| -> Vec<usize> {
+            let list = out.rsplit(": ").next().unwrap();
+            list.split(", ").filter_map(|i| i.parse().ok()).collect()
+        };
+        // the piped allocations are filtered in their order: the matches among the top 20, which
+        // start the top 20 matches
+        let top = indices(memsnap.exec("top 20 @57".into()).unwrap());
+        let piped = indices(
+            memsnap
+                .exec("top 20 @57 | where size >= 1MiB".into())
+                .unwrap(),
+        );
+        let filtered = indices(
+            memsnap
+                .exec("top 20 @57 where size >= 1MiB".into())
+                .unwrap(),
+        );
+        let expected: Vec<usize> = top
+            .into_iter()
+            .filter(|&i| memsnap.allocations[i].size >= 1024 * 1024)
+            .collect();
+        assert_eq!(piped, expected);
+        assert!(filtered.starts_with(&piped));
+        assert!(memsnap.exec("byte 1024 | inspect".into()).is_err());
+        assert!(memsnap.exec("top 50 | peak 10".into()).is_err());
+        assert_eq!(
+            memsnap.exec("inspect where size >= 1MiB".into()).unwrap(),
+            memsnap.exec("where size >= 1MiB | inspect".into()).unwrap()
+        );
+        memsnap
+            .exec("frames hide std::vector<int> >|c10".into())
+            .unwrap();
+        assert_eq!(
+            memsnap.frame_filter.hide[0].as_str(),
+            "std::vector<int> >|c10"
+        );
+        memsnap.exec("frames clear".into()).unwrap();
+
+        let path = std::env::temp_dir().join("tomi_test_redirect.txt");
+        let path = path.to_str().unwrap();
+        memsnap.exec(format!("top 3 | inspect > {}", path)).unwrap();
+        memsnap.exec(format!("i 0 >> {}", path)).unwrap();
+        let written = std::fs::read_to_string(path).unwrap();
+        assert_eq!(written.matches("Allocation Details:").count(), 4);
+        std::fs::remove_file(path).unwrap();
+    }
+}
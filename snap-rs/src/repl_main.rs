@@ -1,13 +1,41 @@
-use clap::{Arg, ArgAction, ArgGroup, Command};
+use clap::{Arg, ArgAction, Command, parser::ValueSource, value_parser};
 use rustyline::{DefaultEditor, error::ReadlineError};
-use snap_rs::repl_ops::memsnap::MemSnap;
+use snap_rs::{frame_filter::FrameFilter, layout::Layout, repl_ops::memsnap::MemSnap};
+use std::path::Path;
 
 enum CliArg {
-    Json { alloc: String, elem: String },
+    Json { alloc: Option<String>, elem: String },
     Zip { path: String },
+    Pickle { path: String },
+    Cache { path: String },
 }
 
-fn cli() -> CliArg {
+impl CliArg {
+    /// The source as `MemSnap::source` records it, None for a cache
+    fn source_path(&self) -> Option<String> {
+        match self {
+            CliArg::Json {
+                alloc: Some(alloc),
+                elem,
+            } => Some(format!("{} {}", alloc, elem)),
+            CliArg::Json { alloc: None, elem } => Some(elem.clone()),
+            CliArg::Zip { path } | CliArg::Pickle { path } => Some(path.clone()),
+            CliArg::Cache { .. } => None,
+        }
+    }
+}
+
+struct CliOptions {
+    source: CliArg,
+    layout: Layout,
+    layout_given: bool,    // passed on the command line, not the default
+    device: Option<usize>, // the first device that recorded allocations if None
+    cache: Option<String>,
+    frames: Option<String>,
+    check: bool,
+}
+
+fn cli() -> CliOptions {
     let matches = Command::new("tomi: pyTOrch Memory Inspection tool")
         .arg(
             Arg::new("zip")
@@ -17,23 +45,83 @@ fn cli() -> CliArg {
                 .action(ArgAction::Set)
                 .num_args(1) // Exactly one path
                 .value_name("ZIP_PATH")
-                .conflicts_with("json"), // Cannot be used with --json
+                .conflicts_with_all(["json", "pickle"]), // Cannot be used with --json or --pickle
         )
         .arg(
             Arg::new("json")
                 .short('j')
                 .long("json")
-                .help("Load snap from allocations.json and elements.json files")
+                .help("Load snap from allocations.json and elements.json files, or from a single raw trace json")
                 .action(ArgAction::Set)
-                .num_args(2) // Exactly two paths
+                .num_args(1..=2) // One or two paths
                 .value_name("JSON_PATHS")
-                .conflicts_with("zip"), // Cannot be used with --zip
+                .conflicts_with_all(["zip", "pickle"]), // Cannot be used with --zip or --pickle
+        )
+        .arg(
+            Arg::new("pickle")
+                .short('p')
+                .long("pickle")
+                .help("Load snap from a raw PyTorch snapshot pickle (torch.cuda.memory._snapshot())")
+                .action(ArgAction::Set)
+                .num_args(1) // Exactly one path
+                .value_name("PICKLE_PATH")
+                .conflicts_with_all(["zip", "json"]),
+        )
+        .arg(
+            Arg::new("cache")
+                .short('c')
+                .long("cache")
+                .help("Load snap from a .tomi cache; if the file does not exist, load from --zip/--json/--pickle and write the cache")
+                .action(ArgAction::Set)
+                .num_args(1)
+                .value_name("CACHE_PATH"),
+        )
+        .arg(
+            Arg::new("device")
+                .short('d')
+                .long("device")
+                .help("Device to select after loading, by default the first one that recorded allocations (switch later with the `device` command)")
+                .action(ArgAction::Set)
+                .value_name("DEVICE_ID")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("layout")
+                .short('l')
+                .long("layout")
+                .help("Memory layout of allocations: `stack` (as in PyTorch's viewer) or `address`")
+                .action(ArgAction::Set)
+                .value_name("LAYOUT")
+                .value_parser(|s: &str| s.parse::<Layout>().map_err(|e| e.to_string()))
+                .default_value("stack"),
+        )
+        .arg(
+            Arg::new("frames")
+                .short('f')
+                .long("frames")
+                .help("Frame filter config file: `preset <name>`, `hide <regex>` and `keep <regex>` rules, one per line")
+                .action(ArgAction::Set)
+                .num_args(1)
+                .value_name("FRAMES_PATH"),
+        )
+        .arg(
+            Arg::new("check")
+                .long("check")
+                .help("Validate every allocation of every device, print the problems and exit (non-zero if any; frees of blocks that a pickle shows were allocated before recording are only noted)")
+                .action(ArgAction::SetTrue),
         )
         // You could also use an ArgGroup for mutual exclusivity, but conflicts_with is more direct here.
         // If you had more complex "either/or" scenarios, ArgGroup would be powerful.
         .get_matches();
 
-    if let Some(zip_paths) = matches.get_many::<String>("zip") {
+    let layout = *matches.get_one::<Layout>("layout").unwrap();
+    let layout_given = matches.value_source("layout") == Some(ValueSource::CommandLine);
+    let device = matches.get_one::<usize>("device").copied();
+    let cache = matches.get_one::<String>("cache").cloned();
+    let frames = matches.get_one::<String>("frames").cloned();
+    let check = matches.get_flag("check");
+
+    let source = if let Some(zip_paths) = matches.get_many::<String>("zip") {
         let path: Vec<_> = zip_paths.map(|s| s.as_str()).collect();
         CliArg::Zip {
             path: path[0].to_string(),
@@ -41,16 +129,40 @@ fn cli() -> CliArg {
     } else if let Some(json_paths) = matches.get_many::<String>("json") {
         let paths: Vec<_> = json_paths.map(|s| s.as_str()).collect();
 
-        CliArg::Json {
-            alloc: paths[0].to_string(),
-            elem: paths[1].to_string(),
+        match paths.as_slice() {
+            [trace] => CliArg::Json {
+                alloc: None,
+                elem: trace.to_string(),
+            },
+            _ => CliArg::Json {
+                alloc: Some(paths[0].to_string()),
+                elem: paths[1].to_string(),
+            },
+        }
+    } else if let Some(pickle_path) = matches.get_one::<String>("pickle") {
+        CliArg::Pickle {
+            path: pickle_path.to_string(),
+        }
+    } else if let Some(cache_path) = &cache {
+        CliArg::Cache {
+            path: cache_path.to_string(),
         }
     } else {
         eprintln!(
-            "No valid arguments provided. Use --zip <PATH> or --json <ALLOC_PATH> <ELEM_PATH>."
+            "No valid arguments provided. Use --zip <PATH>, --json <ALLOC_PATH> <ELEM_PATH>, --json <TRACE_PATH>, --pickle <PATH> or --cache <PATH>."
         );
 
         std::process::exit(1);
+    };
+
+    CliOptions {
+        source,
+        layout,
+        layout_given,
+        device,
+        cache,
+        frames,
+        check,
     }
 }
 
@@ -60,11 +172,51 @@ fn main() -> anyhow::Result<()> {
         .filter_module("snap_rs", log::LevelFilter::Info)
         .init();
 
-    let snap_opt = match cli() {
-        CliArg::Json { alloc, elem } => MemSnap::from_jsons(&alloc, &elem),
-        CliArg::Zip { path } => MemSnap::from_zip(&path),
+    let CliOptions {
+        source,
+        layout,
+        layout_given,
+        device,
+        cache,
+        frames,
+        check,
+    } = cli();
+    let snap_opt = match source {
+        // a cache that already exists is loaded instead, if it was written from the same source
+        // with the same layout
+        _ if cache
+            .as_deref()
+            .is_some_and(|path| Path::new(path).exists()) =>
+        {
+            let source_path = source.source_path();
+            let layout = (source_path.is_some() || layout_given).then_some(layout);
+            MemSnap::from_cache(cache.as_deref().unwrap(), source_path.as_deref(), layout)
+        }
+        CliArg::Cache { path } => Err(anyhow::anyhow!(
+            "Cache file '{}' does not exist. Create it by also passing --zip, --json or --pickle",
+            path
+        )),
+        CliArg::Json {
+            alloc: Some(alloc),
+            elem,
+        } => MemSnap::from_jsons(&alloc, &elem, layout),
+        CliArg::Json { alloc: None, elem } => MemSnap::from_trace_json(&elem, layout),
+        CliArg::Zip { path } => MemSnap::from_zip(&path, layout),
+        CliArg::Pickle { path } => MemSnap::from_pickle(&path, layout),
     };
 
+    let snap_opt = match (&cache, snap_opt) {
+        // loaded from another source: write the cache for next time
+        (Some(path), Ok(mut snap)) if !Path::new(path).exists() => {
+            snap.save_cache(path).map(|_| snap)
+        }
+        (_, snap_opt) => snap_opt,
+    };
+
+    let snap_opt = match device {
+        Some(device) => snap_opt.and_then(|mut snap| snap.select_device(device).map(|_| snap)),
+        None => snap_opt,
+    };
     let mut snap = match snap_opt {
         Ok(snap) => snap,
         Err(err) => {
@@ -73,6 +225,22 @@ fn main() -> anyhow::Result<()> {
         }
     };
 
+    if let Some(path) = frames {
+        match FrameFilter::load(&path) {
+            Ok(filter) => snap.set_frame_filter(filter),
+            Err(err) => {
+                eprintln!("Error loading frame filter: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if check {
+        let (report, issues) = snap.check_all_devices();
+        println!("{}", report);
+        std::process::exit(if issues == 0 { 0 } else { 1 });
+    }
+
     let mut rl = DefaultEditor::new()?;
     loop {
         let readline = rl.readline("tomi> ");
@@ -0,0 +1,327 @@
+use super::memsnap::{MemSnap, Source};
+use crate::allocation::Allocation;
+use crate::allocator::SegmentHistory;
+use crate::callstack::{CallstackTable, StackId};
+use crate::layout::Layout;
+use crate::load::LoadedSnap;
+use log::info;
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// First bytes of every .tomi file
+const MAGIC: &[u8; 4] = b"TOMI";
+/// Bumped whenever the layout of the header or of `CacheFile` changes
+const VERSION: u32 = 4;
+
+/// On-disk layout of a .tomi file (after the magic, the version and the `Option<Source>` of the
+/// snap), encoded with bincode
+#[derive(Deserialize)]
+struct CacheFile {
+    selected_device: usize,
+    devices: Vec<CachedDevice>,
+}
+
+#[derive(Deserialize)]
+struct CachedDevice {
+    device: usize,
+    allocations: Vec<Allocation>,
+    callstacks: CallstackTable,
+    segments: Option<SegmentHistory>,
+    timestamps: Vec<u64>,
+    global_sorted_sizes: Vec<usize>,
+    peak_sorted_sizes: Vec<usize>,
+}
+
+/// Borrowed `CacheFile`, so that saving does not copy the snap. Fields must match `CacheFile`.
+#[derive(Serialize)]
+struct CacheFileRef<'a> {
+    selected_device: usize,
+    devices: Vec<CachedDeviceRef<'a>>,
+}
+
+#[derive(Serialize)]
+struct CachedDeviceRef<'a> {
+    device: usize,
+    allocations: &'a [Allocation],
+    callstacks: &'a CallstackTable,
+    segments: Option<&'a SegmentHistory>,
+    timestamps: &'a [u64],
+    global_sorted_sizes: &'a [usize],
+    peak_sorted_sizes: &'a [usize],
+}
+
+impl MemSnap {
+    /// Write all devices, with their sorted indices, to a .tomi file
+    pub fn save_cache(&mut self, path: &str) -> anyhow::Result<()> {
+        let selected_device = self.device;
+
+        self.build_global_sorted_sizes();
+        self.build_peak_sorted_sizes();
+        for snap in self.other_devices.values_mut() {
+            snap.build_global_sorted_sizes();
+            snap.build_peak_sorted_sizes();
+        }
+
+        let mut devices = vec![cache_device(self)];
+        devices.extend(self.other_devices.values().map(cache_device));
+
+        info!("Writing cache to {}", path);
+        let file = File::create(path)
+            .map_err(|e| anyhow::anyhow!("Failed to create cache file '{}': {}", path, e))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        bincode::serialize_into(&mut writer, &self.source)?;
+        bincode::serialize_into(
+            &mut writer,
+            &CacheFileRef {
+                selected_device,
+                devices,
+            },
+        )?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Load a .tomi file written by `save_cache`, decoding it straight from a memory map of the
+    /// file. If `source` or `layout` is given, the cache must have been written from that source,
+    /// or with that layout.
+    pub fn from_cache(
+        path: &str,
+        source: Option<&str>,
+        layout: Option<Layout>,
+    ) -> anyhow::Result<Self> {
+        info!("Loading allocations from cache...");
+        let file = File::open(path)
+            .map_err(|e| anyhow::anyhow!("Failed to open cache file '{}': {}", path, e))?;
+        // SAFETY: the map is read-only and dropped before returning; nothing may truncate the
+        // cache file while it is being loaded
+        let mmap = unsafe { Mmap::map(&file) }
+            .map_err(|e| anyhow::anyhow!("Failed to map cache file '{}': {}", path, e))?;
+
+        let header_len = MAGIC.len() + size_of::<u32>();
+        if mmap.len() < header_len || &mmap[..MAGIC.len()] != MAGIC {
+            return Err(anyhow::anyhow!("'{}' is not a tomi cache file", path));
+        }
+        let version = u32::from_le_bytes(mmap[MAGIC.len()..header_len].try_into()?);
+        if version != VERSION {
+            return Err(anyhow::anyhow!(
+                "Cache file '{}' has version {}, expected {}. Please re-create it with `save`",
+                path,
+                version,
+                VERSION
+            ));
+        }
+
+        let decode_error = |e| anyhow::anyhow!("Failed to decode cache file '{}': {}", path, e);
+        let body = &mmap[header_len..];
+        let cached_source: Option<Source> = bincode::deserialize(body).map_err(decode_error)?;
+        check_source(path, cached_source.as_ref(), source, layout)?;
+
+        let source_len = bincode::serialized_size(&cached_source)? as usize;
+        let cache: CacheFile = bincode::deserialize(&body[source_len..]).map_err(decode_error)?;
+
+        let mut snaps = cache
+            .devices
+            .into_iter()
+            .map(|device| {
+                restore_device(device)
+                    .map_err(|e| anyhow::anyhow!("Cache file '{}' is corrupt: {}", path, e))
+            })
+            .collect::<anyhow::Result<Vec<MemSnap>>>()?;
+
+        let selected = snaps
+            .iter()
+            .position(|snap| snap.device == cache.selected_device)
+            .ok_or_else(|| anyhow::anyhow!("Cache file '{}' contains no device", path))?;
+        for snap in snaps.iter_mut() {
+            snap.source = cached_source.clone();
+        }
+        let mut snap = snaps.swap_remove(selected);
+        snap.other_devices = snaps.into_iter().map(|s| (s.device, s)).collect();
+
+        Ok(snap)
+    }
+}
+
+/// Rejects a cache written from another source or with another layout than expected
+fn check_source(
+    path: &str,
+    cached: Option<&Source>,
+    source: Option<&str>,
+    layout: Option<Layout>,
+) -> anyhow::Result<()> {
+    if source.is_none() && layout.is_none() {
+        return Ok(());
+    }
+    let Some(cached) = cached else {
+        return Err(anyhow::anyhow!(
+            "Cache file '{}' does not record its source, delete it or pass another cache path",
+            path
+        ));
+    };
+    if let Some(source) = source.filter(|&source| source != cached.path) {
+        return Err(anyhow::anyhow!(
+            "Cache file '{}' was written from '{}', not '{}'. Delete it or pass another cache path",
+            path,
+            cached.path,
+            source
+        ));
+    }
+    if let Some(layout) = layout.filter(|&layout| layout != cached.layout) {
+        return Err(anyhow::anyhow!(
+            "Cache file '{}' was written with the {:?} layout, not {:?}. Delete it or pass another cache path",
+            path,
+            cached.layout,
+            layout
+        ));
+    }
+    Ok(())
+}
+
+/// `snap` must have its sorted indices built
+fn cache_device(snap: &MemSnap) -> CachedDeviceRef<'_> {
+    CachedDeviceRef {
+        device: snap.device,
+        allocations: &snap.allocations,
+        callstacks: &snap.callstacks,
+        segments: snap.segments.as_ref(),
+        timestamps: &snap.timestamps,
+        global_sorted_sizes: snap.global_sorted_sizes.as_deref().unwrap(),
+        peak_sorted_sizes: snap.peak_sorted_sizes.as_deref().unwrap(),
+    }
+}
+
+/// Rejects sorted indices that are not one per allocation
+fn check_sorted(name: &str, indices: &[usize], num_allocations: usize) -> anyhow::Result<()> {
+    if indices.len() != num_allocations {
+        return Err(anyhow::anyhow!(
+            "{} has {} indices for {} allocations",
+            name,
+            indices.len(),
+            num_allocations
+        ));
+    }
+    if let Some(index) = indices.iter().find(|&&index| index >= num_allocations) {
+        return Err(anyhow::anyhow!("{} index out of range: {}", name, index));
+    }
+    Ok(())
+}
+
+/// Every index of a decoded device must be in range, a corrupt cache would panic later otherwise
+fn restore_device(cached: CachedDevice) -> anyhow::Result<MemSnap> {
+    let callstacks = &cached.callstacks;
+    let num_stacks = callstacks.num_stacks();
+    if let Some(alloc) = cached
+        .allocations
+        .iter()
+        .find(|alloc| alloc.stack as usize >= num_stacks)
+    {
+        return Err(anyhow::anyhow!("Stack id out of range: {}", alloc.stack));
+    }
+    if let Some(frame) = (0..num_stacks as StackId)
+        .flat_map(|stack| callstacks.frame_ids(stack))
+        .find(|&&frame| frame as usize >= callstacks.num_frames())
+    {
+        return Err(anyhow::anyhow!("Frame id out of range: {}", frame));
+    }
+    let num_allocations = cached.allocations.len();
+    check_sorted("Size order", &cached.global_sorted_sizes, num_allocations)?;
+    check_sorted("Peak order", &cached.peak_sorted_sizes, num_allocations)?;
+
+    info!(
+        "Device {}: {} allocations",
+        cached.device,
+        cached.allocations.len()
+    );
+    let loaded = LoadedSnap {
+        allocations: cached.allocations,
+        callstacks: cached.callstacks,
+        segments: cached.segments,
+    };
+    let mut snap = MemSnap::with_timestamps(loaded, cached.timestamps);
+    snap.device = cached.device;
+    snap.global_sorted_sizes = Some(cached.global_sorted_sizes);
+    snap.peak_sorted_sizes = Some(cached.peak_sorted_sizes);
+    Ok(snap)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CachedDevice, restore_device};
+    use crate::layout::Layout;
+    use crate::repl_ops::memsnap::{MemSnap, test_snapshot};
+
+    #[test]
+    fn test_cache_roundtrip() {
+        let alloc_path = "../snapshots/allocations.json";
+        let elements_path = "../snapshots/elements.json";
+        let cache_path = std::env::temp_dir().join("tomi_test_cache_roundtrip.tomi");
+        let cache_path = cache_path.to_str().unwrap();
+
+        let mut memsnap = MemSnap::from_jsons(alloc_path, elements_path, Layout::Stack).unwrap();
+        memsnap.save_cache(cache_path).unwrap();
+
+        let mut cached = MemSnap::from_cache(cache_path, None, None).unwrap();
+        assert_eq!(cached.source, memsnap.source);
+        assert_eq!(memsnap.allocations.len(), cached.allocations.len());
+        assert_eq!(memsnap.timestamps, cached.timestamps);
+        for (a, b) in memsnap.allocations.iter().zip(cached.allocations.iter()) {
+            assert_eq!(a.timesteps, b.timesteps);
+            assert_eq!(a.offsets, b.offsets);
+            assert!(
+                memsnap
+                    .callstacks
+                    .frames(a.stack)
+                    .eq(cached.callstacks.frames(b.stack))
+            );
+            assert_eq!(a.addr, b.addr);
+        }
+        assert_eq!(
+            memsnap.global_topk(10).unwrap(),
+            cached.global_topk(10).unwrap()
+        );
+        assert_eq!(
+            memsnap.peak_topk(10).unwrap(),
+            cached.peak_topk(10).unwrap()
+        );
+
+        // a cache of another source or layout is rejected
+        let source = format!("{} {}", alloc_path, elements_path);
+        assert!(MemSnap::from_cache(cache_path, Some(&source), Some(Layout::Stack)).is_ok());
+        assert!(MemSnap::from_cache(cache_path, Some("other.zip"), None).is_err());
+        assert!(MemSnap::from_cache(cache_path, None, Some(Layout::Address)).is_err());
+
+        // a truncated cache is an error, not a panic
+        let bytes = std::fs::read(cache_path).unwrap();
+        for len in (0..bytes.len()).step_by(bytes.len() / 50) {
+            std::fs::write(cache_path, &bytes[..len]).unwrap();
+            assert!(MemSnap::from_cache(cache_path, None, None).is_err());
+        }
+
+        std::fs::write(cache_path, b"not a cache").unwrap();
+        assert!(MemSnap::from_cache(cache_path, None, None).is_err());
+        std::fs::remove_file(cache_path).unwrap();
+
+        // so are indices out of range
+        let num_allocations = memsnap.allocations.len();
+        let device = |global_sorted_sizes: Vec<usize>| {
+            let snap = test_snapshot();
+            CachedDevice {
+                device: 0,
+                allocations: snap.allocations,
+                callstacks: snap.callstacks,
+                segments: None,
+                timestamps: snap.timestamps,
+                global_sorted_sizes,
+                peak_sorted_sizes: (0..num_allocations).collect(),
+            }
+        };
+        assert!(restore_device(device((0..num_allocations).collect())).is_ok());
+        assert!(restore_device(device((1..=num_allocations).collect())).is_err());
+        assert!(restore_device(device((1..num_allocations).collect())).is_err());
+    }
+}
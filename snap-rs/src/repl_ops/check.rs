@@ -0,0 +1,303 @@
+use super::memsnap::{AllocationIndex, MemSnap};
+use crate::allocation::Allocation;
+use std::fmt::{Display, Formatter};
+
+/// Something wrong with one allocation of a snapshot
+#[derive(Debug, PartialEq, Eq)]
+pub enum Problem {
+    EmptyTimesteps,
+    EmptyOffsets,
+    LengthMismatch {
+        timesteps: usize,
+        offsets: usize,
+    },
+    UnsortedTimesteps {
+        position: usize,
+        previous: u64,
+        timestep: u64,
+    },
+    ZeroSize,
+    FreeWithoutAlloc {
+        action: String,
+    },
+    /// A free with no matching alloc, of a block that the allocator state at the start of the
+    /// trace holds: the recording started mid-run, so it is a note rather than an error.
+    AllocatedBeforeRecording {
+        action: String,
+    },
+}
+
+impl Problem {
+    /// Whether the snapshot is malformed, rather than known to be recorded mid-run
+    pub fn is_error(&self) -> bool {
+        !matches!(self, Problem::AllocatedBeforeRecording { .. })
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::EmptyTimesteps => write!(f, "empty timesteps"),
+            Problem::EmptyOffsets => write!(f, "empty offsets"),
+            Problem::LengthMismatch { timesteps, offsets } => write!(
+                f,
+                "length mismatch: {} timesteps vs {} offsets",
+                timesteps, offsets
+            ),
+            Problem::UnsortedTimesteps {
+                position,
+                previous,
+                timestep,
+            } => write!(
+                f,
+                "unsorted timesteps: {} after {} at position {}",
+                timestep, previous, position
+            ),
+            Problem::ZeroSize => write!(f, "zero size"),
+            Problem::FreeWithoutAlloc { action } => {
+                write!(f, "free with no matching alloc (action `{}`)", action)
+            }
+            Problem::AllocatedBeforeRecording { action } => write!(
+                f,
+                "note: free with no matching alloc, the block was allocated before recording started (action `{}`)",
+                action
+            ),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Issue {
+    pub index: AllocationIndex,
+    pub problem: Problem,
+}
+
+/// Every problem of `alloc`. `allocated_before` is whether its block is known to be allocated
+/// before the trace started, see `SegmentHistory::allocated_before`.
+pub fn check_allocation(alloc: &Allocation, allocated_before: bool) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    if alloc.timesteps.is_empty() {
+        problems.push(Problem::EmptyTimesteps);
+    }
+    if alloc.offsets.is_empty() {
+        problems.push(Problem::EmptyOffsets);
+    }
+    if alloc.timesteps.len() != alloc.offsets.len() {
+        problems.push(Problem::LengthMismatch {
+            timesteps: alloc.timesteps.len(),
+            offsets: alloc.offsets.len(),
+        });
+    }
+    if let Some(position) = alloc.timesteps.windows(2).position(|w| w[0] > w[1]) {
+        problems.push(Problem::UnsortedTimesteps {
+            position: position + 1,
+            previous: alloc.timesteps[position],
+            timestep: alloc.timesteps[position + 1],
+        });
+    }
+    if alloc.size == 0 {
+        problems.push(Problem::ZeroSize);
+    }
+    if matches!(alloc.action.as_str(), "free" | "free_completed") {
+        let action = alloc.action.clone();
+        problems.push(match allocated_before {
+            true => Problem::AllocatedBeforeRecording { action },
+            false => Problem::FreeWithoutAlloc { action },
+        });
+    }
+
+    problems
+}
+
+impl MemSnap {
+    /// Validates every allocation of the selected device
+    pub fn check(&self) -> Vec<Issue> {
+        self.allocations
+            .iter()
+            .enumerate()
+            .flat_map(|(index, alloc)| {
+                let allocated_before = self
+                    .segments
+                    .as_ref()
+                    .is_some_and(|history| history.allocated_before(alloc.addr));
+                check_allocation(alloc, allocated_before)
+                    .into_iter()
+                    .map(move |problem| Issue { index, problem })
+            })
+            .collect()
+    }
+
+    /// One line per issue of the selected device, and the number of errors (notes do not count)
+    pub fn check_report(&self) -> (String, usize) {
+        let issues = self.check();
+        let errors = issues
+            .iter()
+            .filter(|issue| issue.problem.is_error())
+            .count();
+        let notes = issues.len() - errors;
+
+        let mut lines = vec![format!(
+            "device {}: {} problems found in {} allocations{}",
+            self.device,
+            if errors == 0 {
+                "no".to_string()
+            } else {
+                errors.to_string()
+            },
+            self.allocations.len(),
+            match notes {
+                0 => String::new(),
+                n => format!(
+                    ", {} blocks allocated before recording started (the trace starts mid-run)",
+                    n
+                ),
+            }
+        )];
+        for issue in &issues {
+            lines.push(format!("  #{}: {}", issue.index, issue.problem));
+        }
+
+        (lines.join("\n"), errors)
+    }
+
+    /// `check_report` of every device
+    pub fn check_all_devices(&self) -> (String, usize) {
+        let mut reports = vec![(self.device, self.check_report())];
+        reports.extend(
+            self.other_devices
+                .values()
+                .map(|snap| (snap.device, snap.check_report())),
+        );
+        reports.sort_by_key(|(device, _)| *device);
+
+        let issues = reports.iter().map(|(_, (_, n))| n).sum();
+        let report = reports
+            .into_iter()
+            .map(|(_, (report, _))| report)
+            .collect::<Vec<_>>()
+            .join("\n");
+        (report, issues)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Issue, Problem, check_allocation};
+    use crate::allocator::{AllocatorAction, AllocatorEvent, SegmentHistory};
+    use crate::pickle::Segment;
+    use crate::{
+        layout::Layout,
+        load::{load_allocations, read_snap_from_jsons},
+        repl_ops::memsnap::MemSnap,
+    };
+
+    #[test]
+    fn test_check() {
+        let alloc_path = "../snapshots/allocations.json";
+        let elements_path = "../snapshots/elements.json";
+
+        let mut loaded = load_allocations(
+            read_snap_from_jsons(alloc_path, elements_path).unwrap(),
+            Layout::Stack,
+        )
+        .unwrap();
+        assert!(
+            loaded
+                .allocations
+                .iter()
+                .all(|alloc| check_allocation(alloc, false).is_empty())
+        );
+
+        let allocations = &mut loaded.allocations;
+        allocations[0].offsets.clear();
+        allocations[1].timesteps.swap(0, 1);
+        allocations[2].size = 0;
+        allocations[3].timesteps.clear();
+        allocations[3].offsets.clear();
+        allocations[4].action = "free_completed".to_string();
+
+        let mut memsnap = MemSnap::new(loaded);
+        let issues = memsnap.check();
+        let problems_of = |index| {
+            issues
+                .iter()
+                .filter(|issue| issue.index == index)
+                .map(|issue| &issue.problem)
+                .collect::<Vec<_>>()
+        };
+
+        assert!(matches!(
+            problems_of(0)[..],
+            [
+                Problem::EmptyOffsets,
+                Problem::LengthMismatch { offsets: 0, .. }
+            ]
+        ));
+        assert!(matches!(
+            problems_of(1)[..],
+            [Problem::UnsortedTimesteps { position: 1, .. }]
+        ));
+        assert_eq!(problems_of(2), vec![&Problem::ZeroSize]);
+        assert_eq!(
+            problems_of(3),
+            vec![&Problem::EmptyTimesteps, &Problem::EmptyOffsets]
+        );
+        assert!(issues.contains(&Issue {
+            index: 4,
+            problem: Problem::FreeWithoutAlloc {
+                action: "free_completed".to_string()
+            }
+        }));
+
+        let (report, count) = memsnap.check_report();
+        assert_eq!(count, issues.len());
+        assert!(report.starts_with(&format!(
+            "device 0: {} problems found in {} allocations\n",
+            count,
+            memsnap.allocations.len()
+        )));
+        assert_eq!(report.lines().count(), issues.len() + 1);
+
+        // unless the allocator state at the start of the trace (rewound from the segments of a
+        // pickle) holds the block: then the recording started mid-run, which is only noted
+        let addr = memsnap.allocations[4].addr;
+        let segment = Segment {
+            device: 0,
+            address: addr,
+            total_size: 1 << 20,
+            allocated_size: 0,
+            active_size: 0,
+            requested_size: 0,
+            stream: 0,
+            segment_type: "small".to_string(),
+            frames: Vec::new(),
+            blocks: Vec::new(),
+        };
+        let free = AllocatorEvent {
+            action: AllocatorAction::FreeCompleted,
+            addr,
+            size: memsnap.allocations[4].size,
+            stream: 0,
+            timestep: 1,
+        };
+        memsnap.segments = Some(SegmentHistory::rewind(&[segment], vec![free]));
+        let issues = memsnap.check();
+        assert!(issues.contains(&Issue {
+            index: 4,
+            problem: Problem::AllocatedBeforeRecording {
+                action: "free_completed".to_string()
+            }
+        }));
+        let (report, count) = memsnap.check_report();
+        assert_eq!(count, issues.len() - 1);
+        assert!(report.starts_with(&format!(
+            "device 0: {} problems found in {} allocations, 1 blocks allocated before recording started (the trace starts mid-run)",
+            count,
+            memsnap.allocations.len()
+        )));
+
+        // malformed allocations must not break the rest of the snap
+        assert!(!memsnap.allocations[3].is_alive_at(0));
+    }
+}
@@ -0,0 +1,215 @@
+use super::memsnap::MemSnap;
+use crate::callstack::{FrameId, StackId};
+use arrow::array::{ArrayRef, RecordBatch, StringArray, UInt32Array, UInt64Array};
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+/// File format of `export_tables`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableFormat {
+    Csv,
+    Parquet,
+    Arrow, // Arrow IPC file, a.k.a. Feather v2
+}
+
+impl TableFormat {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "csv" => Some(TableFormat::Csv),
+            "parquet" => Some(TableFormat::Parquet),
+            "arrow" | "feather" => Some(TableFormat::Arrow),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TableFormat::Csv => "csv",
+            TableFormat::Parquet => "parquet",
+            TableFormat::Arrow => "arrow",
+        }
+    }
+}
+
+fn u64s(values: impl Iterator<Item = u64>) -> ArrayRef {
+    Arc::new(UInt64Array::from_iter_values(values))
+}
+
+fn u32s(values: impl Iterator<Item = u32>) -> ArrayRef {
+    Arc::new(UInt32Array::from_iter_values(values))
+}
+
+fn strings<'a>(values: impl Iterator<Item = &'a str>) -> ArrayRef {
+    Arc::new(StringArray::from_iter_values(values))
+}
+
+impl MemSnap {
+    /// The tables of `export_tables`, by name: the sqlite tables without the formatted callstack,
+    /// plus `timesteps` with one row per (allocation, timestep).
+    pub fn record_batches(&self) -> anyhow::Result<Vec<(&'static str, RecordBatch)>> {
+        let allocs = &self.allocations;
+        let times: Vec<(u64, u64)> = allocs.iter().map(|a| a.start_end_time()).collect();
+        let allocations = RecordBatch::try_from_iter([
+            ("idx", u64s(0..allocs.len() as u64)),
+            ("size", u64s(allocs.iter().map(|a| a.size))),
+            ("stack_id", u32s(allocs.iter().map(|a| a.stack))),
+            ("peak_mem", u64s(allocs.iter().map(|a| a.peak_mem))),
+            ("start_timestamp", u64s(times.iter().map(|t| t.0))),
+            ("end_timestamp", u64s(times.iter().map(|t| t.1))),
+            ("action", strings(allocs.iter().map(|a| a.action.as_str()))),
+            ("addr", u64s(allocs.iter().map(|a| a.addr))),
+            ("stream", u64s(allocs.iter().map(|a| a.stream))),
+            ("time_us", u64s(allocs.iter().map(|a| a.time_us))),
+        ])?;
+
+        let frames: Vec<_> = (0..self.callstacks.num_frames() as FrameId)
+            .map(|id| self.callstacks.frame(id))
+            .collect();
+        let frames = RecordBatch::try_from_iter([
+            ("frame_id", u32s(0..frames.len() as u32)),
+            ("name", strings(frames.iter().map(|f| f.name.as_str()))),
+            (
+                "filename",
+                strings(frames.iter().map(|f| f.filename.as_str())),
+            ),
+            ("line", u32s(frames.iter().map(|f| f.line))),
+        ])?;
+
+        // depth 0 is the innermost frame
+        let stack_frames: Vec<(StackId, u32, FrameId)> = (0..self.callstacks.num_stacks()
+            as StackId)
+            .flat_map(|stack| {
+                self.shown_frame_ids(stack)
+                    .enumerate()
+                    .map(move |(depth, frame)| (stack, depth as u32, frame))
+            })
+            .collect();
+        let stack_frames = RecordBatch::try_from_iter([
+            ("stack_id", u32s(stack_frames.iter().map(|r| r.0))),
+            ("depth", u32s(stack_frames.iter().map(|r| r.1))),
+            ("frame_id", u32s(stack_frames.iter().map(|r| r.2))),
+        ])?;
+
+        let steps: Vec<(u64, u64, u64)> = allocs
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, a)| {
+                a.timesteps
+                    .iter()
+                    .zip(&a.offsets)
+                    .map(move |(&t, &offset)| (idx as u64, t, offset))
+            })
+            .collect();
+        let timesteps = RecordBatch::try_from_iter([
+            ("idx", u64s(steps.iter().map(|r| r.0))),
+            ("timestep", u64s(steps.iter().map(|r| r.1))),
+            ("offset", u64s(steps.iter().map(|r| r.2))),
+        ])?;
+
+        Ok(vec![
+            ("allocations", allocations),
+            ("frames", frames),
+            ("stack_frames", stack_frames),
+            ("timesteps", timesteps),
+        ])
+    }
+
+    /// Writes every table of `record_batches` to `<dir>/<table>.<ext>`, creating `dir` if needed.
+    /// Returns the written paths.
+    pub fn export_tables(&self, dir: &str, format: TableFormat) -> anyhow::Result<Vec<String>> {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| anyhow::anyhow!("Failed to create directory '{}': {}", dir, e))?;
+
+        let mut paths = Vec::new();
+        for (name, batch) in self.record_batches()? {
+            let path = Path::new(dir).join(format!("{}.{}", name, format.extension()));
+            let file = File::create(&path)
+                .map_err(|e| anyhow::anyhow!("Failed to create '{}': {}", path.display(), e))?;
+
+            match format {
+                TableFormat::Csv => {
+                    let mut writer = arrow::csv::Writer::new(file);
+                    writer.write(&batch)?;
+                }
+                TableFormat::Parquet => {
+                    let mut writer =
+                        parquet::arrow::ArrowWriter::try_new(file, batch.schema(), None)?;
+                    writer.write(&batch)?;
+                    writer.close()?;
+                }
+                TableFormat::Arrow => {
+                    let mut writer =
+                        arrow::ipc::writer::FileWriter::try_new(file, &batch.schema())?;
+                    writer.write(&batch)?;
+                    writer.finish()?;
+                }
+            }
+            paths.push(path.to_string_lossy().into_owned());
+        }
+
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TableFormat;
+    use crate::{layout::Layout, repl_ops::memsnap::MemSnap};
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    #[test]
+    fn test_export_tables() {
+        let alloc_path = "../snapshots/allocations.json";
+        let elements_path = "../snapshots/elements.json";
+        let dir = std::env::temp_dir().join("tomi_test_tables");
+        let dir = dir.to_str().unwrap();
+
+        let memsnap = MemSnap::from_jsons(alloc_path, elements_path, Layout::Stack).unwrap();
+        let batches = memsnap.record_batches().unwrap();
+        let rows = |name| {
+            batches
+                .iter()
+                .find(|(table, _)| *table == name)
+                .unwrap()
+                .1
+                .num_rows()
+        };
+        assert_eq!(rows("allocations"), memsnap.allocations.len());
+        assert_eq!(rows("frames"), memsnap.callstacks.num_frames());
+        assert_eq!(
+            rows("timesteps"),
+            memsnap
+                .allocations
+                .iter()
+                .map(|a| a.timesteps.len())
+                .sum::<usize>()
+        );
+
+        for format in [TableFormat::Csv, TableFormat::Parquet, TableFormat::Arrow] {
+            let paths = memsnap.export_tables(dir, format).unwrap();
+            assert_eq!(paths.len(), batches.len());
+            assert!(
+                paths
+                    .iter()
+                    .all(|p| std::fs::metadata(p).unwrap().len() > 0)
+            );
+        }
+
+        // the csv of allocations has a header and one line per allocation
+        let csv = std::fs::read_to_string(format!("{}/allocations.csv", dir)).unwrap();
+        assert!(csv.starts_with("idx,size,stack_id,"));
+        assert_eq!(csv.lines().count(), memsnap.allocations.len() + 1);
+
+        let reader = SerializedFileReader::new(
+            std::fs::File::open(format!("{}/timesteps.parquet", dir)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            reader.metadata().file_metadata().num_rows() as usize,
+            rows("timesteps")
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
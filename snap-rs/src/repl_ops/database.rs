@@ -1,21 +1,26 @@
 use super::memsnap::MemSnap;
-use crate::allocation::Frame;
+use crate::callstack::{CallstackTable, FrameId, StackId};
 use rusqlite::Connection;
-use std::fmt::Write;
 
 #[derive(Debug)]
 pub struct AllocationDbRow {
     pub index: usize,
     pub size: u64,
+    pub stack_id: StackId,
     pub callstack: String,
     pub peak_mem: u64,
     pub start_timestamp: u64,
     pub end_timestamp: u64,
+    pub action: String,
+    pub addr: u64,
+    pub stream: u64,
+    pub time_us: u64,
 }
 
-pub fn format_callstack(frames: &[Frame]) -> String {
-    frames
-        .iter()
+/// One "filename:line:name" line per frame of `stack`, innermost first
+pub fn format_callstack(callstacks: &CallstackTable, stack: StackId) -> String {
+    callstacks
+        .frames(stack)
         .map(|frame| format!("{}:{}:{}", frame.filename, frame.line, frame.name))
         .collect::<Vec<String>>()
         .join("\n")
@@ -25,19 +30,34 @@ impl MemSnap {
     /// 可能被sql用到的字段：
     /// id: index int
     /// size: int
-    /// callstack: varchar
+    /// stack_id: int, callstack: varchar
     /// peak_mem: int
+    /// action: varchar, addr: int, stream: int, time_us: int
+    ///
+    /// Plus the interned callstacks: `frames` (frame_id, name, filename, line) and
+    /// `stack_frames` (stack_id, depth, frame_id), depth 0 being the innermost frame.
     pub fn build_sqlite(&mut self) -> Result<(), anyhow::Error> {
+        // every distinct stack is only formatted once
+        let callstacks: Vec<String> = (0..self.callstacks.num_stacks() as StackId)
+            .map(|stack| self.shown_callstack(stack))
+            .collect();
+
         let rows = self.allocations.iter().enumerate().map(|(index, alloc)| {
-            let callstack = format_callstack(&alloc.callstack);
+            let callstack = callstacks[alloc.stack as usize].clone();
+            let (start_timestamp, end_timestamp) = alloc.start_end_time();
 
             AllocationDbRow {
                 index,
                 size: alloc.size,
+                stack_id: alloc.stack,
                 callstack,
                 peak_mem: alloc.peak_mem,
-                start_timestamp: alloc.timesteps[0],
-                end_timestamp: *alloc.timesteps.last().unwrap(),
+                start_timestamp,
+                end_timestamp,
+                action: alloc.action.clone(),
+                addr: alloc.addr,
+                stream: alloc.stream,
+                time_us: alloc.time_us,
             }
         });
 
@@ -49,10 +69,15 @@ impl MemSnap {
                 "CREATE TABLE allocations (
 idx INTEGER PRIMARY KEY, 
 size INTEGER, 
+stack_id INTEGER,
 callstack TEXT, 
 peak_mem INTEGER,
 start_timestamp INTEGER,
-end_timestamp INTEGER
+end_timestamp INTEGER,
+action TEXT,
+addr INTEGER,
+stream INTEGER,
+time_us INTEGER
 )",
                 (),
             )?;
@@ -60,17 +85,58 @@ end_timestamp INTEGER
             log::info!("Inserting rows into allocations table");
             for row in rows {
                 database.execute(
-                    "INSERT INTO allocations (idx, size, callstack, peak_mem, start_timestamp, end_timestamp) VALUES (?, ?, ?, ?, ?, ?)",
+                    "INSERT INTO allocations (idx, size, stack_id, callstack, peak_mem, start_timestamp, end_timestamp, action, addr, stream, time_us) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
                     (
                         &row.index,
                         &row.size,
+                        &row.stack_id,
                         &row.callstack,
                         &row.peak_mem,
                         &row.start_timestamp,
                         &row.end_timestamp,
+                        &row.action,
+                        &row.addr,
+                        &row.stream,
+                        &row.time_us,
                     ),
                 )?;
             }
+
+            log::info!("Creating frames and stack_frames tables");
+            database.execute(
+                "CREATE TABLE frames (
+frame_id INTEGER PRIMARY KEY,
+name TEXT,
+filename TEXT,
+line INTEGER
+)",
+                (),
+            )?;
+            database.execute(
+                "CREATE TABLE stack_frames (
+stack_id INTEGER,
+depth INTEGER,
+frame_id INTEGER,
+PRIMARY KEY (stack_id, depth)
+)",
+                (),
+            )?;
+
+            for frame_id in 0..self.callstacks.num_frames() as FrameId {
+                let frame = self.callstacks.frame(frame_id);
+                database.execute(
+                    "INSERT INTO frames (frame_id, name, filename, line) VALUES (?, ?, ?, ?)",
+                    (&frame_id, &frame.name, &frame.filename, &frame.line),
+                )?;
+            }
+            for stack_id in 0..self.callstacks.num_stacks() as StackId {
+                for (depth, frame_id) in self.shown_frame_ids(stack_id).enumerate() {
+                    database.execute(
+                        "INSERT INTO stack_frames (stack_id, depth, frame_id) VALUES (?, ?, ?)",
+                        (&stack_id, &depth, &frame_id),
+                    )?;
+                }
+            }
         }
 
         self.database = Some(database);
@@ -133,9 +199,9 @@ end_timestamp INTEGER
 #[cfg(test)]
 mod tests {
     use crate::{
+        layout::Layout,
         load::{load_allocations, read_snap_from_jsons},
         repl_ops::memsnap::MemSnap,
-        utils::format_bytes,
     };
 
     #[test]
@@ -146,8 +212,11 @@ mod tests {
         let alloc_path = "../snapshots/allocations.json";
         let elements_path = "../snapshots/elements.json";
 
-        let allocations =
-            load_allocations(read_snap_from_jsons(alloc_path, elements_path).unwrap()).unwrap();
+        let allocations = load_allocations(
+            read_snap_from_jsons(alloc_path, elements_path).unwrap(),
+            Layout::Stack,
+        )
+        .unwrap();
 
         let mut memsnap = MemSnap::new(allocations);
 
@@ -158,6 +227,35 @@ mod tests {
             Err(e) => eprintln!("SQL error: {}", e),
         }
 
+        // the raw trace fields are in the table, as on the allocations
+        let out = memsnap
+            .exec_sql(
+                "SELECT idx, action, addr, stream, time_us FROM allocations ORDER BY time_us, idx LIMIT 3",
+            )
+            .unwrap();
+        let mut by_time: Vec<usize> = (0..memsnap.allocations.len()).collect();
+        by_time.sort_by_key(|&i| (memsnap.allocations[i].time_us, i));
+        for (row, &i) in by_time.iter().take(3).enumerate() {
+            let alloc = &memsnap.allocations[i];
+            let expected = format!(
+                "===== Row {} =====\ncolumn [idx] : {}\ncolumn [action] : {}\ncolumn [addr] : {}\ncolumn [stream] : {}\ncolumn [time_us] : {}\n",
+                row, i, alloc.action, alloc.addr, alloc.stream, alloc.time_us
+            );
+            assert!(out.contains(&expected), "{}", out);
+        }
+        assert!(out.contains("column [action] : segment_alloc"));
+        assert_eq!(out.matches("===== Row").count(), 3);
+
+        match memsnap.exec_sql(
+            "SELECT a.stack_id, COUNT(*), SUM(a.size), f.filename, f.line, f.name
+FROM allocations a JOIN stack_frames s ON a.stack_id = s.stack_id AND s.depth = 0
+JOIN frames f ON s.frame_id = f.frame_id
+GROUP BY a.stack_id ORDER BY SUM(a.size) DESC LIMIT 3",
+        ) {
+            Ok(out) => println!("{}", out),
+            Err(e) => eprintln!("SQL error: {}", e),
+        }
+
         match memsnap.exec_sql("SELECT SUM(size) FROM allocations ORDER BY size LIMIT 4") {
             Ok(out) => println!("{}", out),
             Err(e) => eprintln!("SQL error: {}", e),
@@ -170,6 +268,7 @@ mod tests {
     }
 
     #[test]
+    #[ignore = "requires ../snapshots/large/transformer_*.json, which is not checked in"]
     fn test_big() {
         // These paths should point to your actual JSON files
         // For demonstration, you'd create dummy files named allocations.json and elements.json
@@ -179,7 +278,7 @@ mod tests {
 
         // start timer
 
-        let mut memsnap = MemSnap::from_jsons(alloc_path, elements_path).unwrap();
+        let mut memsnap = MemSnap::from_jsons(alloc_path, elements_path, Layout::Stack).unwrap();
 
         memsnap.build_sqlite().unwrap();
 
@@ -0,0 +1,135 @@
+use super::memsnap::MemSnap;
+use crate::utils::format_bytes;
+
+impl MemSnap {
+    /// All device ids in this snapshot, ascending
+    pub fn device_ids(&self) -> Vec<usize> {
+        let mut ids: Vec<usize> = self.other_devices.keys().copied().collect();
+        ids.push(self.device);
+        ids.sort();
+        ids
+    }
+
+    /// Swap the allocations (and every cache built on them) of `device` in
+    pub fn select_device(&mut self, device: usize) -> anyhow::Result<()> {
+        if device == self.device {
+            return Ok(());
+        }
+
+        if !self.other_devices.contains_key(&device) {
+            return Err(anyhow::anyhow!(
+                "Device {} not found, available devices: {:?}",
+                device,
+                self.device_ids()
+            ));
+        }
+
+        let mut other_devices = std::mem::take(&mut self.other_devices);
+        let selected = other_devices.remove(&device).unwrap();
+        let mut previous = std::mem::replace(self, selected);
+        self.baseline = previous.baseline.take(); // the baseline is compared with whichever device is selected
+        if self.frame_filter != previous.frame_filter {
+            self.set_frame_filter(previous.frame_filter.clone());
+        }
+        other_devices.insert(previous.device, previous);
+        self.other_devices = other_devices;
+
+        log::info!("Selected device {}", device);
+        Ok(())
+    }
+
+    /// One line per device: number of allocations and peak memory; the selected device is marked with `*`
+    pub fn devices_summary(&mut self) -> String {
+        let mut lines = vec![(self.device, self.device_line(true))];
+        for snap in self.other_devices.values_mut() {
+            lines.push((snap.device, snap.device_line(false)));
+        }
+        lines.sort_by_key(|(device, _)| *device);
+
+        lines
+            .into_iter()
+            .map(|(_, line)| line)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn device_line(&mut self, selected: bool) -> String {
+        let peak_time = self.peak_timestamp();
+        let timeline = self.timeline.as_ref().unwrap();
+
+        format!(
+            "{} device {}: {} allocations, peak {} {}",
+            if selected { "*" } else { " " },
+            self.device,
+            self.allocations.len(),
+            format_bytes(timeline.max_alloc),
+            self.format_timestamp(peak_time)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        layout::Layout,
+        load::{LoadedSnap, load_allocations, read_snap_from_jsons},
+        repl_ops::memsnap::MemSnap,
+        utils::format_bytes,
+    };
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_select_device() {
+        let alloc_path = "../snapshots/allocations.json";
+        let elements_path = "../snapshots/elements.json";
+
+        let load = || {
+            load_allocations(
+                read_snap_from_jsons(alloc_path, elements_path).unwrap(),
+                Layout::Stack,
+            )
+            .unwrap()
+        };
+
+        let mut second = load();
+        second.allocations.truncate(10);
+        let devices = BTreeMap::from([(0, load()), (3, second)]);
+
+        let mut memsnap = MemSnap::from_devices(devices).unwrap();
+        assert_eq!(memsnap.device_ids(), vec![0, 3]);
+        let all = memsnap.allocations.len();
+
+        memsnap.select_device(3).unwrap();
+        assert_eq!(memsnap.device, 3);
+        assert_eq!(memsnap.allocations.len(), 10);
+        assert_eq!(memsnap.device_ids(), vec![0, 3]);
+
+        assert!(memsnap.select_device(1).is_err());
+
+        // one line per device in id order, the selected one marked
+        let summary = memsnap.devices_summary();
+        let lines: Vec<&str> = summary.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with(&format!("  device 0: {} allocations, peak ", all)));
+        let peak = memsnap.peak_timestamp();
+        assert_eq!(
+            lines[1],
+            format!(
+                "* device 3: 10 allocations, peak {} {}",
+                format_bytes(memsnap.timeline.as_ref().unwrap().max_alloc),
+                memsnap.format_timestamp(peak)
+            )
+        );
+
+        memsnap.select_device(0).unwrap();
+        assert_eq!(memsnap.allocations.len(), all);
+
+        // without device 0, or with nothing recorded on it, the first recording device is selected
+        let memsnap = MemSnap::from_devices(BTreeMap::from([(1, load())])).unwrap();
+        assert_eq!(memsnap.device, 1);
+        let devices = BTreeMap::from([(0, LoadedSnap::default()), (2, load())]);
+        let memsnap = MemSnap::from_devices(devices).unwrap();
+        assert_eq!((memsnap.device, memsnap.allocations.len()), (2, all));
+        assert_eq!(memsnap.device_ids(), vec![0, 2]);
+    }
+}
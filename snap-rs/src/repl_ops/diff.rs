@@ -0,0 +1,305 @@
+use super::database::format_callstack;
+use super::memsnap::MemSnap;
+use crate::callstack::StackId;
+use crate::layout::Layout;
+use crate::utils::{format_bytes, format_delta};
+use std::collections::{BTreeSet, HashMap};
+
+/// Allocations of one allocation site (callstack) in one run
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SiteStats {
+    pub count: usize,
+    pub bytes: u64,
+    pub bytes_at_peak: u64, // alive at the peak of the run
+}
+
+/// An allocation site whose allocations differ between the baseline and the current run
+#[derive(Debug)]
+pub struct SiteDiff {
+    pub callstack: String,       // `format_callstack`, the key sites are matched by
+    pub shown_callstack: String, // `shown_callstack`, the frames of `callstack` the frame filter shows
+    pub baseline: SiteStats,
+    pub current: SiteStats,
+}
+
+impl SiteDiff {
+    pub fn delta_at_peak(&self) -> i64 {
+        self.current.bytes_at_peak as i64 - self.baseline.bytes_at_peak as i64
+    }
+
+    pub fn delta_bytes(&self) -> i64 {
+        self.current.bytes as i64 - self.baseline.bytes as i64
+    }
+
+    /// A site only `grown` or `shrunk` if its bytes at the peak and its total bytes agree,
+    /// `changed` if they move in opposite directions or only the count differs
+    pub fn kind(&self) -> &'static str {
+        let deltas = [self.delta_at_peak(), self.delta_bytes()];
+        if self.baseline.count == 0 {
+            "added"
+        } else if self.current.count == 0 {
+            "removed"
+        } else if deltas.iter().all(|&d| d >= 0) && deltas.iter().any(|&d| d > 0) {
+            "grown"
+        } else if deltas.iter().all(|&d| d <= 0) && deltas.iter().any(|&d| d < 0) {
+            "shrunk"
+        } else {
+            "changed"
+        }
+    }
+}
+
+impl MemSnap {
+    /// Loads another snapshot zip to `diff` the selected device against
+    pub fn load_baseline(&mut self, zip_path: &str) -> anyhow::Result<()> {
+        let mut baseline = MemSnap::from_zip(zip_path, Layout::Stack)?;
+        baseline.select_device(self.device).ok(); // same device if it has it, otherwise its first one
+        baseline.set_frame_filter(self.frame_filter.clone());
+        self.baseline = Some(Box::new(baseline));
+        Ok(())
+    }
+
+    /// Allocation sites of the selected device, by callstack, with the frames the filter shows
+    fn site_stats(&mut self) -> HashMap<String, (SiteStats, String)> {
+        let peak = self.peak_timestamp();
+
+        let mut by_stack: HashMap<StackId, SiteStats> = HashMap::new();
+        for alloc in &self.allocations {
+            let stats = by_stack.entry(alloc.stack).or_default();
+            stats.count += 1;
+            stats.bytes += alloc.size;
+        }
+        for index in self.lifetimes.alive_at(peak) {
+            let alloc = &self.allocations[index];
+            by_stack.get_mut(&alloc.stack).unwrap().bytes_at_peak += alloc.size;
+        }
+
+        // stack ids are per snap, callstacks are comparable across runs
+        let mut sites: HashMap<String, (SiteStats, String)> = HashMap::new();
+        for (stack, stats) in by_stack {
+            let (site, _) = sites
+                .entry(format_callstack(&self.callstacks, stack))
+                .or_insert_with(|| (SiteStats::default(), self.shown_callstack(stack)));
+            site.count += stats.count;
+            site.bytes += stats.bytes;
+            site.bytes_at_peak += stats.bytes_at_peak;
+        }
+        sites
+    }
+
+    /// Sites that differ from the baseline, largest change at the peak first
+    pub fn diff_sites(&mut self) -> anyhow::Result<Vec<SiteDiff>> {
+        let baseline = self.baseline.as_mut().ok_or_else(|| {
+            anyhow::anyhow!("No baseline loaded, load one with `load-baseline <zip>`")
+        })?;
+        let mut before = baseline.site_stats();
+        let mut after = self.site_stats();
+
+        let callstacks: BTreeSet<String> = before.keys().chain(after.keys()).cloned().collect();
+        let mut diffs: Vec<SiteDiff> = callstacks
+            .into_iter()
+            .map(|callstack| {
+                let (baseline, shown_before) = before.remove(&callstack).unwrap_or_default();
+                let (current, shown_after) = after.remove(&callstack).unwrap_or_default();
+                SiteDiff {
+                    shown_callstack: match current.count {
+                        0 => shown_before,
+                        _ => shown_after,
+                    },
+                    baseline,
+                    current,
+                    callstack,
+                }
+            })
+            .filter(|diff| diff.baseline != diff.current)
+            .collect();
+
+        diffs.sort_by_key(|diff| {
+            std::cmp::Reverse((
+                diff.delta_at_peak().unsigned_abs(),
+                diff.delta_bytes().unsigned_abs(),
+            ))
+        });
+        Ok(diffs)
+    }
+
+    /// Peaks of both runs, then one line per changed site (with its whole callstack if `verbose`),
+    /// without the frames the frame filter hides
+    pub fn diff_report(&mut self, k: usize, verbose: bool) -> anyhow::Result<String> {
+        let diffs = self.diff_sites()?;
+
+        let baseline = self.baseline.as_mut().unwrap();
+        let baseline_peak = baseline.peak_timestamp();
+        let baseline_peak = baseline.format_timestamp(baseline_peak);
+        let baseline_max = baseline.timeline.as_ref().unwrap().max_alloc;
+        let peak = self.peak_timestamp();
+        let max = self.timeline.as_ref().unwrap().max_alloc;
+
+        let mut lines = vec![
+            format!(
+                "Peak: {} {} (baseline) -> {} {} ({})",
+                format_bytes(baseline_max),
+                baseline_peak,
+                format_bytes(max),
+                self.format_timestamp(peak),
+                format_delta(max as i64 - baseline_max as i64)
+            ),
+            format!(
+                "{} of {} changed sites, by change of the bytes alive at the peak:",
+                k.min(diffs.len()),
+                diffs.len()
+            ),
+        ];
+
+        for diff in diffs.iter().take(k) {
+            let (before, after) = (&diff.baseline, &diff.current);
+            lines.push(format!(
+                "{:<8} @peak {:>12} ({} -> {})  total {:>12} ({} -> {})  count {} -> {}",
+                diff.kind(),
+                format_delta(diff.delta_at_peak()),
+                format_bytes(before.bytes_at_peak),
+                format_bytes(after.bytes_at_peak),
+                format_delta(diff.delta_bytes()),
+                format_bytes(before.bytes),
+                format_bytes(after.bytes),
+                before.count,
+                after.count
+            ));
+
+            let mut frames = diff.shown_callstack.lines();
+            if verbose {
+                lines.extend(frames.map(|frame| format!("    {}", frame)));
+            } else {
+                // the innermost frames are usually c++ unwinding, show the innermost python frame
+                let frame = frames
+                    .clone()
+                    .find(|frame| frame.contains(".py:"))
+                    .or(frames.next());
+                lines.push(format!("    {}", frame.unwrap_or("(empty callstack)")));
+            }
+        }
+
+        Ok(lines.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SiteDiff, SiteStats};
+    use crate::frame_filter::FrameFilter;
+    use crate::{repl_ops::memsnap::test_snapshot, utils::format_bytes};
+
+    #[test]
+    fn test_diff_kind() {
+        let stats = |count, bytes, bytes_at_peak| SiteStats {
+            count,
+            bytes,
+            bytes_at_peak,
+        };
+        let kind = |current| {
+            SiteDiff {
+                callstack: String::new(),
+                shown_callstack: String::new(),
+                baseline: stats(2, 100, 50),
+                current,
+            }
+            .kind()
+        };
+        assert_eq!(kind(stats(0, 0, 0)), "removed");
+        assert_eq!(kind(stats(2, 200, 50)), "grown");
+        assert_eq!(kind(stats(2, 100, 60)), "grown");
+        assert_eq!(kind(stats(2, 50, 50)), "shrunk");
+        assert_eq!(kind(stats(2, 100, 0)), "shrunk");
+        // mixed signs, or only the count differs
+        assert_eq!(kind(stats(2, 1, 60)), "changed");
+        assert_eq!(kind(stats(2, 200, 40)), "changed");
+        assert_eq!(kind(stats(3, 100, 50)), "changed");
+        let added = SiteDiff {
+            callstack: String::new(),
+            shown_callstack: String::new(),
+            baseline: SiteStats::default(),
+            current: stats(1, 10, 0),
+        };
+        assert_eq!(added.kind(), "added");
+    }
+
+    #[test]
+    fn test_diff() {
+        let load = test_snapshot;
+
+        let mut memsnap = load();
+        assert!(memsnap.diff_sites().is_err());
+
+        memsnap.baseline = Some(Box::new(load()));
+        assert!(memsnap.diff_sites().unwrap().is_empty());
+
+        // one site allocates twice as much, another one disappears
+        let grown = memsnap.allocations[0].stack;
+        let removed = memsnap
+            .allocations
+            .iter()
+            .map(|a| a.stack)
+            .find(|&s| s != grown)
+            .unwrap();
+        let mut current = load();
+        for alloc in current.allocations.iter_mut() {
+            if alloc.stack == grown {
+                alloc.size *= 2;
+            }
+        }
+        current.allocations.retain(|a| a.stack != removed);
+        current.baseline = memsnap.baseline.take();
+
+        let diffs = current.diff_sites().unwrap();
+        let grown = diffs.iter().find(|d| d.delta_bytes() > 0).unwrap();
+        assert_eq!(grown.current.bytes, 2 * grown.baseline.bytes);
+        assert_eq!(grown.current.count, grown.baseline.count);
+        let removed: Vec<_> = diffs.iter().filter(|d| d.kind() == "removed").collect();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].current, SiteStats::default());
+
+        // the other sites only differ by what is alive at the (moved) peak
+        assert!(
+            diffs
+                .iter()
+                .filter(|d| d.delta_bytes() == 0)
+                .all(|d| d.current.count == d.baseline.count)
+        );
+
+        // both peaks, the count of changed sites, then each site with its innermost Python frame
+        let max = current.timeline.as_ref().unwrap().max_alloc;
+        let report = current.diff_report(10, false).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert!(lines[0].starts_with("Peak: ") && lines[0].contains(&format_bytes(max)));
+        assert_eq!(
+            lines[1],
+            format!(
+                "{} of {} changed sites, by change of the bytes alive at the peak:",
+                diffs.len().min(10),
+                diffs.len()
+            )
+        );
+        assert_eq!(lines.len(), 2 + 2 * diffs.len().min(10));
+        assert!(lines.iter().any(|line| line.starts_with("removed ")));
+
+        // the verbose report leaves out the frames the filter hides, of removed sites too
+        let mut filter = FrameFilter::default();
+        filter.apply("preset user-code-only").unwrap();
+        current.set_frame_filter(filter);
+        let diffs = current.diff_sites().unwrap();
+        let report = current.diff_report(diffs.len(), true).unwrap();
+        let frames: Vec<&str> = report
+            .lines()
+            .filter_map(|line| line.strip_prefix("    "))
+            .collect();
+        let shown: Vec<&str> = diffs
+            .iter()
+            .flat_map(|d| d.shown_callstack.lines())
+            .collect();
+        assert_eq!(frames, shown);
+        assert!(diffs.iter().all(|d| {
+            d.shown_callstack.lines().count() < d.callstack.lines().count()
+                && d.shown_callstack.lines().all(|f| d.callstack.contains(f))
+        }));
+    }
+}
@@ -0,0 +1,482 @@
+use super::memsnap::{AllocationIndex, MemSnap};
+use crate::callstack::StackId;
+use crate::clock::{Clock, parse_timestamp};
+use crate::utils::{parse_bytes, parse_duration};
+use regex::Regex;
+use std::collections::HashMap;
+
+/// A `where` clause: predicates on allocations combined with `and`, `or`, `not` and parentheses, e.g.
+/// `size > 16MiB and frame ~ "attention" and alive @1200 and lifetime > 500`
+#[derive(Debug)]
+pub struct Filter {
+    expr: Expr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Index,
+    Size,
+    Peak,
+    Lifetime, // in timesteps
+    Start,
+    End,
+    Addr,
+    Stream,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CmpOp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+impl CmpOp {
+    fn apply<T: PartialOrd>(self, a: T, b: T) -> bool {
+        match self {
+            CmpOp::Lt => a < b,
+            CmpOp::Le => a <= b,
+            CmpOp::Gt => a > b,
+            CmpOp::Ge => a >= b,
+            CmpOp::Eq => a == b,
+            CmpOp::Ne => a != b,
+        }
+    }
+}
+
+/// What a regex is matched against, for each frame of the callstack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FrameText {
+    Frame, // "filename:line:name"
+    File,
+    Func,
+}
+
+#[derive(Debug)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Field, CmpOp, u64),
+    LifetimeUs(CmpOp, u64), // `lifetime` compared to a duration, in wall-clock time
+    Action(CmpOp, String),  // only Eq/Ne
+    Alive(u64),
+    /// Some frame of the callstack matches; `id` tells apart the matches cached per stack
+    Matches {
+        id: usize,
+        text: FrameText,
+        regex: Regex,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+}
+
+fn tokenize(s: &str) -> anyhow::Result<Vec<Token>> {
+    const OPS: [&str; 9] = [">=", "<=", "==", "!=", "!~", ">", "<", "=", "~"];
+    let is_special = |c: char| c.is_whitespace() || "()<>=!~\"'".contains(c);
+
+    let mut tokens = Vec::new();
+    let mut rest = s;
+    while let Some(c) = rest.chars().next() {
+        if c.is_whitespace() {
+            rest = &rest[c.len_utf8()..];
+        } else if c == '(' || c == ')' {
+            tokens.push(if c == '(' {
+                Token::LParen
+            } else {
+                Token::RParen
+            });
+            rest = &rest[1..];
+        } else if c == '"' || c == '\'' {
+            let end = rest[1..]
+                .find(c)
+                .ok_or_else(|| anyhow::anyhow!("Unterminated string: {}", rest))?;
+            tokens.push(Token::Str(rest[1..end + 1].to_string()));
+            rest = &rest[end + 2..];
+        } else if let Some(op) = OPS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else {
+            let end = rest.find(is_special).unwrap_or(rest.len());
+            if end == 0 {
+                return Err(anyhow::anyhow!("Unexpected character: {}", c));
+            }
+            tokens.push(Token::Word(rest[..end].to_string()));
+            rest = &rest[end..];
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    clock: Option<&'a Clock>,
+    num_matches: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> anyhow::Result<Token> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Unexpected end of filter"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> anyhow::Result<Expr> {
+        let mut expr = self.and()?;
+        while self.eat_keyword("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> anyhow::Result<Expr> {
+        let mut expr = self.unary()?;
+        while self.eat_keyword("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> anyhow::Result<Expr> {
+        if self.eat_keyword("not") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.peek() == Some(&Token::LParen) {
+            self.pos += 1;
+            let expr = self.or()?;
+            if self.next()? != Token::RParen {
+                return Err(anyhow::anyhow!("Expected `)`"));
+            }
+            return Ok(expr);
+        }
+        self.predicate()
+    }
+
+    fn predicate(&mut self) -> anyhow::Result<Expr> {
+        let name = match self.next()? {
+            Token::Word(w) => w.to_ascii_lowercase(),
+            token => return Err(anyhow::anyhow!("Expected a field, got {:?}", token)),
+        };
+
+        if name == "alive" {
+            let Token::Word(ts) = self.next()? else {
+                return Err(anyhow::anyhow!("Expected @timestamp after `alive`"));
+            };
+            return Ok(Expr::Alive(parse_timestamp(&ts, self.clock)?));
+        }
+
+        let op = match self.next()? {
+            Token::Op(op) => op,
+            token => {
+                return Err(anyhow::anyhow!(
+                    "Expected an operator after `{}`, got {:?}",
+                    name,
+                    token
+                ));
+            }
+        };
+        let value = match self.next()? {
+            Token::Word(w) | Token::Str(w) => w,
+            token => return Err(anyhow::anyhow!("Expected a value, got {:?}", token)),
+        };
+
+        let text = match name.as_str() {
+            "frame" => Some(FrameText::Frame),
+            "file" => Some(FrameText::File),
+            "func" | "function" => Some(FrameText::Func),
+            _ => None,
+        };
+        if let Some(text) = text {
+            let regex = Regex::new(&value)
+                .map_err(|e| anyhow::anyhow!("Invalid regex '{}': {}", value, e))?;
+            let id = self.num_matches;
+            self.num_matches += 1;
+            let matches = Expr::Matches { id, text, regex };
+            return match op {
+                "~" => Ok(matches),
+                "!~" => Ok(Expr::Not(Box::new(matches))),
+                _ => Err(anyhow::anyhow!("`{}` takes `~` or `!~`, got {}", name, op)),
+            };
+        }
+
+        let op = match op {
+            "<" => CmpOp::Lt,
+            "<=" => CmpOp::Le,
+            ">" => CmpOp::Gt,
+            ">=" => CmpOp::Ge,
+            "=" | "==" => CmpOp::Eq,
+            "!=" => CmpOp::Ne,
+            _ => return Err(anyhow::anyhow!("`{}` cannot be compared with {}", name, op)),
+        };
+
+        if name == "action" {
+            if !matches!(op, CmpOp::Eq | CmpOp::Ne) {
+                return Err(anyhow::anyhow!("`action` takes `=` or `!=`"));
+            }
+            return Ok(Expr::Action(op, value));
+        }
+
+        let parse_u64 = |v: &str| {
+            v.parse::<u64>()
+                .map_err(|e| anyhow::anyhow!("Invalid value for `{}`: {} ({})", name, v, e))
+        };
+        let field = match name.as_str() {
+            "idx" | "index" => Field::Index,
+            "size" => Field::Size,
+            "peak" | "peak_mem" => Field::Peak,
+            "lifetime" => Field::Lifetime,
+            "start" => Field::Start,
+            "end" | "stop" => Field::End,
+            "addr" => Field::Addr,
+            "stream" => Field::Stream,
+            _ => return Err(anyhow::anyhow!("Unknown field: {}", name)),
+        };
+        let value = match field {
+            Field::Size | Field::Peak => parse_bytes(&value)?,
+            Field::Start | Field::End if value.starts_with('@') => {
+                parse_timestamp(&value, self.clock)?
+            }
+            Field::Addr => match value.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16)
+                    .map_err(|e| anyhow::anyhow!("Invalid address {}: {}", value, e))?,
+                None => parse_u64(&value)?,
+            },
+            Field::Lifetime if value.parse::<u64>().is_err() => {
+                if self.clock.is_none() {
+                    return Err(anyhow::anyhow!(
+                        "This trace has no wall-clock times (time_us), give `lifetime` in timesteps"
+                    ));
+                }
+                return Ok(Expr::LifetimeUs(op, parse_duration(&value)?));
+            }
+            _ => parse_u64(&value)?,
+        };
+        Ok(Expr::Compare(field, op, value))
+    }
+}
+
+impl Filter {
+    /// Timestamps in the filter may be elapsed times if there is a `clock`
+    pub fn parse(s: &str, clock: Option<&Clock>) -> anyhow::Result<Filter> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+            clock,
+            num_matches: 0,
+        };
+        if parser.tokens.is_empty() {
+            return Err(anyhow::anyhow!("Empty filter"));
+        }
+        let expr = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return Err(anyhow::anyhow!(
+                "Unexpected {:?}, expected `and` or `or`",
+                token
+            ));
+        }
+        Ok(Filter { expr })
+    }
+}
+
+/// Splits `args` at a `where` keyword: the arguments of the command, and the filter if any
+pub fn split_where(args: &str) -> (&str, Option<&str>) {
+    if let Some(filter) = args.strip_prefix("where ") {
+        return ("", Some(filter.trim()));
+    }
+    match args.split_once(" where ") {
+        Some((args, filter)) => (args.trim(), Some(filter.trim())),
+        None => (args, None),
+    }
+}
+
+/// Evaluation state: whether a stack has matched a regex, and which allocations are alive at a
+/// timestamp, are only computed once
+struct Eval<'a> {
+    snap: &'a MemSnap,
+    stack_matches: HashMap<(usize, StackId), bool>,
+    alive_at: HashMap<u64, Vec<bool>>, // timestamp -> by allocation index, from the lifetime index
+}
+
+impl Eval<'_> {
+    fn eval(&mut self, expr: &Expr, index: AllocationIndex) -> bool {
+        let alloc = &self.snap.allocations[index];
+        match expr {
+            Expr::And(a, b) => self.eval(a, index) && self.eval(b, index),
+            Expr::Or(a, b) => self.eval(a, index) || self.eval(b, index),
+            Expr::Not(a) => !self.eval(a, index),
+            Expr::Compare(field, op, value) => {
+                let (start, stop) = alloc.start_end_time();
+                let actual = match field {
+                    Field::Index => index as u64,
+                    Field::Size => alloc.size,
+                    Field::Peak => alloc.peak_mem,
+                    Field::Lifetime => stop - start,
+                    Field::Start => start,
+                    Field::End => stop,
+                    Field::Addr => alloc.addr,
+                    Field::Stream => alloc.stream,
+                };
+                op.apply(actual, *value)
+            }
+            Expr::LifetimeUs(op, value) => {
+                let clock = self.snap.clock.as_ref().unwrap();
+                let (start, stop) = alloc.start_end_time();
+                op.apply(clock.elapsed_us(stop) - clock.elapsed_us(start), *value)
+            }
+            Expr::Action(op, value) => op.apply(&alloc.action, value),
+            Expr::Alive(timestamp) => {
+                let snap = self.snap;
+                self.alive_at.entry(*timestamp).or_insert_with(|| {
+                    let mut mask = vec![false; snap.allocations.len()];
+                    for index in snap.lifetimes.alive_at(*timestamp) {
+                        mask[index] = true;
+                    }
+                    mask
+                })[index]
+            }
+            Expr::Matches { id, text, regex } => {
+                let callstacks = &self.snap.callstacks;
+                *self
+                    .stack_matches
+                    .entry((*id, alloc.stack))
+                    .or_insert_with(|| {
+                        callstacks.frames(alloc.stack).any(|frame| match text {
+                            FrameText::Frame => regex.is_match(&format!(
+                                "{}:{}:{}",
+                                frame.filename, frame.line, frame.name
+                            )),
+                            FrameText::File => regex.is_match(&frame.filename),
+                            FrameText::Func => regex.is_match(&frame.name),
+                        })
+                    })
+            }
+        }
+    }
+}
+
+impl MemSnap {
+    /// Whether each allocation matches `filter`
+    pub fn filter_mask(&self, filter: &Filter) -> Vec<bool> {
+        let mut eval = Eval {
+            snap: self,
+            stack_matches: HashMap::new(),
+            alive_at: HashMap::new(),
+        };
+        (0..self.allocations.len())
+            .map(|index| eval.eval(&filter.expr, index))
+            .collect()
+    }
+
+    /// Indices of the allocations that match `filter`, ascending
+    pub fn filter_indices(&self, filter: &Filter) -> Vec<AllocationIndex> {
+        self.filter_mask(filter)
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, matches)| matches.then_some(index))
+            .collect()
+    }
+
+    /// The first `k` of `ranked` that match `filter`
+    pub fn take_matching(
+        &self,
+        ranked: &[AllocationIndex],
+        filter: &Filter,
+        k: usize,
+    ) -> Vec<AllocationIndex> {
+        let mask = self.filter_mask(filter);
+        ranked
+            .iter()
+            .copied()
+            .filter(|&i| mask[i])
+            .take(k)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Filter, split_where};
+    use crate::repl_ops::memsnap::test_snapshot;
+
+    #[test]
+    fn test_filter() {
+        let memsnap = test_snapshot();
+        let clock = memsnap.clock.as_ref();
+        let select = |s: &str| memsnap.filter_indices(&Filter::parse(s, clock).unwrap());
+        let expected = |f: &dyn Fn(usize) -> bool| {
+            (0..memsnap.allocations.len())
+                .filter(|&i| f(i))
+                .collect::<Vec<_>>()
+        };
+        let allocs = &memsnap.allocations;
+
+        assert_eq!(
+            select("size > 1MiB and alive @57"),
+            expected(&|i| allocs[i].size > 1 << 20 && allocs[i].is_alive_at(57))
+        );
+        assert_eq!(
+            select("not (size<=1MiB or lifetime < 20)"),
+            expected(&|i| {
+                let (start, stop) = allocs[i].start_end_time();
+                allocs[i].size > 1 << 20 && stop - start >= 20
+            })
+        );
+
+        // callstack regexes, on the whole frame or one part of it
+        let linear = select(r#"frame ~ "linear\.py:\d+:forward""#);
+        assert!(!linear.is_empty());
+        assert_eq!(linear, select("file ~ 'linear.py$' and func ~ ^forward$"));
+        assert_eq!(
+            select("frame !~ linear.py").len() + linear.len(),
+            allocs.len()
+        );
+        assert_eq!(
+            select("action != alloc"),
+            expected(&|i| allocs[i].action != "alloc")
+        );
+        assert_eq!(
+            select("idx >= 10 and idx < 20"),
+            (10..20).collect::<Vec<_>>()
+        );
+
+        assert!(Filter::parse("size >", clock).is_err());
+        assert!(Filter::parse("size > 1MiB and", clock).is_err());
+        assert!(Filter::parse("colour = red", clock).is_err());
+        assert!(Filter::parse("(size > 1", clock).is_err());
+
+        assert_eq!(
+            split_where("10 v where size > 1MiB"),
+            ("10 v", Some("size > 1MiB"))
+        );
+        assert_eq!(split_where("where alive @3"), ("", Some("alive @3")));
+        assert_eq!(split_where("10"), ("10", None));
+    }
+}
@@ -0,0 +1,155 @@
+use super::memsnap::MemSnap;
+use crate::allocation::Allocation;
+use crate::callstack::StackId;
+use crate::utils::format_bytes;
+use inferno::flamegraph;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// What the width of a frame in the flamegraph stands for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlameWeight {
+    /// Bytes of the allocations alive at this timestamp
+    AliveAt(u64),
+    /// Bytes times the number of timesteps each allocation is alive, over the whole trace
+    ByteTimesteps,
+}
+
+impl MemSnap {
+    /// Brendan Gregg's folded stacks, `outermost;...;innermost weight`, heaviest first
+    pub fn folded_stacks(&self, weight: FlameWeight) -> Vec<String> {
+        // allocations with the same callstack share a stack id, so sum by id first
+        let mut weights: HashMap<StackId, u64> = HashMap::new();
+        let allocations: Vec<&Allocation> = match weight {
+            FlameWeight::AliveAt(timestamp) => self
+                .lifetimes
+                .alive_at(timestamp)
+                .into_iter()
+                .map(|i| &self.allocations[i])
+                .collect(),
+            FlameWeight::ByteTimesteps => self.allocations.iter().collect(),
+        };
+        for alloc in allocations {
+            let w = match weight {
+                FlameWeight::AliveAt(_) => alloc.size,
+                FlameWeight::ByteTimesteps => {
+                    if alloc.timesteps.is_empty() {
+                        continue;
+                    }
+                    let (start, stop) = alloc.start_end_time();
+                    alloc.size * (stop - start + 1)
+                }
+            };
+            *weights.entry(alloc.stack).or_default() += w;
+        }
+
+        let mut weights: Vec<(StackId, u64)> =
+            weights.into_iter().filter(|&(_, w)| w > 0).collect();
+        weights.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        weights
+            .into_iter()
+            .map(|(stack, w)| format!("{} {}", self.folded_stack(stack), w))
+            .collect()
+    }
+
+    fn folded_stack(&self, stack: StackId) -> String {
+        let frames: Vec<String> = self
+            .shown_frames(stack)
+            .rev() // folded stacks start at the root
+            .map(|frame| {
+                format!("{} ({}:{})", frame.name, frame.filename, frame.line).replace(';', ",")
+            })
+            .collect();
+
+        if frames.is_empty() {
+            "[no callstack]".to_string()
+        } else {
+            frames.join(";")
+        }
+    }
+
+    /// Writes the folded stacks next to `svg_path` (with the `.folded` extension), and renders them
+    /// to `svg_path`. Returns the path of the folded stacks.
+    pub fn write_flamegraph(&self, weight: FlameWeight, svg_path: &str) -> anyhow::Result<String> {
+        let lines = self.folded_stacks(weight);
+        if lines.is_empty() {
+            return Err(anyhow::anyhow!("No memory to draw for {:?}", weight));
+        }
+
+        let folded_path = Path::new(svg_path).with_extension("folded");
+        let mut folded = BufWriter::new(File::create(&folded_path)?);
+        for line in &lines {
+            writeln!(folded, "{}", line)?;
+        }
+        folded.flush()?;
+
+        let mut options = flamegraph::Options::default();
+        match weight {
+            FlameWeight::AliveAt(timestamp) => {
+                let total: u64 = self
+                    .lifetimes
+                    .alive_at(timestamp)
+                    .into_iter()
+                    .map(|i| self.allocations[i].size)
+                    .sum();
+                options.title = format!("Memory alive {}", self.format_timestamp(timestamp));
+                options.subtitle = Some(format!("{} in total", format_bytes(total)));
+                options.count_name = "bytes".to_string();
+            }
+            FlameWeight::ByteTimesteps => {
+                options.title = "Memory over the whole trace".to_string();
+                options.count_name = "byte-timesteps".to_string();
+            }
+        }
+
+        let svg = BufWriter::new(
+            File::create(svg_path)
+                .map_err(|e| anyhow::anyhow!("Failed to create '{}': {}", svg_path, e))?,
+        );
+        flamegraph::from_lines(&mut options, lines.iter().map(String::as_str), svg)?;
+
+        Ok(folded_path.to_string_lossy().into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FlameWeight;
+    use crate::{layout::Layout, repl_ops::memsnap::MemSnap};
+
+    #[test]
+    fn test_flame() {
+        let alloc_path = "../snapshots/allocations.json";
+        let elements_path = "../snapshots/elements.json";
+        let svg_path = std::env::temp_dir().join("tomi_test_flame.svg");
+        let svg_path = svg_path.to_str().unwrap();
+
+        let memsnap = MemSnap::from_jsons(alloc_path, elements_path, Layout::Stack).unwrap();
+
+        // the folded weights add up to the bytes alive at that timestamp
+        let alive: u64 = memsnap
+            .allocations
+            .iter()
+            .filter(|a| a.is_alive_at(57))
+            .map(|a| a.size)
+            .sum();
+        let folded = memsnap.folded_stacks(FlameWeight::AliveAt(57));
+        let total: u64 = folded
+            .iter()
+            .map(|line| line.rsplit_once(' ').unwrap().1.parse::<u64>().unwrap())
+            .sum();
+        assert_eq!(total, alive);
+
+        let folded_path = memsnap
+            .write_flamegraph(FlameWeight::ByteTimesteps, svg_path)
+            .unwrap();
+        assert!(std::fs::read_to_string(svg_path).unwrap().contains("<svg"));
+        assert!(!std::fs::read_to_string(&folded_path).unwrap().is_empty());
+
+        std::fs::remove_file(svg_path).unwrap();
+        std::fs::remove_file(folded_path).unwrap();
+    }
+}
@@ -0,0 +1,60 @@
+use super::memsnap::{AllocationIndex, MemSnap};
+use crate::allocation::{AllocationDisplay, Frame};
+use crate::callstack::{FrameId, StackId};
+use crate::frame_filter::FrameFilter;
+
+impl MemSnap {
+    /// Frames hidden by `filter` are left out of printed callstacks, SQL, groups and exports, of
+    /// the baseline too. The SQL database is dropped, since its callstacks are built with the
+    /// previous filter.
+    pub fn set_frame_filter(&mut self, filter: FrameFilter) {
+        if let Some(baseline) = self.baseline.as_mut() {
+            baseline.set_frame_filter(filter.clone());
+        }
+        self.shown_frames = filter.mask(&self.callstacks);
+        self.frame_filter = filter;
+        self.database = None;
+    }
+
+    /// Frame ids of `stack` that the frame filter shows, innermost first
+    pub fn shown_frame_ids(&self, stack: StackId) -> impl DoubleEndedIterator<Item = FrameId> + '_ {
+        self.callstacks
+            .frame_ids(stack)
+            .iter()
+            .copied()
+            .filter(|&id| self.shown_frames[id as usize])
+    }
+
+    /// Frames of `stack` that the frame filter shows, innermost first
+    pub fn shown_frames(&self, stack: StackId) -> impl DoubleEndedIterator<Item = &Frame> + '_ {
+        self.shown_frame_ids(stack)
+            .map(|id| self.callstacks.frame(id))
+    }
+
+    /// One "filename:line:name" line per shown frame of `stack`, innermost first
+    pub fn shown_callstack(&self, stack: StackId) -> String {
+        self.shown_frames(stack)
+            .map(|frame| format!("{}:{}:{}", frame.filename, frame.line, frame.name))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// The allocation at `index` with elapsed times and the shown frames
+    pub fn display(&self, index: AllocationIndex) -> AllocationDisplay<'_> {
+        self.allocations[index]
+            .display(&self.callstacks)
+            .with_clock(self.clock.as_ref())
+            .with_shown_frames(&self.shown_frames)
+    }
+
+    /// The rules, and how many frames they hide
+    pub fn frames_summary(&self) -> String {
+        let hidden = self.shown_frames.iter().filter(|&&shown| !shown).count();
+        format!(
+            "{}\n{} of {} frames hidden",
+            self.frame_filter,
+            hidden,
+            self.shown_frames.len()
+        )
+    }
+}
@@ -0,0 +1,227 @@
+use super::memsnap::MemSnap;
+use crate::allocation::Frame;
+use crate::callstack::StackId;
+use crate::utils::format_bytes;
+use std::collections::HashMap;
+
+/// What `group` aggregates allocations by. Function, file and line are those of the innermost
+/// Python frame (the innermost frames are usually c++ unwinding), or of the innermost frame if
+/// the callstack has no Python frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupKey {
+    Function,
+    File,
+    Line,
+    Stack,
+    UserFrame, // innermost Python frame outside of packages and the standard library
+}
+
+impl GroupKey {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "function" | "func" => Some(GroupKey::Function),
+            "file" => Some(GroupKey::File),
+            "file:line" | "line" => Some(GroupKey::Line),
+            "stack" => Some(GroupKey::Stack),
+            "user" | "user-frame" => Some(GroupKey::UserFrame),
+            _ => None,
+        }
+    }
+
+    /// Group of the allocations with callstack `stack`
+    pub fn of(&self, frames: &[&Frame], stack: StackId) -> String {
+        let innermost = || {
+            frames
+                .iter()
+                .find(|frame| frame.is_python())
+                .or(frames.first())
+        };
+        let frame = match self {
+            GroupKey::Stack => return format!("stack {}", stack),
+            GroupKey::UserFrame => frames.iter().find(|frame| frame.is_user_code()),
+            _ => innermost(),
+        };
+        let Some(frame) = frame else {
+            return "(no frame)".to_string();
+        };
+        match self {
+            GroupKey::Function => frame.name.clone(),
+            GroupKey::File => frame.filename.clone(),
+            GroupKey::Line | GroupKey::UserFrame => {
+                format!("{}:{} ({})", frame.filename, frame.line, frame.name)
+            }
+            GroupKey::Stack => unreachable!(),
+        }
+    }
+}
+
+/// Allocations of one group
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GroupStats {
+    pub key: String,
+    pub stacks: Vec<StackId>, // callstacks in the group, ascending
+    pub count: usize,
+    pub alive: usize,     // allocations alive at the timestamp
+    pub live_bytes: u64,  // bytes alive at the timestamp
+    pub peak_bytes: u64,  // bytes alive at the peak of the trace
+    pub total_bytes: u64, // bytes allocated over the whole trace
+}
+
+impl MemSnap {
+    /// Allocations grouped by `key`, with the memory alive at `timestamp` (if any), largest first:
+    /// by live bytes at the timestamp, then bytes at the peak, then total bytes
+    pub fn group_by(&mut self, key: GroupKey, timestamp: Option<u64>) -> Vec<GroupStats> {
+        let peak = self.peak_timestamp();
+
+        // allocations with the same callstack share a stack id, so key by id first
+        let mut by_stack: HashMap<StackId, GroupStats> = HashMap::new();
+        for alloc in &self.allocations {
+            let stats = by_stack.entry(alloc.stack).or_default();
+            stats.count += 1;
+            stats.total_bytes += alloc.size;
+        }
+        for index in self.lifetimes.alive_at(peak) {
+            let alloc = &self.allocations[index];
+            by_stack.get_mut(&alloc.stack).unwrap().peak_bytes += alloc.size;
+        }
+        if let Some(timestamp) = timestamp {
+            for index in self.lifetimes.alive_at(timestamp) {
+                let alloc = &self.allocations[index];
+                let stats = by_stack.get_mut(&alloc.stack).unwrap();
+                stats.alive += 1;
+                stats.live_bytes += alloc.size;
+            }
+        }
+
+        let mut groups: HashMap<String, GroupStats> = HashMap::new();
+        for (stack, stats) in by_stack {
+            let frames: Vec<&Frame> = self.shown_frames(stack).collect();
+            let key = key.of(&frames, stack);
+            let group = groups.entry(key.clone()).or_insert_with(|| GroupStats {
+                key,
+                ..Default::default()
+            });
+            group.stacks.push(stack);
+            group.count += stats.count;
+            group.alive += stats.alive;
+            group.live_bytes += stats.live_bytes;
+            group.peak_bytes += stats.peak_bytes;
+            group.total_bytes += stats.total_bytes;
+        }
+
+        let mut groups: Vec<GroupStats> = groups.into_values().collect();
+        for group in groups.iter_mut() {
+            group.stacks.sort_unstable();
+        }
+        groups.sort_by(|a, b| {
+            (b.live_bytes, b.peak_bytes, b.total_bytes, &a.key).cmp(&(
+                a.live_bytes,
+                a.peak_bytes,
+                a.total_bytes,
+                &b.key,
+            ))
+        });
+        groups
+    }
+
+    /// The `n` largest groups, one line each (followed by the callstack, for `GroupKey::Stack`)
+    pub fn group_report(&mut self, key: GroupKey, timestamp: Option<u64>, n: usize) -> String {
+        let groups = self.group_by(key, timestamp);
+        let when = match timestamp {
+            Some(timestamp) => format!("alive {}", self.format_timestamp(timestamp)),
+            None => "over the whole trace".to_string(),
+        };
+
+        let mut lines = vec![
+            format!(
+                "{} of {} groups, memory {}:",
+                n.min(groups.len()),
+                groups.len(),
+                when
+            ),
+            format!(
+                "{:>12} {:>7} {:>12} {:>12} {:>7}  group",
+                "live", "alive", "at peak", "total", "count"
+            ),
+        ];
+        for group in groups.iter().take(n) {
+            let (live, alive) = match timestamp {
+                Some(_) => (format_bytes(group.live_bytes), group.alive.to_string()),
+                None => ("-".to_string(), "-".to_string()),
+            };
+            lines.push(format!(
+                "{:>12} {:>7} {:>12} {:>12} {:>7}  {}",
+                live,
+                alive,
+                format_bytes(group.peak_bytes),
+                format_bytes(group.total_bytes),
+                group.count,
+                group.key
+            ));
+            if key == GroupKey::Stack {
+                let frames = self.shown_frames(group.stacks[0]);
+                lines.extend(frames.map(|frame| format!("    {}", frame)));
+            }
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GroupKey;
+    use crate::repl_ops::memsnap::test_snapshot;
+
+    #[test]
+    fn test_group_by() {
+        let mut memsnap = test_snapshot();
+
+        let total: u64 = memsnap.allocations.iter().map(|a| a.size).sum();
+        let live: u64 = memsnap
+            .allocations
+            .iter()
+            .filter(|a| a.is_alive_at(57))
+            .map(|a| a.size)
+            .sum();
+
+        for key in [
+            GroupKey::Function,
+            GroupKey::File,
+            GroupKey::Line,
+            GroupKey::Stack,
+            GroupKey::UserFrame,
+        ] {
+            let groups = memsnap.group_by(key, Some(57));
+            assert_eq!(groups.iter().map(|g| g.total_bytes).sum::<u64>(), total);
+            assert_eq!(groups.iter().map(|g| g.live_bytes).sum::<u64>(), live);
+            assert_eq!(
+                groups.iter().map(|g| g.count).sum::<usize>(),
+                memsnap.allocations.len()
+            );
+            assert!(
+                groups
+                    .windows(2)
+                    .all(|w| w[0].live_bytes >= w[1].live_bytes)
+            );
+        }
+        assert_eq!(
+            memsnap.group_by(GroupKey::Stack, None).len(),
+            memsnap.callstacks.num_stacks()
+        );
+
+        // header, column names, then one line per group
+        let groups = memsnap.group_by(GroupKey::UserFrame, Some(57)).len();
+        let report = memsnap.group_report(GroupKey::UserFrame, Some(57), 5);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            lines[0],
+            format!(
+                "{} of {} groups, memory alive {}:",
+                groups.min(5),
+                groups,
+                memsnap.format_timestamp(57)
+            )
+        );
+        assert_eq!(lines.len(), groups.min(5) + 2);
+    }
+}
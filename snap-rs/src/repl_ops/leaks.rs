@@ -0,0 +1,258 @@
+use super::group::GroupKey;
+use super::memsnap::{AllocationIndex, MemSnap};
+use super::peaks::{Iteration, Prominence};
+use crate::callstack::StackId;
+use crate::utils::format_bytes;
+use std::collections::BTreeMap;
+
+/// Allocations of one callstack that are still alive at the end of the trace
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeakStack {
+    pub stack: StackId,
+    pub allocations: Vec<AllocationIndex>, // by first timestep
+    pub bytes: u64,
+    pub per_iteration: Vec<usize>, // surviving allocations born in each iteration
+    pub every_iteration: bool,     // a new survivor in every iteration after the first
+    pub growth: f64,               // bytes per iteration after the first
+}
+
+/// The never freed allocations of a trace, by callstack
+#[derive(Debug, Clone, PartialEq)]
+pub struct Leaks {
+    pub iterations: usize, // that the trace is split into, see `MemSnap::iterations`
+    pub stacks: Vec<LeakStack>,
+}
+
+/// How many of `births` fall in each iteration; births before the first one count for it
+pub fn births_per_iteration(births: &[u64], iterations: &[Iteration]) -> Vec<usize> {
+    let mut counts = vec![0; iterations.len()];
+    for &birth in births {
+        let i = iterations.partition_point(|it| it.start <= birth);
+        if let Some(count) = counts.get_mut(i.saturating_sub(1)) {
+            *count += 1;
+        }
+    }
+    counts
+}
+
+impl MemSnap {
+    /// Allocations never freed in the trace (alive at its last timestep), by callstack, most bytes
+    /// first, over the iterations split with `prominence`. The first iteration usually allocates
+    /// parameters and optimizer state, so only the ones after it count for `every_iteration` and
+    /// `growth`.
+    pub fn leaks(&mut self, prominence: Prominence) -> Leaks {
+        let iterations = self.iterations(prominence);
+        let trace_end = self.timestamps.last().copied().unwrap_or(0);
+
+        let mut by_stack: BTreeMap<StackId, Vec<AllocationIndex>> = BTreeMap::new();
+        for index in self.lifetimes.stopped_in(trace_end, trace_end) {
+            by_stack
+                .entry(self.allocations[index].stack)
+                .or_default()
+                .push(index);
+        }
+
+        let steady = iterations.len().saturating_sub(1);
+        let mut leaks: Vec<LeakStack> = by_stack
+            .into_iter()
+            .map(|(stack, mut allocations)| {
+                allocations.sort_by_key(|&i| (self.allocations[i].start_end_time().0, i));
+                let births: Vec<u64> = allocations
+                    .iter()
+                    .map(|&i| self.allocations[i].start_end_time().0)
+                    .collect();
+                let per_iteration = births_per_iteration(&births, &iterations);
+                let steady_bytes: u64 = allocations
+                    .iter()
+                    .zip(&births)
+                    .filter(|&(_, &birth)| steady > 0 && birth >= iterations[1].start)
+                    .map(|(&i, _)| self.allocations[i].size)
+                    .sum();
+                LeakStack {
+                    stack,
+                    bytes: allocations.iter().map(|&i| self.allocations[i].size).sum(),
+                    allocations,
+                    every_iteration: steady > 0 && per_iteration[1..].iter().all(|&n| n > 0),
+                    growth: steady_bytes as f64 / steady.max(1) as f64,
+                    per_iteration,
+                }
+            })
+            .collect();
+        leaks.sort_by_key(|leak| (std::cmp::Reverse(leak.bytes), leak.stack));
+        Leaks {
+            iterations: iterations.len(),
+            stacks: leaks,
+        }
+    }
+
+    /// The stacks with allocations never freed, those leaking every iteration first, with their
+    /// growth per iteration
+    pub fn leaks_report(&mut self, prominence: Prominence) -> String {
+        let Leaks {
+            iterations,
+            stacks: mut leaks,
+        } = self.leaks(prominence);
+        leaks.sort_by_key(|leak| !leak.every_iteration);
+        let survivors: usize = leaks.iter().map(|leak| leak.allocations.len()).sum();
+        let bytes: u64 = leaks.iter().map(|leak| leak.bytes).sum();
+
+        let mut lines = vec![
+            format!(
+                "{} allocations ({}) alive at the end of the trace {}, from {} callstacks, over {} iterations:",
+                survivors,
+                format_bytes(bytes),
+                self.format_timestamp(self.timestamps.last().copied().unwrap_or(0)),
+                leaks.len(),
+                iterations
+            ),
+            format!(
+                "{:>12} {:>7} {:>14}  {:<5}  group",
+                "bytes", "count", "per iteration", "every"
+            ),
+        ];
+        for leak in &leaks {
+            let frames: Vec<_> = self.shown_frames(leak.stack).collect();
+            lines.push(format!(
+                "{:>12} {:>7} {:>14}  {:<5}  {}",
+                format_bytes(leak.bytes),
+                leak.allocations.len(),
+                format_bytes(leak.growth as u64),
+                if leak.every_iteration { "yes" } else { "no" },
+                GroupKey::Line.of(&frames, leak.stack)
+            ));
+        }
+
+        let leaking: Vec<&LeakStack> = leaks.iter().filter(|leak| leak.every_iteration).collect();
+        lines.push(match leaking.len() {
+            0 => "No callstack leaves a new allocation alive in every iteration".to_string(),
+            n => format!(
+                "{} callstacks leave a new allocation alive in every iteration, growing {} per iteration",
+                n,
+                format_bytes(leaking.iter().map(|leak| leak.growth).sum::<f64>() as u64)
+            ),
+        });
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::births_per_iteration;
+    use crate::layout::Layout;
+    use crate::repl_ops::memsnap::{MemSnap, test_snapshot};
+    use crate::repl_ops::peaks::{Iteration, Prominence};
+    use crate::utils::format_bytes;
+
+    #[test]
+    fn test_leaks() {
+        let iteration = |start, end| Iteration {
+            start,
+            end,
+            peak: (start, 0),
+            min: (start, 0),
+        };
+        let iterations = [iteration(0, 9), iteration(10, 19), iteration(20, 29)];
+        assert_eq!(
+            births_per_iteration(&[1, 5, 10, 19, 25, 29], &iterations),
+            vec![2, 2, 2]
+        );
+        assert_eq!(births_per_iteration(&[], &iterations), vec![0, 0, 0]);
+
+        let mut memsnap = test_snapshot();
+        let trace_end = *memsnap.timestamps.last().unwrap();
+
+        // every allocation alive at the end is in exactly one stack
+        let leaks = memsnap.leaks(Prominence::DEFAULT);
+        assert_eq!(
+            leaks.iterations,
+            memsnap.iterations(Prominence::DEFAULT).len()
+        );
+        let leaks = leaks.stacks;
+        let mut leaked: Vec<usize> = leaks
+            .iter()
+            .flat_map(|leak| leak.allocations.iter().copied())
+            .collect();
+        leaked.sort_unstable();
+        let alive: Vec<usize> = (0..memsnap.allocations.len())
+            .filter(|&i| memsnap.allocations[i].is_alive_at(trace_end))
+            .collect();
+        assert_eq!(leaked, alive);
+        assert!(leaks.windows(2).all(|w| w[0].bytes >= w[1].bytes));
+        assert!(leaks.iter().all(|leak| {
+            leak.per_iteration.iter().sum::<usize>() == leak.allocations.len()
+                && leak
+                    .allocations
+                    .iter()
+                    .all(|&i| memsnap.allocations[i].stack == leak.stack)
+        }));
+
+        // header, column names, one row per stack, summary
+        let report = memsnap.leaks_report(Prominence::DEFAULT);
+        let lines: Vec<&str> = report.lines().collect();
+        assert!(lines[0].starts_with(&format!(
+            "{} allocations ({}) alive at the end of the trace @{}",
+            alive.len(),
+            format_bytes(leaks.iter().map(|leak| leak.bytes).sum()),
+            trace_end
+        )));
+        assert_eq!(lines.len(), leaks.len() + 3);
+        assert_eq!(
+            lines.last().unwrap(),
+            &"No callstack leaves a new allocation alive in every iteration"
+        );
+
+        // four iterations of activations, each one also leaving 1MiB alive from `leak`, after
+        // parameters that are allocated once and kept
+        let mib = 1 << 20;
+        let event = |action: &str, addr: u64, size: u64, line: u32| {
+            serde_json::json!({
+                "action": action,
+                "addr": addr,
+                "size": size,
+                "frames": [{"name": "step", "filename": "train.py", "line": line}],
+            })
+        };
+        let mut trace = vec![event("alloc", 0, 20 * mib, 1)];
+        for i in 0..4 {
+            let base = (i + 1) * 100 * mib;
+            trace.push(event("alloc", base, mib, 2));
+            let activations = (1..=3).map(|a| base + a * 10 * mib);
+            trace.extend(
+                activations
+                    .clone()
+                    .map(|addr| event("alloc", addr, 10 * mib, 3)),
+            );
+            trace.extend(activations.map(|addr| event("free_completed", addr, 10 * mib, 3)));
+        }
+        let trace_path = std::env::temp_dir().join("tomi_test_leaks_trace.json");
+        let trace_path = trace_path.to_str().unwrap();
+        std::fs::write(trace_path, serde_json::to_string(&trace).unwrap()).unwrap();
+        let mut memsnap = MemSnap::from_trace_json(trace_path, Layout::Stack).unwrap();
+        std::fs::remove_file(trace_path).unwrap();
+
+        let leaks = memsnap.leaks(Prominence::DEFAULT);
+        assert_eq!(leaks.iterations, 4);
+        let [params, leak] = &leaks.stacks[..] else {
+            panic!("expected two leaking stacks, got {:?}", leaks.stacks);
+        };
+        assert_eq!((params.bytes, params.allocations.len()), (20 * mib, 1));
+        assert!(!params.every_iteration && params.growth == 0.0);
+        assert_eq!((leak.bytes, leak.allocations.len()), (4 * mib, 4));
+        assert_eq!(leak.per_iteration, vec![1, 1, 1, 1]);
+        assert!(leak.every_iteration);
+        assert_eq!(leak.growth, mib as f64);
+
+        // the stack leaking every iteration comes first, and is summed up at the end
+        let report = memsnap.leaks_report(Prominence::DEFAULT);
+        let lines: Vec<&str> = report.lines().collect();
+        assert!(lines[0].starts_with("5 allocations (24.0 MiB) alive at the end of the trace @"));
+        assert!(lines[0].ends_with(", from 2 callstacks, over 4 iterations:"));
+        assert_eq!(lines.len(), 2 + 3);
+        assert!(lines[2].starts_with("     4.0 MiB       4        1.0 MiB  yes  "));
+        assert!(lines[3].starts_with("    20.0 MiB       1          0.0 B  no   "));
+        assert_eq!(
+            lines[4],
+            "1 callstacks leave a new allocation alive in every iteration, growing 1.0 MiB per iteration"
+        );
+    }
+}
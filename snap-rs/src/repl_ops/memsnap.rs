@@ -1,9 +1,19 @@
-use log::{info, trace};
+use log::info;
 use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     allocation::Allocation,
-    load::{RawSnap, SnapType, load_allocations, read_snap_from_jsons, read_snap_from_zip},
+    allocator::SegmentHistory,
+    callstack::CallstackTable,
+    clock::{Clock, format_timestamp, parse_timestamp},
+    frame_filter::FrameFilter,
+    layout::Layout,
+    lifetime::LifetimeIndex,
+    load::{
+        LoadedSnap, load_allocations, load_allocations_from_pickle, read_snap_from_jsons,
+        read_snap_from_trace_json, read_snap_from_zip,
+    },
 };
 use std::collections::BTreeMap;
 
@@ -11,29 +21,56 @@ use super::timeline::Timeline;
 
 pub type AllocationIndex = usize;
 
+/// The file(s) a snap was loaded from and how, recorded in caches so that a stale one is rejected
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Source {
+    pub path: String, // both paths, space separated, for allocations.json and elements.json
+    pub layout: Layout,
+}
+
 /// Options are lazily created
 pub struct MemSnap {
+    pub device: usize, // device whose allocations are loaded in this struct
+
+    pub source: Option<Source>, // None if built in memory
+
     pub allocations: Vec<Allocation>,
 
+    pub callstacks: CallstackTable, // frames and stacks that `Allocation::stack` refers to
+
+    pub segments: Option<SegmentHistory>, // caching allocator segments over time, if the source has them
+
     pub timestamps: Vec<u64>, // all timestamps that something happens, sorted ascending
 
+    pub clock: Option<Clock>, // timestep -> wall-clock time, if the trace has `time_us`
+
+    pub frame_filter: FrameFilter, // frames of the callstacks that are printed and exported
+
+    pub shown_frames: Vec<bool>, // by frame id, from `frame_filter`
+
     pub timeline: Option<Timeline>,
 
     pub global_sorted_sizes: Option<Vec<AllocationIndex>>, // indices, sorted descending
 
-    pub timestamp_sorted_sizes: BTreeMap<u64, Vec<AllocationIndex>>, // timestamp -> indices, sorted descending
+    pub lifetimes: LifetimeIndex, // which allocations are alive when
+
+    pub timestamp_sorted_sizes: Option<(u64, Vec<AllocationIndex>)>, // last timestamp queried, indices sorted descending
 
     pub peak_sorted_sizes: Option<Vec<AllocationIndex>>,
 
     pub database: Option<Connection>, // database connection to sqlite
+
+    pub other_devices: BTreeMap<usize, MemSnap>, // device -> its snap, swapped in by `select_device`
+
+    pub baseline: Option<Box<MemSnap>>, // another run to `diff` against, set by `load_baseline`
 }
 
 impl MemSnap {
-    pub fn new(allocations: Vec<Allocation>) -> Self {
+    pub fn new(loaded: LoadedSnap) -> Self {
         info!("Sorting timestamps...");
         let mut timestamps: Vec<u64> = Vec::new();
 
-        for alloc in &allocations {
+        for alloc in &loaded.allocations {
             timestamps.extend(alloc.timesteps.iter());
         }
 
@@ -42,37 +79,133 @@ impl MemSnap {
 
         // dbg!(&timestamps);
 
+        Self::with_timestamps(loaded, timestamps)
+    }
+
+    /// `timestamps` must be the sorted, deduplicated timesteps of all allocations
+    pub fn with_timestamps(loaded: LoadedSnap, timestamps: Vec<u64>) -> Self {
         MemSnap {
-            allocations,
-            timestamps: timestamps,
+            device: 0,
+            source: None,
+            clock: Clock::from_allocations(&loaded.allocations),
+            lifetimes: LifetimeIndex::new(&loaded.allocations),
+            frame_filter: FrameFilter::default(),
+            shown_frames: vec![true; loaded.callstacks.num_frames()],
+            allocations: loaded.allocations,
+            callstacks: loaded.callstacks,
+            segments: loaded.segments,
+            timestamps,
             timeline: None,
             global_sorted_sizes: None,
-            timestamp_sorted_sizes: BTreeMap::new(),
+            timestamp_sorted_sizes: None,
             peak_sorted_sizes: None,
             database: None,
+            other_devices: BTreeMap::new(),
+            baseline: None,
         }
     }
 
-    pub fn from_zip(zip_path: &str) -> anyhow::Result<Self> {
+    /// One snap per device; the lowest device id is selected
+    /// Selects the first device that recorded allocations (a pickle has a trace for every
+    /// device, even the unused ones), or the first device if none did
+    pub fn from_devices(devices: BTreeMap<usize, LoadedSnap>) -> anyhow::Result<Self> {
+        let selected = devices
+            .iter()
+            .find(|(_, loaded)| !loaded.allocations.is_empty())
+            .or(devices.first_key_value())
+            .map(|(&device, _)| device)
+            .ok_or_else(|| anyhow::anyhow!("Snapshot contains no device trace"))?;
+
+        let mut snaps: BTreeMap<usize, MemSnap> = devices
+            .into_iter()
+            .map(|(device, loaded)| {
+                info!("Device {}:", device);
+                let mut snap = Self::new(loaded);
+                snap.device = device;
+                (device, snap)
+            })
+            .collect();
+
+        let mut snap = snaps.remove(&selected).unwrap();
+        snap.other_devices = snaps;
+
+        Ok(snap)
+    }
+
+    pub fn from_zip(zip_path: &str, layout: Layout) -> anyhow::Result<Self> {
         // pretty_env_logger::formatted_timed_builder()
         //     .filter_level(log::LevelFilter::Trace)
         //     .init();
 
         info!("Loading allocations from zip...");
-        let rawsnap = read_snap_from_zip(zip_path)?;
-        let allocations = load_allocations(rawsnap)?;
+        let devices = read_snap_from_zip(zip_path)?
+            .into_iter()
+            .map(|(device, rawsnap)| Ok((device, load_allocations(rawsnap, layout)?)))
+            .collect::<anyhow::Result<_>>()?;
 
-        Ok(Self::new(allocations))
+        Ok(Self::from_devices(devices)?.with_source(zip_path, layout))
     }
 
-    pub fn from_jsons(alloc_path: &str, elements_path: &str) -> anyhow::Result<Self> {
+    pub fn from_jsons(
+        alloc_path: &str,
+        elements_path: &str,
+        layout: Layout,
+    ) -> anyhow::Result<Self> {
         // pretty_env_logger::formatted_timed_builder()
         //     .filter_level(log::LevelFilter::Trace)
         //     .init();
 
         info!("Loading allocations from jsons...");
         let rawsnap = read_snap_from_jsons(alloc_path, elements_path)?;
-        let allocations = load_allocations(rawsnap)?;
-        Ok(Self::new(allocations))
+        let allocations = load_allocations(rawsnap, layout)?;
+        let path = format!("{} {}", alloc_path, elements_path);
+        Ok(Self::new(allocations).with_source(&path, layout))
+    }
+
+    pub fn from_trace_json(trace_path: &str, layout: Layout) -> anyhow::Result<Self> {
+        info!("Loading allocations from trace json...");
+        let rawsnap = read_snap_from_trace_json(trace_path)?;
+        let allocations = load_allocations(rawsnap, layout)?;
+        Ok(Self::new(allocations).with_source(trace_path, layout))
+    }
+
+    pub fn from_pickle(pickle_path: &str, layout: Layout) -> anyhow::Result<Self> {
+        info!("Loading allocations from pickle...");
+        let devices = load_allocations_from_pickle(pickle_path, layout)?;
+        Ok(Self::from_devices(devices)?.with_source(pickle_path, layout))
+    }
+
+    /// Records the source on every device
+    fn with_source(mut self, path: &str, layout: Layout) -> Self {
+        let source = Source {
+            path: path.to_string(),
+            layout,
+        };
+        for snap in self.other_devices.values_mut() {
+            snap.source = Some(source.clone());
+        }
+        self.source = Some(source);
+        self
+    }
+
+    /// Parses a `@timestep` or `@<elapsed time>` argument, see `clock::parse_timestamp`
+    pub fn parse_timestamp(&self, s: &str) -> anyhow::Result<u64> {
+        parse_timestamp(s, self.clock.as_ref())
     }
+
+    /// `@timestep (+elapsed time)`
+    pub fn format_timestamp(&self, timestep: u64) -> String {
+        format_timestamp(timestep, self.clock.as_ref())
+    }
+}
+
+/// The snapshot checked in under `../snapshots`, in the stack layout, for tests
+#[cfg(test)]
+pub fn test_snapshot() -> MemSnap {
+    MemSnap::from_jsons(
+        "../snapshots/allocations.json",
+        "../snapshots/elements.json",
+        Layout::Stack,
+    )
+    .unwrap()
 }
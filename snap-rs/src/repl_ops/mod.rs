@@ -1,5 +1,22 @@
+pub mod cache;
+pub mod check;
+pub mod columnar;
 pub mod database;
+pub mod device;
+pub mod diff;
+pub mod filter;
+pub mod flame;
+pub mod frames;
+pub mod group;
+pub mod leaks;
 pub mod memsnap;
 pub mod peak;
+pub mod peakinfo;
+pub mod peaks;
+pub mod perfetto;
+pub mod pprof;
+pub mod range;
+pub mod segments;
 pub mod sort;
+pub mod subset;
 pub mod timeline;
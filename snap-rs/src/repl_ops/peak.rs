@@ -10,30 +10,30 @@ impl MemSnap {
             )));
         }
 
-        match &self.peak_sorted_sizes {
-            Some(indices_sorted_by_peak) => Ok(indices_sorted_by_peak[..k].to_vec()),
-            None => {
-                log::info!("Sorting by peak globally");
-                // create topk vector
-                let mut peaks = self
-                    .allocations
-                    .iter()
-                    .enumerate()
-                    .map(|(i, alloc)| (i, alloc.peak_mem))
-                    .collect::<Vec<(usize, u64)>>();
-
-                // NOTE: sort descending
-                peaks.sort_by(|(i1, peak1), (i2, peak2)| peak2.cmp(peak1));
+        self.build_peak_sorted_sizes();
+        let indices_sorted_by_peak = self.peak_sorted_sizes.as_ref().unwrap();
+        Ok(indices_sorted_by_peak[..k].to_vec())
+    }
 
-                let indices_sorted_by_peak: Vec<usize> =
-                    peaks.into_iter().map(|(index, peak)| index).collect();
+    /// Sort all allocations by peak, descending, if not sorted yet
+    pub fn build_peak_sorted_sizes(&mut self) {
+        if self.peak_sorted_sizes.is_none() {
+            log::info!("Sorting by peak globally");
+            // create topk vector
+            let mut peaks = self
+                .allocations
+                .iter()
+                .enumerate()
+                .map(|(i, alloc)| (i, alloc.peak_mem))
+                .collect::<Vec<(usize, u64)>>();
 
-                self.peak_sorted_sizes = Some(indices_sorted_by_peak.clone());
+            // NOTE: sort descending
+            peaks.sort_by(|(_, peak1), (_, peak2)| peak2.cmp(peak1));
 
-                // dbg!(&indices_sorted_by_peak);
+            let indices_sorted_by_peak: Vec<usize> =
+                peaks.into_iter().map(|(index, _)| index).collect();
 
-                Ok(indices_sorted_by_peak[..k].to_vec())
-            }
+            self.peak_sorted_sizes = Some(indices_sorted_by_peak);
         }
     }
 }
@@ -41,6 +41,7 @@ impl MemSnap {
 #[cfg(test)]
 mod tests {
     use crate::{
+        layout::Layout,
         load::{load_allocations, read_snap_from_jsons},
         repl_ops::memsnap::MemSnap,
         utils::format_bytes,
@@ -54,8 +55,11 @@ mod tests {
         let alloc_path = "../snapshots/allocations.json";
         let elements_path = "../snapshots/elements.json";
 
-        let allocations =
-            load_allocations(read_snap_from_jsons(alloc_path, elements_path).unwrap()).unwrap();
+        let allocations = load_allocations(
+            read_snap_from_jsons(alloc_path, elements_path).unwrap(),
+            Layout::Stack,
+        )
+        .unwrap();
 
         let mut memsnap = MemSnap::new(allocations);
 
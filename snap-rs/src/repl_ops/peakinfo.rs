@@ -0,0 +1,178 @@
+use super::group::GroupKey;
+use super::memsnap::{AllocationIndex, MemSnap};
+use crate::utils::format_bytes;
+
+/// What the memory in use is made of at its global maximum
+#[derive(Debug)]
+pub struct PeakInfo {
+    pub timestamp: u64,
+    pub peak: u64,                    // memory in use at the peak, from the timeline
+    pub alive: Vec<AllocationIndex>,  // alive at the peak, largest first
+    pub ramp_start: (u64, u64),       // (timestamp, memory) where the last climb to the peak starts
+    pub pushed: Vec<AllocationIndex>, // alive at the peak and born during that climb, latest first
+}
+
+impl MemSnap {
+    pub fn peak_info(&mut self) -> PeakInfo {
+        let timestamp = self.peak_timestamp();
+        let timeline = self.timeline.as_ref().unwrap();
+        let peak = timeline.max_alloc;
+
+        // walk back from the peak while memory keeps going down
+        let mut i = timeline.timeline.partition_point(|&(t, _)| t < timestamp);
+        while i > 0 && timeline.timeline[i - 1].1 <= timeline.timeline[i].1 {
+            i -= 1;
+        }
+        let ramp_start = timeline
+            .timeline
+            .get(i)
+            .copied()
+            .unwrap_or((timestamp, peak));
+
+        let mut alive = self.lifetimes.alive_at(timestamp);
+        alive.sort_by_key(|&i| (std::cmp::Reverse(self.allocations[i].size), i));
+
+        let mut pushed: Vec<AllocationIndex> = alive
+            .iter()
+            .copied()
+            .filter(|&i| self.allocations[i].start_end_time().0 > ramp_start.0)
+            .collect();
+        pushed.sort_by_key(|&i| (std::cmp::Reverse(self.allocations[i].start_end_time().0), i));
+
+        PeakInfo {
+            timestamp,
+            peak,
+            alive,
+            ramp_start,
+            pushed,
+        }
+    }
+
+    /// The peak, the groups (by innermost Python line) and allocations alive at it with their
+    /// share of the peak (the first `n` allocations), and the allocations that pushed memory up
+    /// to it
+    pub fn peak_report(&mut self, n: Option<usize>) -> String {
+        let info = self.peak_info();
+        let alive_bytes: u64 = info.alive.iter().map(|&i| self.allocations[i].size).sum();
+        let share = |bytes: u64| 100.0 * bytes as f64 / info.peak.max(1) as f64;
+
+        let mut lines = vec![format!(
+            "Peak: {} {}, {} allocations alive ({})",
+            format_bytes(info.peak),
+            self.format_timestamp(info.timestamp),
+            info.alive.len(),
+            format_bytes(alive_bytes)
+        )];
+
+        lines.push(String::new());
+        lines.push("By line (innermost Python frame):".to_string());
+        lines.push(format!(
+            "{:>12} {:>6} {:>7}  group",
+            "bytes", "share", "count"
+        ));
+        let groups = self.group_by(GroupKey::Line, Some(info.timestamp));
+        for group in groups.iter().filter(|group| group.alive > 0) {
+            lines.push(format!(
+                "{:>12} {:>5.1}% {:>7}  {}",
+                format_bytes(group.live_bytes),
+                share(group.live_bytes),
+                group.alive,
+                group.key
+            ));
+        }
+
+        let row = |snap: &MemSnap, i: AllocationIndex| {
+            let alloc = &snap.allocations[i];
+            let frames: Vec<_> = snap.shown_frames(alloc.stack).collect();
+            format!(
+                "{:>7} {:>12} {:>5.1}%  born {:<20} {}",
+                i,
+                format_bytes(alloc.size),
+                share(alloc.size),
+                snap.format_timestamp(alloc.start_end_time().0),
+                GroupKey::Line.of(&frames, alloc.stack)
+            )
+        };
+
+        let n = n.unwrap_or(info.alive.len());
+        lines.push(String::new());
+        lines.push(format!(
+            "Allocations ({} of {}, largest first):",
+            n.min(info.alive.len()),
+            info.alive.len()
+        ));
+        lines.extend(info.alive.iter().take(n).map(|&i| row(self, i)));
+
+        lines.push(String::new());
+        let (ramp_time, ramp_memory) = info.ramp_start;
+        lines.push(format!(
+            "Pushed over: memory climbed from {} {} to the peak, with {} allocations born since (latest first):",
+            format_bytes(ramp_memory),
+            self.format_timestamp(ramp_time),
+            info.pushed.len()
+        ));
+        lines.extend(info.pushed.iter().map(|&i| row(self, i)));
+
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{repl_ops::memsnap::test_snapshot, utils::format_bytes};
+
+    #[test]
+    fn test_peak_info() {
+        let mut memsnap = test_snapshot();
+
+        let info = memsnap.peak_info();
+        let allocs = &memsnap.allocations;
+
+        // in the stack layout, what is alive at the peak makes up the peak
+        let alive: u64 = info.alive.iter().map(|&i| allocs[i].size).sum();
+        assert_eq!(alive, info.peak);
+        assert!(
+            info.alive
+                .iter()
+                .all(|&i| allocs[i].is_alive_at(info.timestamp))
+        );
+        assert!(
+            info.alive
+                .windows(2)
+                .all(|w| allocs[w[0]].size >= allocs[w[1]].size)
+        );
+
+        // the climb ends at the peak, and what was born during it is part of the climb
+        assert!(info.ramp_start.0 <= info.timestamp && info.ramp_start.1 <= info.peak);
+        let pushed: u64 = info.pushed.iter().map(|&i| allocs[i].size).sum();
+        assert!(!info.pushed.is_empty() && pushed >= info.peak - info.ramp_start.1);
+
+        // the header, then the alive allocations and the pushed ones are listed
+        let (timestamp, count, pushed) = (info.timestamp, info.alive.len(), info.pushed.len());
+        let report = memsnap.peak_report(Some(3));
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            lines[0],
+            format!(
+                "Peak: {} {}, {} allocations alive ({})",
+                format_bytes(info.peak),
+                memsnap.format_timestamp(timestamp),
+                count,
+                format_bytes(alive)
+            )
+        );
+        let listed = lines
+            .iter()
+            .position(|line| line.starts_with("Allocations ("))
+            .unwrap();
+        assert_eq!(
+            lines[listed],
+            format!(
+                "Allocations ({} of {}, largest first):",
+                count.min(3),
+                count
+            )
+        );
+        assert_eq!(lines.len(), listed + count.min(3) + 3 + pushed);
+    }
+}
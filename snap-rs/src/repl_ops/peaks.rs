@@ -0,0 +1,335 @@
+use super::memsnap::MemSnap;
+use crate::utils::{format_bytes, format_delta, format_duration, parse_bytes};
+
+/// A local maximum of the memory in use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalPeak {
+    pub timestamp: u64,
+    pub height: u64,
+    pub prominence: u64, // how far memory drops before it reaches a higher peak, on both sides
+}
+
+/// One step of a training loop: from one split (the lowest point between two peaks) to the next
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Iteration {
+    pub start: u64,
+    pub end: u64,
+    pub peak: (u64, u64), // (timestamp, memory)
+    pub min: (u64, u64),
+}
+
+/// Minimum prominence of a peak: bytes, or a percentage of the global peak (`1%`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Prominence {
+    Bytes(u64),
+    Percent(f64),
+}
+
+impl Prominence {
+    pub const DEFAULT: Prominence = Prominence::Percent(1.0);
+
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        match s.strip_suffix('%') {
+            Some(percent) => {
+                Ok(Prominence::Percent(percent.parse().map_err(|e| {
+                    anyhow::anyhow!("Invalid percentage '{}': {}", s, e)
+                })?))
+            }
+            None => Ok(Prominence::Bytes(parse_bytes(s)?)),
+        }
+    }
+
+    fn bytes(&self, max: u64) -> u64 {
+        match self {
+            Prominence::Bytes(bytes) => *bytes,
+            Prominence::Percent(percent) => (max as f64 * percent / 100.0) as u64,
+        }
+    }
+}
+
+/// For each point, the lowest value between it and the nearest strictly higher point on its
+/// left (or the start), with a stack of decreasing values and the minimum since each of them
+fn left_bases(values: &[u64]) -> Vec<u64> {
+    let mut stack: Vec<(u64, u64)> = Vec::new(); // (value, minimum since the entry below it)
+    values
+        .iter()
+        .map(|&value| {
+            let mut min = value;
+            while let Some(&(top, top_min)) = stack.last() {
+                if top > value {
+                    break;
+                }
+                min = min.min(top_min);
+                stack.pop();
+            }
+            stack.push((value, min));
+            min
+        })
+        .collect()
+}
+
+/// Local maxima of `values` (plateaus count once, at their first point) whose prominence is at
+/// least `min_prominence`, in order. The first and last points are never peaks.
+pub fn find_peaks(values: &[u64], min_prominence: u64) -> Vec<(usize, u64)> {
+    let left = left_bases(values);
+    let mut right = left_bases(&values.iter().rev().copied().collect::<Vec<_>>());
+    right.reverse();
+
+    let mut peaks = Vec::new();
+    let mut i = 1;
+    while i + 1 < values.len() {
+        // the end of a plateau starting at i
+        let mut j = i;
+        while j + 1 < values.len() && values[j + 1] == values[i] {
+            j += 1;
+        }
+        if values[i - 1] < values[i] && j + 1 < values.len() && values[j + 1] < values[i] {
+            // the right base of the plateau is the one of its last point
+            let prominence = values[i] - left[i].max(right[j]);
+            if prominence >= min_prominence {
+                peaks.push((i, prominence));
+            }
+        }
+        i = j + 1;
+    }
+    peaks
+}
+
+impl MemSnap {
+    /// Local maxima of the memory in use with at least `prominence`, in time order
+    pub fn local_peaks(&mut self, prominence: Prominence) -> Vec<LocalPeak> {
+        self.build_timeline();
+        let timeline = self.timeline.as_ref().unwrap();
+        let values: Vec<u64> = timeline.timeline.iter().map(|&(_, mem)| mem).collect();
+
+        find_peaks(&values, prominence.bytes(timeline.max_alloc).max(1))
+            .into_iter()
+            .map(|(i, prominence)| LocalPeak {
+                timestamp: timeline.timeline[i].0,
+                height: values[i],
+                prominence,
+            })
+            .collect()
+    }
+
+    /// The trace split at the lowest point between each two consecutive peaks
+    pub fn iterations(&mut self, prominence: Prominence) -> Vec<Iteration> {
+        let peaks = self.local_peaks(prominence);
+        let timeline = &self.timeline.as_ref().unwrap().timeline;
+        if timeline.is_empty() {
+            return Vec::new();
+        }
+        let position = |t: u64| timeline.partition_point(|&(ts, _)| ts < t);
+
+        let mut splits = vec![0];
+        for pair in peaks.windows(2) {
+            let (from, to) = (position(pair[0].timestamp), position(pair[1].timestamp));
+            let lowest = (from..=to).min_by_key(|&i| timeline[i].1).unwrap();
+            splits.push(lowest);
+        }
+        splits.push(timeline.len());
+
+        splits
+            .windows(2)
+            .filter(|w| w[0] < w[1])
+            .map(|w| {
+                let points = &timeline[w[0]..w[1]];
+                Iteration {
+                    start: points[0].0,
+                    end: points[points.len() - 1].0,
+                    peak: *points
+                        .iter()
+                        .max_by_key(|(t, mem)| (mem, u64::MAX - t))
+                        .unwrap(),
+                    min: *points.iter().min_by_key(|(t, mem)| (mem, *t)).unwrap(),
+                }
+            })
+            .collect()
+    }
+
+    /// The `n` most prominent peaks in time order, with the gaps between them, and the spread of
+    /// their heights and gaps
+    pub fn peaks_report(&mut self, n: usize, prominence: Prominence) -> String {
+        let mut peaks = self.local_peaks(prominence);
+        let found = peaks.len();
+        peaks.sort_by_key(|peak| (std::cmp::Reverse(peak.prominence), peak.timestamp));
+        peaks.truncate(n);
+        peaks.sort_by_key(|peak| peak.timestamp);
+
+        let max = self.timeline.as_ref().unwrap().max_alloc;
+        let mut lines = vec![
+            format!(
+                "{} of {} peaks with prominence >= {}:",
+                peaks.len(),
+                found,
+                format_bytes(prominence.bytes(max).max(1))
+            ),
+            format!(
+                "{:<24} {:>12} {:>12}  since previous",
+                "timestamp", "height", "prominence"
+            ),
+        ];
+        for (i, peak) in peaks.iter().enumerate() {
+            let gap = match i {
+                0 => "-".to_string(),
+                _ => self.format_gap(peaks[i - 1].timestamp, peak.timestamp),
+            };
+            lines.push(format!(
+                "{:<24} {:>12} {:>12}  {}",
+                self.format_timestamp(peak.timestamp),
+                format_bytes(peak.height),
+                format_bytes(peak.prominence),
+                gap
+            ));
+        }
+
+        if peaks.len() >= 2 {
+            let heights = peaks.iter().map(|peak| peak.height);
+            let (lowest, highest) = (heights.clone().min().unwrap(), heights.max().unwrap());
+            let mut gaps: Vec<u64> = peaks
+                .windows(2)
+                .map(|w| w[1].timestamp - w[0].timestamp)
+                .collect();
+            gaps.sort_unstable();
+            lines.push(format!(
+                "Heights {} to {} (spread {}), gaps of {} to {} timesteps (median {})",
+                format_bytes(lowest),
+                format_bytes(highest),
+                format_bytes(highest - lowest),
+                gaps[0],
+                gaps[gaps.len() - 1],
+                gaps[gaps.len() / 2]
+            ));
+        }
+        lines.join("\n")
+    }
+
+    /// One line per iteration: its range, peak, minimum and how they grew since the previous one,
+    /// then the growth of the minimum per iteration over the whole trace
+    pub fn iterations_report(&mut self, prominence: Prominence) -> String {
+        let iterations = self.iterations(prominence);
+        let delta = |now: u64, before: u64| format_delta(now as i64 - before as i64);
+
+        let mut lines = vec![
+            format!("{} iterations, split between peaks:", iterations.len()),
+            format!(
+                "{:>4}  {:<32} {:>12} {:>12} {:>12} {:>12}",
+                "#", "range", "peak", "Δpeak", "min", "Δmin"
+            ),
+        ];
+        for (i, iteration) in iterations.iter().enumerate() {
+            let (peak_delta, min_delta) = match i {
+                0 => ("-".to_string(), "-".to_string()),
+                _ => (
+                    delta(iteration.peak.1, iterations[i - 1].peak.1),
+                    delta(iteration.min.1, iterations[i - 1].min.1),
+                ),
+            };
+            lines.push(format!(
+                "{:>4}  {:<32} {:>12} {:>12} {:>12} {:>12}",
+                i,
+                format!("@{}..{}", iteration.start, iteration.end),
+                format_bytes(iteration.peak.1),
+                peak_delta,
+                format_bytes(iteration.min.1),
+                min_delta
+            ));
+        }
+
+        if iterations.len() >= 3 {
+            // the first iteration usually allocates parameters and optimizer state, skip it
+            let steady = &iterations[1..];
+            let median = |deltas: &mut Vec<i64>| {
+                deltas.sort_unstable();
+                deltas[deltas.len() / 2]
+            };
+            let mut min_deltas: Vec<i64> = steady
+                .windows(2)
+                .map(|w| w[1].min.1 as i64 - w[0].min.1 as i64)
+                .collect();
+            let mut peak_deltas: Vec<i64> = steady
+                .windows(2)
+                .map(|w| w[1].peak.1 as i64 - w[0].peak.1 as i64)
+                .collect();
+            let (first, last) = (steady[0], steady[steady.len() - 1]);
+            lines.push(format!(
+                "From iteration 1 on: min {} and peak {} per iteration (median), min {} and peak {} in total",
+                format_delta(median(&mut min_deltas)),
+                format_delta(median(&mut peak_deltas)),
+                delta(last.min.1, first.min.1),
+                delta(last.peak.1, first.peak.1)
+            ));
+        }
+        lines.join("\n")
+    }
+
+    /// Timesteps between two timestamps, and the elapsed time if there is a clock
+    fn format_gap(&self, from: u64, to: u64) -> String {
+        match &self.clock {
+            Some(clock) => format!(
+                "{} timesteps ({})",
+                to - from,
+                format_duration(clock.elapsed_us(to) - clock.elapsed_us(from))
+            ),
+            None => format!("{} timesteps", to - from),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Prominence, find_peaks};
+    use crate::repl_ops::memsnap::test_snapshot;
+
+    #[test]
+    fn test_peaks() {
+        // three steps, creeping up by 1 each; the bump at 4 is not prominent
+        let values = [0, 10, 2, 3, 2, 11, 3, 3, 12, 12, 4, 0];
+        assert_eq!(find_peaks(&values, 5), vec![(1, 8), (5, 8), (8, 12)]);
+        assert_eq!(find_peaks(&values, 1).len(), 4);
+        assert_eq!(find_peaks(&[5, 5, 5], 0), vec![]);
+
+        assert_eq!(Prominence::parse("10%").unwrap(), Prominence::Percent(10.0));
+        assert_eq!(Prominence::parse("1KiB").unwrap(), Prominence::Bytes(1024));
+
+        let mut memsnap = test_snapshot();
+        let peaks = memsnap.local_peaks(Prominence::DEFAULT);
+        let iterations = memsnap.iterations(Prominence::DEFAULT);
+        assert_eq!(iterations.len(), peaks.len().max(1));
+
+        // iterations cover the timeline, and the global peak is one of theirs
+        let timeline = &memsnap.timeline.as_ref().unwrap().timeline;
+        assert_eq!(iterations[0].start, timeline[0].0);
+        assert_eq!(iterations.last().unwrap().end, timeline.last().unwrap().0);
+        assert!(iterations.windows(2).all(|w| w[0].end < w[1].start));
+        let max = memsnap.timeline.as_ref().unwrap().max_alloc;
+        assert!(iterations.iter().any(|it| it.peak.1 == max));
+
+        // header, column names, one line per peak and a summary of their heights and gaps
+        let report = memsnap.peaks_report(10, Prominence::DEFAULT);
+        let lines: Vec<&str> = report.lines().collect();
+        let shown = peaks.len().min(10);
+        assert!(lines[0].starts_with(&format!(
+            "{} of {} peaks with prominence >= ",
+            shown,
+            peaks.len()
+        )));
+        assert_eq!(lines.len(), shown + 2 + usize::from(shown >= 2));
+
+        // header, column names, one line per iteration and the growth from iteration 1 on
+        let report = memsnap.iterations_report(Prominence::DEFAULT);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            lines[0],
+            format!("{} iterations, split between peaks:", iterations.len())
+        );
+        assert_eq!(
+            lines.len(),
+            iterations.len() + 2 + usize::from(iterations.len() >= 3)
+        );
+        assert!(lines[2].trim_start().starts_with(&format!(
+            "0  @{}..{}",
+            iterations[0].start, iterations[0].end
+        )));
+    }
+}
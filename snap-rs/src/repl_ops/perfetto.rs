@@ -0,0 +1,178 @@
+use super::memsnap::MemSnap;
+use crate::utils::format_bytes;
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// Allocations smaller than this are only part of the live bytes counter, not slices
+pub const DEFAULT_SLICE_MIN_SIZE: u64 = 1 << 20;
+
+/// One event of the Chrome trace event format, as read by Perfetto and chrome://tracing
+#[derive(Serialize)]
+struct ChromeEvent {
+    name: String,
+    ph: &'static str, // C: counter, X: complete slice, M: metadata
+    ts: u64,          // microseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dur: Option<u64>,
+    pid: usize,
+    tid: usize,
+    args: Value,
+}
+
+impl MemSnap {
+    /// Writes a Chrome trace JSON: a counter track of live bytes, and one slice per allocation of at
+    /// least `min_size` bytes with its callstack in args. Timestamps are `time_us` if the trace has
+    /// them (frees are placed at the next alloc), timesteps otherwise.
+    pub fn export_perfetto(&self, path: &str, min_size: u64) -> anyhow::Result<usize> {
+        let clock = self.clock.as_ref();
+        let to_ts = |timestep: u64| match clock {
+            Some(clock) => clock.time_us(timestep),
+            None => timestep,
+        };
+
+        let pid = self.device;
+        let mut events = vec![ChromeEvent {
+            name: "process_name".to_string(),
+            ph: "M",
+            ts: 0,
+            dur: None,
+            pid,
+            tid: 0,
+            args: json!({ "name": format!("tomi memory (device {})", self.device) }),
+        }];
+
+        // live bytes: +size when an allocation starts, -size after it stops
+        let mut deltas: BTreeMap<u64, i64> = BTreeMap::new();
+        for alloc in &self.allocations {
+            if alloc.timesteps.is_empty() {
+                continue;
+            }
+            let (start, stop) = alloc.start_end_time();
+            *deltas.entry(start).or_default() += alloc.size as i64;
+            *deltas.entry(stop + 1).or_default() -= alloc.size as i64;
+        }
+        let mut live: i64 = 0;
+        for (timestep, delta) in deltas {
+            live += delta;
+            events.push(ChromeEvent {
+                name: "live bytes".to_string(),
+                ph: "C",
+                ts: to_ts(timestep),
+                dur: None,
+                pid,
+                tid: 0,
+                args: json!({ "bytes": live }),
+            });
+        }
+
+        // large allocations as slices, on as few lanes as possible without overlaps in a lane
+        let mut large: Vec<usize> = (0..self.allocations.len())
+            .filter(|&i| {
+                let alloc = &self.allocations[i];
+                alloc.size >= min_size && !alloc.timesteps.is_empty()
+            })
+            .collect();
+        large.sort_by_key(|&i| self.allocations[i].start_end_time());
+
+        let mut lane_ends: Vec<u64> = Vec::new(); // last timestep in use, per lane
+        for &i in &large {
+            let alloc = &self.allocations[i];
+            let (start, stop) = alloc.start_end_time();
+            let lane = match lane_ends.iter().position(|&end| end < start) {
+                Some(lane) => lane,
+                None => {
+                    lane_ends.push(0);
+                    lane_ends.len() - 1
+                }
+            };
+            lane_ends[lane] = stop;
+
+            let ts = to_ts(start);
+            events.push(ChromeEvent {
+                name: format!("#{} {}", i, format_bytes(alloc.size)),
+                ph: "X",
+                ts,
+                dur: Some(to_ts(stop + 1).saturating_sub(ts).max(1)),
+                pid,
+                tid: lane + 1,
+                args: json!({
+                    "index": i,
+                    "size": alloc.size,
+                    "addr": format!("{:#x}", alloc.addr),
+                    "stream": alloc.stream,
+                    "start_timestep": start,
+                    "stop_timestep": stop,
+                    "callstack": self.shown_callstack(alloc.stack),
+                }),
+            });
+        }
+        for lane in 0..lane_ends.len() {
+            events.push(ChromeEvent {
+                name: "thread_name".to_string(),
+                ph: "M",
+                ts: 0,
+                dur: None,
+                pid,
+                tid: lane + 1,
+                args: json!({ "name": format!("allocations >= {} ({})", format_bytes(min_size), lane) }),
+            });
+        }
+
+        let file = File::create(path)
+            .map_err(|e| anyhow::anyhow!("Failed to create trace file '{}': {}", path, e))?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(
+            &mut writer,
+            &json!({
+                "traceEvents": events,
+                "displayTimeUnit": "ms",
+                "otherData": { "time_axis": if clock.is_some() { "time_us" } else { "timestep" } },
+            }),
+        )?;
+        writer.flush()?;
+
+        Ok(large.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{layout::Layout, repl_ops::memsnap::MemSnap};
+
+    #[test]
+    fn test_export_perfetto() {
+        let alloc_path = "../snapshots/allocations.json";
+        let elements_path = "../snapshots/elements.json";
+        let path = std::env::temp_dir().join("tomi_test_perfetto.json");
+        let path = path.to_str().unwrap();
+
+        let memsnap = MemSnap::from_jsons(alloc_path, elements_path, Layout::Stack).unwrap();
+        let slices = memsnap.export_perfetto(path, 1 << 20).unwrap();
+
+        let trace: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let events = trace["traceEvents"].as_array().unwrap();
+        let of_phase = |ph: &'static str| events.iter().filter(move |e| e["ph"] == ph);
+
+        assert_eq!(of_phase("X").count(), slices);
+        assert_eq!(
+            slices,
+            memsnap
+                .allocations
+                .iter()
+                .filter(|a| a.size >= 1 << 20)
+                .count()
+        );
+        assert!(of_phase("X").all(|e| e["args"]["callstack"].is_string()));
+        assert_eq!(trace["otherData"]["time_axis"], "time_us");
+
+        // every allocation ends, including those alive at the end of the trace
+        let last = &of_phase("C").next_back().unwrap()["args"]["bytes"];
+        assert_eq!(last.as_i64(), Some(0));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
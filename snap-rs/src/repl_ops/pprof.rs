@@ -0,0 +1,253 @@
+use super::memsnap::MemSnap;
+use crate::callstack::StackId;
+use flate2::{Compression, write::GzEncoder};
+use prost::Message;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+// The messages of pprof's profile.proto that we write, see
+// https://github.com/google/pprof/blob/main/proto/profile.proto. String fields are indices into
+// `string_table`, whose first entry must be "".
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Profile {
+    #[prost(message, repeated, tag = "1")]
+    pub sample_type: Vec<ValueType>,
+    #[prost(message, repeated, tag = "2")]
+    pub sample: Vec<Sample>,
+    #[prost(message, repeated, tag = "4")]
+    pub location: Vec<Location>,
+    #[prost(message, repeated, tag = "5")]
+    pub function: Vec<Function>,
+    #[prost(string, repeated, tag = "6")]
+    pub string_table: Vec<String>,
+    #[prost(message, optional, tag = "11")]
+    pub period_type: Option<ValueType>,
+    #[prost(int64, tag = "12")]
+    pub period: i64,
+    #[prost(int64, repeated, tag = "13")]
+    pub comment: Vec<i64>,
+    #[prost(int64, tag = "14")]
+    pub default_sample_type: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ValueType {
+    #[prost(int64, tag = "1")]
+    pub r#type: i64,
+    #[prost(int64, tag = "2")]
+    pub unit: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Sample {
+    #[prost(uint64, repeated, tag = "1")]
+    pub location_id: Vec<u64>, // innermost first
+    #[prost(int64, repeated, tag = "2")]
+    pub value: Vec<i64>, // one per sample type
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Location {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(message, repeated, tag = "4")]
+    pub line: Vec<Line>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Line {
+    #[prost(uint64, tag = "1")]
+    pub function_id: u64,
+    #[prost(int64, tag = "2")]
+    pub line: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Function {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(int64, tag = "2")]
+    pub name: i64,
+    #[prost(int64, tag = "3")]
+    pub system_name: i64,
+    #[prost(int64, tag = "4")]
+    pub filename: i64,
+}
+
+/// Sample types of the exported profiles, in the order of `Sample::value`
+pub const SAMPLE_TYPES: [(&str, &str); 3] = [
+    ("inuse_space", "bytes"),
+    ("inuse_objects", "count"),
+    ("alloc_space", "bytes"),
+];
+
+#[derive(Default)]
+struct StringTable {
+    strings: Vec<String>,
+    ids: HashMap<String, i64>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        let mut table = StringTable::default();
+        table.intern("");
+        table
+    }
+
+    fn intern(&mut self, s: &str) -> i64 {
+        if let Some(&id) = self.ids.get(s) {
+            return id;
+        }
+        let id = self.strings.len() as i64;
+        self.strings.push(s.to_string());
+        self.ids.insert(s.to_string(), id);
+        id
+    }
+}
+
+impl MemSnap {
+    /// Heap profile of the device. With a timestamp, `inuse_*` counts the allocations alive at it and
+    /// `alloc_space` everything allocated up to it. Without one, `inuse_*` is taken at the end of
+    /// the trace and `alloc_space` covers the whole trace, like a Go heap profile.
+    pub fn pprof_profile(&self, timestamp: Option<u64>) -> Profile {
+        let at = timestamp.unwrap_or_else(|| self.timestamps.last().copied().unwrap_or(0));
+
+        // [inuse_space, inuse_objects, alloc_space] per stack
+        let mut values: BTreeMap<StackId, [i64; 3]> = BTreeMap::new();
+        for alloc in &self.allocations {
+            if alloc.timesteps.is_empty() || alloc.start_end_time().0 > at {
+                continue;
+            }
+            values.entry(alloc.stack).or_default()[2] += alloc.size as i64;
+        }
+        for index in self.lifetimes.alive_at(at) {
+            let alloc = &self.allocations[index];
+            let value = values.entry(alloc.stack).or_default();
+            value[0] += alloc.size as i64;
+            value[1] += 1;
+        }
+
+        let mut strings = StringTable::new();
+        let mut functions: Vec<Function> = Vec::new();
+        let mut function_ids: HashMap<(&str, &str), u64> = HashMap::new();
+        let mut location_ids: HashMap<u32, u64> = HashMap::new(); // by frame id
+        let mut locations: Vec<Location> = Vec::new();
+
+        let mut samples = Vec::new();
+        for (&stack, value) in &values {
+            let mut location_id = Vec::new();
+            for frame_id in self.shown_frame_ids(stack) {
+                let id = *location_ids.entry(frame_id).or_insert_with(|| {
+                    let frame = self.callstacks.frame(frame_id);
+                    let function_id = *function_ids
+                        .entry((frame.name.as_str(), frame.filename.as_str()))
+                        .or_insert_with(|| {
+                            let name = strings.intern(&frame.name);
+                            functions.push(Function {
+                                id: functions.len() as u64 + 1,
+                                name,
+                                system_name: name,
+                                filename: strings.intern(&frame.filename),
+                            });
+                            functions.len() as u64
+                        });
+                    locations.push(Location {
+                        id: locations.len() as u64 + 1,
+                        line: vec![Line {
+                            function_id,
+                            line: frame.line as i64,
+                        }],
+                    });
+                    locations.len() as u64
+                });
+                location_id.push(id);
+            }
+            samples.push(Sample {
+                location_id,
+                value: value.to_vec(),
+            });
+        }
+
+        let sample_type = SAMPLE_TYPES
+            .iter()
+            .map(|(ty, unit)| ValueType {
+                r#type: strings.intern(ty),
+                unit: strings.intern(unit),
+            })
+            .collect::<Vec<_>>();
+        let comment = vec![strings.intern(&match timestamp {
+            Some(t) => format!("tomi: device {}, {}", self.device, self.format_timestamp(t)),
+            None => format!("tomi: device {}, whole trace", self.device),
+        })];
+
+        Profile {
+            period_type: Some(sample_type[0].clone()),
+            period: 1,
+            default_sample_type: sample_type[0].r#type,
+            sample_type,
+            sample: samples,
+            location: locations,
+            function: functions,
+            string_table: strings.strings,
+            comment,
+        }
+    }
+
+    /// Writes `pprof_profile(timestamp)` gzipped to `path`, for `pprof`/`go tool pprof`.
+    /// Returns the number of samples (distinct callstacks).
+    pub fn export_pprof(&self, path: &str, timestamp: Option<u64>) -> anyhow::Result<usize> {
+        let profile = self.pprof_profile(timestamp);
+
+        let file = File::create(path)
+            .map_err(|e| anyhow::anyhow!("Failed to create profile '{}': {}", path, e))?;
+        let mut encoder = GzEncoder::new(BufWriter::new(file), Compression::default());
+        encoder.write_all(&profile.encode_to_vec())?;
+        encoder.finish()?.flush()?;
+
+        Ok(profile.sample.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Profile;
+    use crate::{layout::Layout, repl_ops::memsnap::MemSnap};
+    use flate2::read::GzDecoder;
+    use prost::Message;
+    use std::io::Read;
+
+    #[test]
+    fn test_export_pprof() {
+        let alloc_path = "../snapshots/allocations.json";
+        let elements_path = "../snapshots/elements.json";
+        let path = std::env::temp_dir().join("tomi_test_pprof.pb.gz");
+        let path = path.to_str().unwrap();
+
+        let memsnap = MemSnap::from_jsons(alloc_path, elements_path, Layout::Stack).unwrap();
+        let samples = memsnap.export_pprof(path, Some(57)).unwrap();
+
+        let mut bytes = Vec::new();
+        GzDecoder::new(std::fs::File::open(path).unwrap())
+            .read_to_end(&mut bytes)
+            .unwrap();
+        let profile = Profile::decode(bytes.as_slice()).unwrap();
+        assert_eq!(profile.sample.len(), samples);
+        assert_eq!(profile.string_table[0], "");
+
+        let sum = |i: usize| profile.sample.iter().map(|s| s.value[i]).sum::<i64>();
+        let alive = memsnap.allocations.iter().filter(|a| a.is_alive_at(57));
+        assert_eq!(sum(0), alive.clone().map(|a| a.size as i64).sum::<i64>());
+        assert_eq!(sum(1), alive.count() as i64);
+        assert!(sum(2) >= sum(0));
+
+        // every location points to a function, with an existing name
+        for location in &profile.location {
+            let function = &profile.function[location.line[0].function_id as usize - 1];
+            assert!(!profile.string_table[function.name as usize].is_empty());
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+use super::filter::Filter;
+use super::memsnap::{AllocationIndex, MemSnap};
+
+/// How the lifetime of an allocation relates to a time range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeQuery {
+    /// `alive`: alive at every timestep of the range
+    Through,
+    /// `born`: allocated in the range
+    Born,
+    /// `freed`: freed in the range (allocations alive at the end of the trace are never freed)
+    Freed,
+    /// `overlap`: alive at some timestep of the range
+    Overlapping,
+}
+
+impl RangeQuery {
+    /// From the REPL command name
+    pub fn parse(command: &str) -> Option<Self> {
+        match command {
+            "alive" => Some(RangeQuery::Through),
+            "born" => Some(RangeQuery::Born),
+            "freed" => Some(RangeQuery::Freed),
+            "overlap" => Some(RangeQuery::Overlapping),
+            _ => None,
+        }
+    }
+}
+
+impl MemSnap {
+    /// Allocations whose lifetime relates to `[from, to]` as `query` says, that match `filter`,
+    /// ascending
+    pub fn range_query(
+        &self,
+        query: RangeQuery,
+        from: u64,
+        to: u64,
+        filter: Option<&Filter>,
+    ) -> Vec<AllocationIndex> {
+        let mut indices = match query {
+            RangeQuery::Through => self.lifetimes.alive_throughout(from, to),
+            RangeQuery::Born => self.lifetimes.started_in(from, to),
+            RangeQuery::Freed => {
+                let trace_end = self.timestamps.last().copied().unwrap_or(0);
+                self.lifetimes
+                    .stopped_in(from, to.min(trace_end.saturating_sub(1)))
+            }
+            RangeQuery::Overlapping => self.lifetimes.overlapping(from, to),
+        };
+        indices.sort_unstable();
+
+        if let Some(filter) = filter {
+            let mask = self.filter_mask(filter);
+            indices.retain(|&i| mask[i]);
+        }
+        indices
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RangeQuery;
+    use crate::repl_ops::{filter::Filter, memsnap::test_snapshot};
+
+    #[test]
+    fn test_range_query() {
+        let memsnap = test_snapshot();
+        let allocs = &memsnap.allocations;
+        let trace_end = *memsnap.timestamps.last().unwrap();
+
+        let (from, to) = (20, 40);
+        let through = memsnap.range_query(RangeQuery::Through, from, to, None);
+        let overlapping = memsnap.range_query(RangeQuery::Overlapping, from, to, None);
+        assert!(
+            through
+                .iter()
+                .all(|&i| allocs[i].is_alive_in_interval(from, to))
+        );
+        assert!(through.iter().all(|i| overlapping.contains(i)));
+
+        // everything overlapping the range was alive before it, or is born in it
+        let born = memsnap.range_query(RangeQuery::Born, from, to, None);
+        let before = memsnap.range_query(RangeQuery::Through, from, from, None);
+        let mut union = [born.clone(), before].concat();
+        union.sort_unstable();
+        union.dedup();
+        assert_eq!(union, overlapping);
+
+        let freed = memsnap.range_query(RangeQuery::Freed, 0, trace_end, None);
+        assert!(freed.iter().all(
+            |&i| !allocs[i].is_alive_at(trace_end) && allocs[i].start_end_time().1 < trace_end
+        ));
+
+        let filter = Filter::parse("size >= 1MiB", None).unwrap();
+        let large = memsnap.range_query(RangeQuery::Born, from, to, Some(&filter));
+        assert!(
+            large
+                .iter()
+                .all(|&i| allocs[i].size >= 1 << 20 && born.contains(&i))
+        );
+    }
+}
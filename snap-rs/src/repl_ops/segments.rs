@@ -0,0 +1,220 @@
+use super::memsnap::MemSnap;
+use crate::allocator::{BlockState, SegmentHistory, SegmentState};
+use crate::utils::format_bytes;
+
+impl MemSnap {
+    fn segment_history(&self) -> anyhow::Result<&SegmentHistory> {
+        self.segments
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No allocator segments in this snapshot"))
+    }
+
+    /// Every segment alive at `timestamp`, with its blocks and the free space between them
+    pub fn segments_at(&self, timestamp: u64) -> anyhow::Result<String> {
+        let segments = self.segment_history()?.at(timestamp);
+
+        let reserved: u64 = segments.values().map(|s| s.total_size).sum();
+        let allocated: u64 = segments.values().map(|s| s.allocated()).sum();
+        let mut lines = vec![format!(
+            "Segments {}: {} segments, {} reserved, {} allocated, {} free",
+            self.format_timestamp(timestamp),
+            segments.len(),
+            format_bytes(reserved),
+            format_bytes(allocated),
+            format_bytes(reserved.saturating_sub(allocated))
+        )];
+
+        for segment in segments.values() {
+            lines.push(format_segment(segment));
+        }
+
+        Ok(lines.join("\n"))
+    }
+
+    /// Reserved vs allocated memory at their peaks
+    pub fn segments_summary(&self) -> anyhow::Result<String> {
+        let timeline = self.segment_history()?.reserved_timeline();
+
+        let &(reserved_time, max_reserved, _) = timeline
+            .iter()
+            .rev() // first timestep at which the peak is reached
+            .max_by_key(|(_, reserved, _)| *reserved)
+            .unwrap();
+        let &(allocated_time, reserved_at_peak, max_allocated) = timeline
+            .iter()
+            .rev()
+            .max_by_key(|(_, _, allocated)| *allocated)
+            .unwrap();
+
+        Ok(format!(
+            "Peak reserved: {} {}\nPeak allocated: {} {} ({} reserved, {} caching overhead)",
+            format_bytes(max_reserved),
+            self.format_timestamp(reserved_time),
+            format_bytes(max_allocated),
+            self.format_timestamp(allocated_time),
+            format_bytes(reserved_at_peak),
+            format_bytes(reserved_at_peak.saturating_sub(max_allocated))
+        ))
+    }
+}
+
+fn format_segment(segment: &SegmentState) -> String {
+    let allocated = segment.allocated();
+    let mut lines = vec![format!(
+        "Segment {:#x} ({}, stream {}): {} blocks, {} allocated, {} free",
+        segment.address,
+        format_bytes(segment.total_size),
+        segment.stream,
+        segment.blocks.len(),
+        format_bytes(allocated),
+        format_bytes(segment.total_size.saturating_sub(allocated))
+    )];
+
+    // blocks in address order, with the gaps between them as free blocks
+    let mut entries = Vec::new();
+    let mut cursor = segment.address;
+    for (&addr, block) in &segment.blocks {
+        if addr > cursor {
+            entries.push((cursor, addr - cursor, "free"));
+        }
+        let state = match block.state {
+            BlockState::ActiveAllocated => "allocated",
+            BlockState::ActiveAwaitingFree => "awaiting free",
+        };
+        entries.push((addr, block.size, state));
+        cursor = cursor.max(addr + block.size);
+    }
+    let end = segment.address + segment.total_size;
+    if cursor < end {
+        entries.push((cursor, end - cursor, "free"));
+    }
+
+    for (i, (addr, size, state)) in entries.iter().enumerate() {
+        let prefix = if i == entries.len() - 1 {
+            "└──"
+        } else {
+            "├──"
+        };
+        lines.push(format!(
+            "  {} {:#x} {:>12}  {}",
+            prefix,
+            addr,
+            format_bytes(*size),
+            state
+        ));
+    }
+
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::allocator::SegmentState;
+    use crate::pickle::read_torch_snapshot;
+    use crate::utils::format_bytes;
+    use crate::{
+        layout::Layout,
+        repl_ops::memsnap::{MemSnap, test_snapshot},
+    };
+
+    #[test]
+    fn test_segments() {
+        let pickle_path = "../snapshots/snapshot.pickle";
+        let memsnap = MemSnap::from_pickle(pickle_path, Layout::Stack).unwrap();
+        let history = memsnap.segments.as_ref().unwrap();
+
+        // the trace allocates one of the two segments of the snapshot
+        let first = history.at(0);
+        let last = history.at(*memsnap.timestamps.last().unwrap() + 1);
+        assert_eq!(first.len(), 1);
+        assert_eq!(last.len(), 2);
+        assert!(first.values().all(|s| s.allocated() <= s.total_size));
+
+        // every allocation is in a segment while it is alive
+        let alloc = memsnap
+            .allocations
+            .iter()
+            .find(|a| a.action == "alloc")
+            .unwrap();
+        let (start, stop) = alloc.start_end_time();
+        for t in [start, stop] {
+            let segments = history.at(t);
+            assert!(
+                segments
+                    .values()
+                    .any(|s| s.blocks.contains_key(&alloc.addr))
+            );
+        }
+
+        // the header with the totals, one line per segment, then its blocks and free gaps
+        let report = memsnap.segments_at(start).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            lines[0],
+            "Segments @0 (+0us): 1 segments, 2.0 MiB reserved, 10.5 KiB allocated, 2.0 MiB free"
+        );
+        assert_eq!(
+            lines[1],
+            "Segment 0x703e00000 (2.0 MiB, stream 0): 7 blocks, 10.5 KiB allocated, 2.0 MiB free"
+        );
+        assert_eq!(lines.len(), 2 + 7 + 1);
+        assert_eq!(lines[2], "  ├── 0x703e00000      1.0 KiB  allocated");
+        assert!(lines[2..9].iter().all(|line| line.ends_with("  allocated")));
+        assert_eq!(lines[9], "  └── 0x703e02a00      2.0 MiB  free");
+
+        // at the peak, the totals add up over both segments
+        let at_peak = history.at(57);
+        let report = memsnap.segments_at(57).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert!(lines[0].starts_with("Segments @57 "));
+        assert!(lines[0].ends_with(&format!(
+            "2 segments, {} reserved, {} allocated, {} free",
+            format_bytes(at_peak.values().map(|s| s.total_size).sum()),
+            format_bytes(at_peak.values().map(|s| s.allocated()).sum()),
+            format_bytes(at_peak.values().map(|s| s.total_size - s.allocated()).sum())
+        )));
+        assert_eq!(
+            lines.iter().filter(|l| l.starts_with("Segment 0x")).count(),
+            at_peak.len()
+        );
+        assert_eq!(
+            lines.iter().filter(|l| !l.ends_with("  free")).count(),
+            1 + at_peak.len() + at_peak.values().map(|s| s.blocks.len()).sum::<usize>()
+        );
+
+        assert_eq!(
+            memsnap.segments_summary().unwrap(),
+            "Peak reserved: 22.0 MiB @2 (+27.2ms)\n\
+             Peak allocated: 17.3 MiB @57 (+126.6ms) (22.0 MiB reserved, 4.7 MiB caching overhead)"
+        );
+
+        // json snapshots replay the trace from no segments: they miss the one allocated before
+        // recording, and agree with the pickle on the others. The same goes for the raw trace.
+        let trace_path = std::env::temp_dir().join("tomi_test_segments_trace.json");
+        let trace_path = trace_path.to_str().unwrap();
+        let snapshot = read_torch_snapshot(pickle_path).unwrap();
+        std::fs::write(
+            trace_path,
+            serde_json::to_string(&snapshot.device_traces[0]).unwrap(),
+        )
+        .unwrap();
+        let from_trace = MemSnap::from_trace_json(trace_path, Layout::Stack).unwrap();
+        std::fs::remove_file(trace_path).unwrap();
+
+        for from_json in [test_snapshot(), from_trace] {
+            let replayed = from_json.segments.as_ref().unwrap();
+            assert!(replayed.at(0).is_empty());
+            for t in 0..=*memsnap.timestamps.last().unwrap() + 1 {
+                let (segments, expected) = (replayed.at(t), history.at(t));
+                assert_eq!(segments.len(), expected.len() - 1);
+                for (addr, segment) in &segments {
+                    let blocks = |s: &SegmentState| -> Vec<(u64, u64)> {
+                        s.blocks.iter().map(|(&a, b)| (a, b.size)).collect()
+                    };
+                    assert_eq!(segment.total_size, expected[addr].total_size);
+                    assert_eq!(blocks(segment), blocks(&expected[addr]));
+                }
+            }
+        }
+    }
+}
@@ -11,28 +11,30 @@ impl MemSnap {
             )));
         }
 
-        match &self.global_sorted_sizes {
-            Some(indices_sorted_by_size) => Ok(indices_sorted_by_size[..k].to_vec()),
-            None => {
-                log::info!("Sorting by size globally");
-                // create topk vector
-                let mut sizes = self
-                    .allocations
-                    .iter()
-                    .enumerate()
-                    .map(|(i, alloc)| (i, alloc.size)) // map allocation size to its index
-                    .collect::<Vec<(usize, u64)>>();
-
-                // NOTE: sort by allocation size, DEScending
-                sizes.sort_by(|(i1, size1), (i2, size2)| size2.cmp(size1));
-
-                let indices_sorted_by_size: Vec<usize> =
-                    sizes.into_iter().map(|(index, size)| index).collect();
-
-                self.global_sorted_sizes = Some(indices_sorted_by_size.clone());
-
-                Ok(indices_sorted_by_size[..k].to_vec())
-            }
+        self.build_global_sorted_sizes();
+        let indices_sorted_by_size = self.global_sorted_sizes.as_ref().unwrap();
+        Ok(indices_sorted_by_size[..k].to_vec())
+    }
+
+    /// Sort all allocations by size, descending, if not sorted yet
+    pub fn build_global_sorted_sizes(&mut self) {
+        if self.global_sorted_sizes.is_none() {
+            log::info!("Sorting by size globally");
+            // create topk vector
+            let mut sizes = self
+                .allocations
+                .iter()
+                .enumerate()
+                .map(|(i, alloc)| (i, alloc.size)) // map allocation size to its index
+                .collect::<Vec<(usize, u64)>>();
+
+            // NOTE: sort by allocation size, DEScending
+            sizes.sort_by(|(_, size1), (_, size2)| size2.cmp(size1));
+
+            let indices_sorted_by_size: Vec<usize> =
+                sizes.into_iter().map(|(index, _)| index).collect();
+
+            self.global_sorted_sizes = Some(indices_sorted_by_size);
         }
     }
 
@@ -50,6 +52,13 @@ impl MemSnap {
             )));
         }
 
+        let indices_sorted_by_size = self.timestamp_sorted(timestamp)?;
+        Ok(indices_sorted_by_size[..k.min(indices_sorted_by_size.len())].to_vec())
+    }
+
+    /// Indices of the allocations alive at the nearest timestamp at or after `timestamp`, sorted
+    /// descending by size (and cached until another timestamp is queried)
+    pub fn timestamp_sorted(&mut self, timestamp: u64) -> Result<&[usize], anyhow::Error> {
         let nearest_timestamp_index = match self.timestamps.binary_search(&timestamp) {
             Ok(i) => i,
             Err(i) => i,
@@ -64,48 +73,35 @@ impl MemSnap {
         }
         let nearest_timestamp = self.timestamps[nearest_timestamp_index];
 
-        match self.timestamp_sorted_sizes.get(&nearest_timestamp) {
-            Some(indices_sorted_by_size) => {
+        match &self.timestamp_sorted_sizes {
+            Some((cached, _)) if *cached == nearest_timestamp => {
                 println!("Hit {}", nearest_timestamp);
-                Ok(indices_sorted_by_size[..k].to_vec())
             }
-            None => {
+            _ => {
                 log::info!(
                     "Round to timestamp {}, sorting for this timestamp",
                     nearest_timestamp
                 );
-                // create topk vector
-                let mut sizes = self
-                    .allocations
-                    .iter()
-                    .enumerate() // first enumerate, make sure index does not change
-                    .filter_map(|(i, alloc)| {
-                        if alloc.is_alive_at(nearest_timestamp) {
-                            Some((i, alloc.size))
-                        } else {
-                            None
-                        }
-                    }) // check if this alloc is alive at timestamp
-                    .collect::<Vec<(usize, u64)>>();
-
-                // NOTE: sort descending
-                sizes.sort_by(|(i1, size1), (i2, size2)| size2.cmp(size1));
-
-                let indices_sorted_by_size: Vec<usize> =
-                    sizes.into_iter().map(|(index, size)| index).collect();
-
-                self.timestamp_sorted_sizes
-                    .insert(nearest_timestamp, indices_sorted_by_size.clone());
-
-                Ok(indices_sorted_by_size[..k].to_vec())
+                let mut indices_sorted_by_size = self.lifetimes.alive_at(nearest_timestamp);
+
+                // NOTE: sort descending, ties by index
+                indices_sorted_by_size.sort_by(|&i1, &i2| {
+                    let (size1, size2) = (self.allocations[i1].size, self.allocations[i2].size);
+                    size2.cmp(&size1).then(i1.cmp(&i2))
+                });
+
+                self.timestamp_sorted_sizes = Some((nearest_timestamp, indices_sorted_by_size));
             }
         }
+
+        Ok(&self.timestamp_sorted_sizes.as_ref().unwrap().1)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{
+        layout::Layout,
         load::{load_allocations, read_snap_from_jsons},
         repl_ops::memsnap::MemSnap,
         utils::format_bytes,
@@ -119,8 +115,11 @@ mod tests {
         let alloc_path = "../snapshots/allocations.json";
         let elements_path = "../snapshots/elements.json";
 
-        let allocations =
-            load_allocations(read_snap_from_jsons(alloc_path, elements_path).unwrap()).unwrap();
+        let allocations = load_allocations(
+            read_snap_from_jsons(alloc_path, elements_path).unwrap(),
+            Layout::Stack,
+        )
+        .unwrap();
 
         let mut memsnap = MemSnap::new(allocations);
 
@@ -144,14 +143,17 @@ mod tests {
         let alloc_path = "../snapshots/allocations.json";
         let elements_path = "../snapshots/elements.json";
 
-        let allocations =
-            load_allocations(read_snap_from_jsons(alloc_path, elements_path).unwrap()).unwrap();
+        let allocations = load_allocations(
+            read_snap_from_jsons(alloc_path, elements_path).unwrap(),
+            Layout::Stack,
+        )
+        .unwrap();
 
         let mut memsnap = MemSnap::new(allocations);
 
-        let top3 = memsnap.timestamp_topk(24, 3).unwrap();
-        let top3 = memsnap.timestamp_topk(25, 3).unwrap(); // hit 26
-        let top3 = memsnap.timestamp_topk(24, 3).unwrap(); // hit 26
+        memsnap.timestamp_topk(24, 3).unwrap();
+        memsnap.timestamp_topk(25, 3).unwrap(); // hit 26
+        memsnap.timestamp_topk(24, 3).unwrap(); // hit 26
         let top3 = memsnap.timestamp_topk(25, 3).unwrap(); // hit 26
 
         dbg!(&top3);
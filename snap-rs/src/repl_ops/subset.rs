@@ -0,0 +1,231 @@
+use super::database::format_callstack;
+use super::memsnap::{AllocationIndex, MemSnap};
+use crate::callstack::StackId;
+use crate::clock::{Clock, parse_time_range};
+use crate::layout::{Layout, TraceActions, layout_actions};
+use crate::pickle::TraceEvent;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use zip::{ZipWriter, write::SimpleFileOptions};
+
+/// Which allocations `save_subset` keeps
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubsetFilter {
+    /// `@start..end`: alive at some timestep of `start..=end`, e.g. `@100..200` or `@1.5s..2s`
+    TimeRange(u64, u64),
+    /// `3,5,10-20`: allocation indices and inclusive ranges of them
+    Indices(Vec<AllocationIndex>),
+    /// `~pattern`: some "filename:line:name" line of the callstack contains `pattern`
+    Callstack(String),
+}
+
+impl SubsetFilter {
+    /// The ends of a time range are timesteps or elapsed times, see `clock::parse_timestamp`.
+    /// Indices must be below `num_allocations`, so that a huge range is rejected before it is
+    /// expanded.
+    pub fn parse(s: &str, clock: Option<&Clock>, num_allocations: usize) -> anyhow::Result<Self> {
+        let parse_index = |n: &str| {
+            let index = n
+                .trim()
+                .parse::<usize>()
+                .map_err(|e| anyhow::anyhow!("Invalid number '{}': {}", n, e))?;
+            if index >= num_allocations {
+                return Err(anyhow::anyhow!(
+                    "Index {} out of range (0..{})",
+                    index,
+                    num_allocations
+                ));
+            }
+            Ok(index)
+        };
+
+        if let Some(pattern) = s.strip_prefix('~') {
+            let pattern = pattern.trim();
+            if pattern.is_empty() {
+                return Err(anyhow::anyhow!("Empty callstack pattern"));
+            }
+            return Ok(SubsetFilter::Callstack(pattern.to_string()));
+        }
+
+        if s.starts_with('@') {
+            let (start, end) = parse_time_range(s, clock)?;
+            return Ok(SubsetFilter::TimeRange(start, end));
+        }
+
+        let mut indices = Vec::new();
+        for part in s.split(',') {
+            match part.split_once('-') {
+                Some((first, last)) => indices.extend(parse_index(first)?..=parse_index(last)?),
+                None => indices.push(parse_index(part)?),
+            }
+        }
+        indices.sort_unstable();
+        indices.dedup();
+        Ok(SubsetFilter::Indices(indices))
+    }
+}
+
+impl MemSnap {
+    /// Indices of the allocations kept by `filter`, ascending
+    pub fn select(&self, filter: &SubsetFilter) -> anyhow::Result<Vec<AllocationIndex>> {
+        match filter {
+            SubsetFilter::TimeRange(start, end) => {
+                let mut indices = self.lifetimes.overlapping(*start, *end);
+                indices.sort_unstable();
+                Ok(indices)
+            }
+            SubsetFilter::Indices(indices) => {
+                if let Some(&index) = indices.iter().find(|&&i| i >= self.allocations.len()) {
+                    return Err(anyhow::anyhow!(
+                        "Index {} out of range (0..{})",
+                        index,
+                        self.allocations.len()
+                    ));
+                }
+                Ok(indices.clone())
+            }
+            SubsetFilter::Callstack(pattern) => {
+                let matches: Vec<bool> = (0..self.callstacks.num_stacks() as StackId)
+                    .map(|stack| format_callstack(&self.callstacks, stack).contains(pattern))
+                    .collect();
+                Ok((0..self.allocations.len())
+                    .filter(|&i| matches[self.allocations[i].stack as usize])
+                    .collect())
+            }
+        }
+    }
+
+    /// A device trace with only the alloc/free events of `indices`, in the order they happen.
+    /// Allocations alive at the end of the trace are not freed.
+    fn subset_trace(&self, indices: &[AllocationIndex]) -> Vec<TraceEvent> {
+        let trace_end = self.timestamps.last().copied().unwrap_or(0);
+
+        let event = |index: AllocationIndex, action: &str| {
+            let alloc = &self.allocations[index];
+            TraceEvent {
+                action: action.to_string(),
+                addr: alloc.addr,
+                size: alloc.size,
+                stream: alloc.stream,
+                time_us: alloc.time_us,
+                frames: self.callstacks.frames(alloc.stack).cloned().collect(),
+            }
+        };
+
+        // (timestep, frees before allocs at the same timestep, event)
+        let mut events: Vec<(u64, bool, TraceEvent)> = Vec::new();
+        for &index in indices {
+            let alloc = &self.allocations[index];
+            if alloc.timesteps.is_empty() {
+                continue;
+            }
+            let (start, stop) = alloc.start_end_time();
+            match alloc.action.as_str() {
+                // allocated before recording started: only its free is in the trace
+                "free" | "free_completed" => {
+                    events.push((stop, false, event(index, &alloc.action)))
+                }
+                action => {
+                    events.push((start, true, event(index, action)));
+                    if stop < trace_end {
+                        events.push((stop, false, event(index, "free_completed")));
+                    }
+                }
+            }
+        }
+        events.sort_by_key(|(timestep, is_alloc, _)| (*timestep, *is_alloc));
+
+        events.into_iter().map(|(_, _, event)| event).collect()
+    }
+
+    /// Writes the allocations kept by `filter` as a zip with allocations.json and elements.json,
+    /// laid out again as if they were the whole trace. Returns the number of allocations written.
+    pub fn save_subset(&self, zip_path: &str, filter: &SubsetFilter) -> anyhow::Result<usize> {
+        let indices = self.select(filter)?;
+        if indices.is_empty() {
+            return Err(anyhow::anyhow!("No allocation matches {:?}", filter));
+        }
+
+        let mut trace = TraceActions::default();
+        for event in self.subset_trace(&indices) {
+            trace.push(event);
+        }
+        let laid_out = layout_actions(trace, Layout::Stack);
+
+        let file = File::create(zip_path)
+            .map_err(|e| anyhow::anyhow!("Failed to create '{}': {}", zip_path, e))?;
+        let mut zip = ZipWriter::new(BufWriter::new(file));
+        let options = SimpleFileOptions::default().large_file(true);
+
+        zip.start_file("allocations.json", options)?;
+        serde_json::to_writer(&mut zip, &laid_out.allocations)?;
+        zip.start_file("elements.json", options)?;
+        serde_json::to_writer(&mut zip, &laid_out.elements)?;
+        zip.finish()?.flush()?;
+
+        Ok(laid_out.allocations.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SubsetFilter;
+    use crate::{layout::Layout, repl_ops::memsnap::MemSnap};
+
+    #[test]
+    fn test_save_subset() {
+        let alloc_path = "../snapshots/allocations.json";
+        let elements_path = "../snapshots/elements.json";
+        let zip_path = std::env::temp_dir().join("tomi_test_subset.zip");
+        let zip_path = zip_path.to_str().unwrap();
+
+        assert_eq!(
+            SubsetFilter::parse("1,4-6,4", None, 10).unwrap(),
+            SubsetFilter::Indices(vec![1, 4, 5, 6])
+        );
+        assert_eq!(
+            SubsetFilter::parse("@10..20", None, 10).unwrap(),
+            SubsetFilter::TimeRange(10, 20)
+        );
+        assert!(SubsetFilter::parse("@20..10", None, 10).is_err());
+        assert!(SubsetFilter::parse("0-18446744073709551615", None, 10).is_err());
+        assert!(SubsetFilter::parse("3,10", None, 10).is_err());
+
+        let memsnap = MemSnap::from_jsons(alloc_path, elements_path, Layout::Stack).unwrap();
+        let sorted = |snap: &MemSnap| {
+            let mut allocs: Vec<_> = snap
+                .allocations
+                .iter()
+                .map(|a| (a.timesteps.clone(), a.offsets.clone(), a.size, a.addr))
+                .collect();
+            allocs.sort();
+            allocs
+        };
+
+        // the whole trace is laid out exactly as before
+        let all = SubsetFilter::Indices((0..memsnap.allocations.len()).collect());
+        assert_eq!(
+            memsnap.save_subset(zip_path, &all).unwrap(),
+            memsnap.allocations.len()
+        );
+        let reloaded = MemSnap::from_zip(zip_path, Layout::Stack).unwrap();
+        assert_eq!(sorted(&reloaded), sorted(&memsnap));
+
+        // a time window is stacked without the gaps of the allocations left out
+        let window = SubsetFilter::TimeRange(100, 120);
+        let kept = memsnap.select(&window).unwrap();
+        memsnap.save_subset(zip_path, &window).unwrap();
+        let reloaded = MemSnap::from_zip(zip_path, Layout::Stack).unwrap();
+        assert_eq!(reloaded.allocations.len(), kept.len());
+        let total: u64 = kept.iter().map(|&i| memsnap.allocations[i].size).sum();
+        assert!(
+            reloaded
+                .allocations
+                .iter()
+                .all(|a| a.offsets.iter().all(|&o| o + a.size <= total))
+        );
+        assert!(reloaded.check().is_empty());
+
+        std::fs::remove_file(zip_path).unwrap();
+    }
+}
@@ -1,9 +1,9 @@
 use super::memsnap::MemSnap;
 use plotters::{
     chart::{ChartBuilder, LabelAreaPosition},
-    prelude::{BitMapBackend, IntoDrawingArea, SVGBackend},
+    prelude::{IntoDrawingArea, SVGBackend},
     series::LineSeries,
-    style::{GREEN, WHITE},
+    style::{BLUE, GREEN, WHITE},
 };
 use std::collections::BTreeMap;
 
@@ -46,9 +46,48 @@ impl MemSnap {
         }
     }
 
-    pub fn plot_timeline(&mut self, path: &str) -> anyhow::Result<()> {
+    /// First timestamp at which the memory in use peaks
+    pub fn peak_timestamp(&mut self) -> u64 {
+        self.build_timeline();
+        let timeline = self.timeline.as_ref().unwrap();
+        timeline
+            .timeline
+            .iter()
+            .find(|(_, mem)| *mem == timeline.max_alloc)
+            .map_or(0, |(timestamp, _)| *timestamp)
+    }
+
+    /// Plots memory in use (and reserved, if known) over timesteps, or over the elapsed wall-clock
+    /// time if `time_axis`
+    pub fn plot_timeline(&mut self, path: &str, time_axis: bool) -> anyhow::Result<()> {
         self.build_timeline();
 
+        let clock = match (time_axis, &self.clock) {
+            (false, _) => None,
+            (true, Some(clock)) => Some(clock),
+            (true, None) => {
+                return Err(anyhow::anyhow!(
+                    "This trace has no wall-clock times (time_us), plot it over timesteps"
+                ));
+            }
+        };
+        // x coordinate of a timestep: itself, or seconds since the first event
+        let x = |timestep: u64| match clock {
+            Some(clock) => clock.elapsed_us(timestep) as f64 / 1e6,
+            None => timestep as f64,
+        };
+
+        // reserved memory of the caching allocator, as a step line
+        let reserved: Vec<(f64, u64)> = match &self.segments {
+            Some(segments) => segments
+                .reserved_timeline()
+                .windows(2)
+                .flat_map(|w| [(x(w[0].0), w[0].1), (x(w[1].0), w[0].1)])
+                .collect(),
+            None => Vec::new(),
+        };
+        let max_reserved = reserved.iter().map(|&(_, mem)| mem).max().unwrap_or(0);
+
         match &self.timeline {
             Some(timeline) => {
                 let root_area = SVGBackend::new(path, (3000, 800)).into_drawing_area();
@@ -58,11 +97,24 @@ impl MemSnap {
                     .set_label_area_size(LabelAreaPosition::Left, 24)
                     .set_label_area_size(LabelAreaPosition::Bottom, 24)
                     .caption("Memory Trace Timeline", ("sans-serif", 40))
-                    .build_cartesian_2d(0..timeline.max_time, 0..timeline.max_alloc)?;
+                    .build_cartesian_2d(
+                        0.0..x(timeline.max_time).max(f64::MIN_POSITIVE),
+                        0..timeline.max_alloc.max(max_reserved),
+                    )?;
 
-                ctx.configure_mesh().draw()?;
+                let format_x = |v: &f64| match clock {
+                    Some(_) => format!("{:.2}s", v),
+                    None => format!("{:.0}", v),
+                };
+                ctx.configure_mesh().x_label_formatter(&format_x).draw()?;
 
-                ctx.draw_series(LineSeries::new(timeline.timeline.clone(), &GREEN))?;
+                ctx.draw_series(LineSeries::new(
+                    timeline.timeline.iter().map(|&(t, mem)| (x(t), mem)),
+                    &GREEN,
+                ))?;
+                if !reserved.is_empty() {
+                    ctx.draw_series(LineSeries::new(reserved, &BLUE))?;
+                }
 
                 Ok(())
             }
@@ -0,0 +1,183 @@
+use crate::utils::format_bytes;
+use log::info;
+use serde::de::{DeserializeOwned, SeqAccess, Visitor};
+use std::cell::Cell;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::marker::PhantomData;
+use std::rc::Rc;
+use zip::ZipArchive;
+
+/// Log a progress line every this many parsed entries
+const PROGRESS_EVERY: usize = 100_000;
+
+/// A json document that is opened only when it is parsed, so its text is never held in memory
+#[derive(Debug, Clone)]
+pub enum JsonSource {
+    File {
+        path: String,
+        size: u64,
+    },
+    ZipEntry {
+        zip_path: String,
+        index: usize,
+        size: u64,
+    },
+}
+
+impl JsonSource {
+    /// Size of the (uncompressed) document in bytes
+    pub fn size(&self) -> u64 {
+        match self {
+            JsonSource::File { size, .. } | JsonSource::ZipEntry { size, .. } => *size,
+        }
+    }
+
+    fn with_reader<T>(
+        &self,
+        f: impl FnOnce(&mut dyn Read) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        match self {
+            JsonSource::File { path, .. } => {
+                let mut file = File::open(path)
+                    .map_err(|e| anyhow::anyhow!("Failed to open '{}': {}", path, e))?;
+                f(&mut file)
+            }
+            JsonSource::ZipEntry {
+                zip_path, index, ..
+            } => {
+                let mut archive = ZipArchive::new(File::open(zip_path)?)?;
+                let mut entry = archive.by_index(*index)?;
+                f(&mut entry)
+            }
+        }
+    }
+}
+
+/// Counts the bytes that go through it
+struct CountingReader<R> {
+    inner: R,
+    read: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read.set(self.read.get() + n as u64);
+        Ok(n)
+    }
+}
+
+struct Progress<'a> {
+    what: &'a str,
+    total: u64,
+    read: Rc<Cell<u64>>,
+    entries: usize,
+}
+
+impl Progress<'_> {
+    fn entry(&mut self) {
+        self.entries += 1;
+        if self.entries.is_multiple_of(PROGRESS_EVERY) {
+            let read = self.read.get();
+            info!(
+                "Parsing {}: {} entries, {} / {} ({:.0}%)",
+                self.what,
+                self.entries,
+                format_bytes(read),
+                format_bytes(self.total),
+                100.0 * read as f64 / self.total.max(1) as f64
+            );
+        }
+    }
+}
+
+struct EntryVisitor<'a, T, F> {
+    f: F,
+    progress: Progress<'a>,
+    marker: PhantomData<T>,
+}
+
+impl<'de, T: DeserializeOwned, F: FnMut(T)> Visitor<'de> for EntryVisitor<'_, T, F> {
+    type Value = usize;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "a list of {}", self.progress.what)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(mut self, mut seq: A) -> Result<usize, A::Error> {
+        while let Some(entry) = seq.next_element::<T>()? {
+            (self.f)(entry);
+            self.progress.entry();
+        }
+        Ok(self.progress.entries)
+    }
+}
+
+/// Parses the top-level json list of `source` entry by entry, handing each entry to `f` as soon as
+/// it is parsed. Returns the number of entries. `what` names the entries in progress and error messages.
+pub fn for_each_entry<T: DeserializeOwned>(
+    source: &JsonSource,
+    what: &str,
+    f: impl FnMut(T),
+) -> anyhow::Result<usize> {
+    info!("Parsing {} ({})", what, format_bytes(source.size()));
+
+    let entries = source
+        .with_reader(|reader| {
+            let read = Rc::new(Cell::new(0));
+            let reader = BufReader::new(CountingReader {
+                inner: reader,
+                read: read.clone(),
+            });
+            let mut deserializer = serde_json::Deserializer::from_reader(reader);
+
+            let visitor = EntryVisitor {
+                f,
+                progress: Progress {
+                    what,
+                    total: source.size(),
+                    read,
+                    entries: 0,
+                },
+                marker: PhantomData,
+            };
+            let entries = serde::Deserializer::deserialize_seq(&mut deserializer, visitor)?;
+            deserializer.end()?;
+            Ok(entries)
+        })
+        .map_err(|e| anyhow::anyhow!("Failed to parse {} JSON from {:?}: {}", what, source, e))?;
+
+    info!("Parsed {} {}", entries, what);
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JsonSource, for_each_entry};
+    use crate::allocation::ElementData;
+
+    #[test]
+    fn test_for_each_entry() {
+        let path = "../snapshots/elements.json";
+        let source = JsonSource::File {
+            path: path.to_string(),
+            size: std::fs::metadata(path).unwrap().len(),
+        };
+
+        let mut sizes = Vec::new();
+        let entries =
+            for_each_entry(&source, "elements", |e: ElementData| sizes.push(e.size)).unwrap();
+        assert_eq!(entries, 241);
+        assert_eq!(sizes.len(), 241);
+
+        // not a list of elements
+        let path = "../snapshots/allocations.json";
+        let source = JsonSource::File {
+            path: path.to_string(),
+            size: std::fs::metadata(path).unwrap().len(),
+        };
+        assert!(for_each_entry(&source, "elements", |_: ElementData| {}).is_err());
+    }
+}
@@ -12,3 +12,98 @@ pub fn format_bytes(bytes: u64) -> String {
 
     format!("{:.1}YiB", num) // Should be unreachable for typical u64 values
 }
+
+/// Signed byte difference, e.g. `+1.5 MiB`
+pub fn format_delta(delta: i64) -> String {
+    let sign = if delta < 0 { "-" } else { "+" };
+    format!("{}{}", sign, format_bytes(delta.unsigned_abs()))
+}
+
+/// Parses a byte count such as `1048576`, `512KiB`, `16MiB` or `1.5 GiB` (K/M/G/T are binary units too)
+pub fn parse_bytes(s: &str) -> anyhow::Result<u64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+
+    let num: f64 = num
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid byte value: {}", s))?;
+    let unit = unit
+        .trim()
+        .trim_end_matches(['B', 'b'])
+        .trim_end_matches('i');
+    let scale = match unit.to_ascii_uppercase().as_str() {
+        "" => 1u64,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(anyhow::anyhow!("Invalid byte unit: {}", s)),
+    };
+
+    Ok((num * scale as f64) as u64)
+}
+
+/// Formats microseconds as `12us`, `340.0ms` or `12.500s`
+pub fn format_duration(us: u64) -> String {
+    if us < 1_000 {
+        format!("{}us", us)
+    } else if us < 1_000_000 {
+        format!("{:.1}ms", us as f64 / 1e3)
+    } else {
+        format!("{:.3}s", us as f64 / 1e6)
+    }
+}
+
+/// Parses a duration such as `12.5s`, `340ms`, `1500us` or `2min` into microseconds
+pub fn parse_duration(s: &str) -> anyhow::Result<u64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+
+    let num: f64 = num
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid duration: {}", s))?;
+    let scale = match unit.trim() {
+        "us" => 1.0,
+        "ms" => 1e3,
+        "s" => 1e6,
+        "min" => 60e6,
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Invalid duration unit: {} (expected us, ms, s or min)",
+                s
+            ));
+        }
+    };
+
+    Ok((num * scale).round() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_duration, parse_bytes, parse_duration};
+
+    #[test]
+    fn test_parse_bytes() {
+        assert_eq!(parse_bytes("1024").unwrap(), 1024);
+        assert_eq!(parse_bytes("16MiB").unwrap(), 16 << 20);
+        assert_eq!(parse_bytes("1.5 GiB").unwrap(), 3 << 29);
+        assert_eq!(parse_bytes("512k").unwrap(), 512 << 10);
+        assert!(parse_bytes("12 apples").is_err());
+        assert!(parse_bytes("MiB").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("12.5s").unwrap(), 12_500_000);
+        assert_eq!(parse_duration("340ms").unwrap(), 340_000);
+        assert_eq!(parse_duration("2min").unwrap(), 120_000_000);
+        assert!(parse_duration("12").is_err());
+        assert_eq!(format_duration(340_000), "340.0ms");
+    }
+}